@@ -0,0 +1,96 @@
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use ruvicorn_core::conn::http11::bound::RequestBody;
+use ruvicorn_core::server::channel::RequestChannel;
+
+// Awaits one event the way an app does. The baseline is the Python
+// coroutine over `asyncio.Queue` the channel replaces.
+const DRIVER: &str = r#"
+import asyncio
+
+loop = asyncio.new_event_loop()
+message = {"type": "http.response.body", "body": b"x" * 100, "more_body": True}
+
+async def wait(awaitable):
+    return await awaitable
+
+def now(awaitable):
+    coroutine = wait(awaitable)
+    try:
+        coroutine.send(None)
+    except StopIteration as e:
+        return e.value
+    raise RuntimeError("suspended")
+
+queue = asyncio.Queue()
+
+async def queue_receive():
+    return await queue.get()
+
+async def queue_send(message):
+    await queue.put(message)
+"#;
+
+fn events(c: &mut Criterion) {
+    Python::with_gil(|py| {
+        let globals = PyDict::new(py);
+        py.run(DRIVER, Some(globals), None).unwrap();
+        let channel = RequestChannel::new(globals.get_item("loop").unwrap().into());
+        let (receive, send) = channel.callables(py);
+        globals.set_item("receive", receive).unwrap();
+        globals.set_item("send", send).unwrap();
+        let now = globals.get_item("now").unwrap();
+        let message = globals.get_item("message").unwrap();
+        let start = PyDict::new(py);
+        start.set_item("type", "http.response.start").unwrap();
+        start.set_item("status", 200).unwrap();
+        // Waits for the connection to check the head, unlike body parts.
+        globals.get_item("send").unwrap().call1((start,)).unwrap();
+        channel.next_sent().unwrap();
+
+        let mut group = c.benchmark_group("receive");
+        let receive = globals.get_item("receive").unwrap();
+        let body = Bytes::from_static(&[b'x'; 100]);
+        group.bench_function("channel", |b| {
+            b.iter(|| {
+                channel.push_body(RequestBody {
+                    body: body.clone(),
+                    more_body: true,
+                });
+                black_box(now.call1((receive.call0().unwrap(),)).unwrap());
+            })
+        });
+        let queue = globals.get_item("queue").unwrap();
+        let queue_receive = globals.get_item("queue_receive").unwrap();
+        group.bench_function("asyncio_queue", |b| {
+            b.iter(|| {
+                queue.call_method1("put_nowait", (message,)).unwrap();
+                black_box(now.call1((queue_receive.call0().unwrap(),)).unwrap());
+            })
+        });
+        group.finish();
+
+        let mut group = c.benchmark_group("send");
+        let send = globals.get_item("send").unwrap();
+        group.bench_function("channel", |b| {
+            b.iter(|| {
+                black_box(now.call1((send.call1((message,)).unwrap(),)).unwrap());
+                channel.next_sent().unwrap();
+            })
+        });
+        let queue_send = globals.get_item("queue_send").unwrap();
+        group.bench_function("asyncio_queue", |b| {
+            b.iter(|| {
+                black_box(now.call1((queue_send.call1((message,)).unwrap(),)).unwrap());
+                queue.call_method0("get_nowait").unwrap();
+            })
+        });
+        group.finish();
+    });
+}
+
+criterion_group!(benches, events);
+criterion_main!(benches);
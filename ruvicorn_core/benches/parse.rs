@@ -0,0 +1,36 @@
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use ruvicorn_core::conn::http11::{bound::RequestData, request::Request, state::State};
+use ruvicorn_core::handle::Handle;
+
+fn request_with_headers(count: usize) -> Bytes {
+    let mut data = b"GET /bench?query=1 HTTP/1.1\r\nHost: localhost\r\n".to_vec();
+    for i in 1..count {
+        data.extend(format!("X-Header-{}: some header value {}\r\n", i, i).as_bytes());
+    }
+    data.extend(b"\r\n");
+    Bytes::from(data)
+}
+
+fn parse_request_head(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_request_head");
+    for count in [1, 10, 30] {
+        let data = request_with_headers(count);
+        group.bench_function(format!("{}_headers", count), |b| {
+            let mut request = Request::new(Arc::default());
+            b.iter(|| {
+                let mut buffer = BytesMut::new();
+                let result =
+                    request.step(&mut buffer, State::Idle, RequestData { data: data.clone() });
+                black_box(result.unwrap());
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse_request_head);
+criterion_main!(benches);
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use ruvicorn_core_fuzz::{run_connection, split};
+
+// Arbitrary bytes, cut at arbitrary points, through the connection state
+// machine. Reads sizes must never change what the application sees.
+fuzz_target!(|input: (Vec<u16>, Vec<u8>)| {
+    let (points, data) = input;
+
+    let whole = run_connection(&[&data]);
+    let parts = run_connection(&split(&data, &points));
+
+    assert_eq!(whole, parts);
+});
@@ -0,0 +1,63 @@
+#![no_main]
+
+use bytes::{Bytes, BytesMut};
+use libfuzzer_sys::fuzz_target;
+use ruvicorn_core::conn::http11::bound::RequestData;
+use ruvicorn_core::conn::http11::payload_handle::{ChunkedPayload, LengthedPayload, PayloadType};
+use ruvicorn_core::conn::http11::state::State;
+use ruvicorn_core::handle::Handle;
+use ruvicorn_core_fuzz::split;
+
+#[derive(Debug, PartialEq)]
+enum Decoded {
+    // Body so far, whether it is finished and the bytes left in the buffer.
+    Body(Vec<u8>, bool, Vec<u8>),
+    Err,
+}
+
+fn decode(mut payload: PayloadType, parts: &[&[u8]]) -> Decoded {
+    let mut buffer = BytesMut::new();
+    let mut state = State::RequestHeadFinished;
+    let mut body = Vec::new();
+
+    for part in parts {
+        let data = Bytes::copy_from_slice(part);
+        if state == State::RequestBodyFinished {
+            // Next request, left for the request parser.
+            buffer.extend_from_slice(&data);
+            continue;
+        }
+        match payload.step(&mut buffer, state, RequestData { data }) {
+            Ok((chunk, next, _)) => {
+                assert_eq!(chunk.more_body, next == State::RequestHeadFinished);
+                body.extend_from_slice(&chunk.body);
+                state = next;
+            }
+            Err((_, next)) => {
+                assert_eq!(next, State::Closed);
+                return Decoded::Err;
+            }
+        }
+    }
+    Decoded::Body(body, state == State::RequestBodyFinished, buffer.to_vec())
+}
+
+fn new_payload(chunked: bool, length: u16) -> PayloadType {
+    if chunked {
+        PayloadType::Chunked(ChunkedPayload::default())
+    } else {
+        PayloadType::Lenghthed(LengthedPayload {
+            remaining: length as usize,
+        })
+    }
+}
+
+// Both decoders must give the same body and leftover however the input is cut.
+fuzz_target!(|input: (bool, u16, Vec<u16>, Vec<u8>)| {
+    let (chunked, length, points, data) = input;
+
+    let whole = decode(new_payload(chunked, length), &[&data]);
+    let parts = decode(new_payload(chunked, length), &split(&data, &points));
+
+    assert_eq!(whole, parts);
+});
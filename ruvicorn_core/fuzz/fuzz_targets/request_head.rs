@@ -0,0 +1,41 @@
+#![no_main]
+
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use libfuzzer_sys::fuzz_target;
+use ruvicorn_core::conn::http11::bound::RequestData;
+use ruvicorn_core::conn::http11::request::{Request, RequestError};
+use ruvicorn_core::conn::http11::state::State;
+use ruvicorn_core::handle::Handle;
+
+fuzz_target!(|data: &[u8]| {
+    let mut request = Request::new(Arc::default());
+    let mut buffer = BytesMut::new();
+
+    let result = request.step(
+        &mut buffer,
+        State::Idle,
+        RequestData {
+            data: Bytes::copy_from_slice(data),
+        },
+    );
+
+    match result {
+        Ok((head, state, _)) => {
+            assert_eq!(state, State::RequestHeadFinished);
+            // Only the payload is left, and headers are sliced out of the head.
+            assert!(buffer.len() < data.len());
+            let head_len = data.len() - buffer.len();
+            for (name, value) in &head.headers {
+                assert!(!name.is_empty());
+                assert!(name.len() + value.len() < head_len);
+            }
+        }
+        Err((RequestError::PartialRequest, state)) => {
+            assert_eq!(state, State::Idle);
+            assert_eq!(buffer.as_ref(), data);
+        }
+        Err((_, state)) => assert_eq!(state, State::Closed),
+    }
+});
@@ -0,0 +1,124 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use ruvicorn_core::conn::http11::bound::{
+    Inbound, Outbound, RequestData, ResponseBody, ResponseStart,
+};
+use ruvicorn_core::conn::http11::conn::Http11Connection;
+use ruvicorn_core::conn::http11::state::State;
+
+/// Split `data` at `points`, each taken modulo the data length.
+pub fn split<'a>(data: &'a [u8], points: &[u16]) -> Vec<&'a [u8]> {
+    if data.is_empty() {
+        return vec![data];
+    }
+    let mut points: Vec<usize> = points.iter().map(|p| *p as usize % data.len()).collect();
+    points.sort_unstable();
+    points.dedup();
+
+    let mut parts = Vec::with_capacity(points.len() + 1);
+    let mut start = 0;
+    for point in points {
+        parts.push(&data[start..point]);
+        start = point;
+    }
+    parts.push(&data[start..]);
+    parts
+}
+
+/// What the application sees from a connection, independent of read sizes.
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    Head {
+        method: String,
+        path: String,
+        query_string: String,
+        headers: Vec<(Bytes, Bytes)>,
+    },
+    // Body parts are merged, read sizes only change how they are cut.
+    Body(Vec<u8>),
+    BodyEnd,
+    // Error response sent before closing.
+    RequestErr(Bytes),
+}
+
+fn push_body(events: &mut Vec<Event>, body: &[u8]) {
+    if body.is_empty() {
+        return;
+    }
+    if let Some(Event::Body(buffer)) = events.last_mut() {
+        buffer.extend_from_slice(body);
+    } else {
+        events.push(Event::Body(body.to_vec()));
+    }
+}
+
+// Answer the finished request so pipelined requests are parsed. The response
+// is framed, so the connection is kept alive if the request allows it.
+fn respond(conn: &mut Http11Connection) {
+    let start = conn.step(Inbound::ResponseStart(ResponseStart {
+        status: 200,
+        headers: vec![(
+            Bytes::from_static(b"Content-Length"),
+            Bytes::from_static(b"0"),
+        )],
+    }));
+    assert!(matches!(start, Outbound::ResponseStart(_)), "{:?}", start);
+    let body = conn.step(Inbound::ResponseBody(ResponseBody {
+        body: Bytes::new(),
+        more_body: false,
+    }));
+    assert!(matches!(body, Outbound::ResponseBody(_)), "{:?}", body);
+}
+
+fn feed(conn: &mut Http11Connection, data: &[u8], events: &mut Vec<Event>) {
+    let mut data = Bytes::copy_from_slice(data);
+    loop {
+        let inbound = Inbound::RequestData(RequestData {
+            data: std::mem::take(&mut data),
+        });
+        match conn.step(inbound) {
+            // Buffered body bytes are read with the next (empty) data.
+            Outbound::RequestHead(head) => events.push(Event::Head {
+                method: head.method,
+                path: head.path,
+                query_string: head.query_string,
+                headers: head.headers,
+            }),
+            Outbound::RequestBody(body) if body.more_body => {
+                push_body(events, &body.body);
+                return;
+            }
+            Outbound::RequestBody(body) => {
+                push_body(events, &body.body);
+                events.push(Event::BodyEnd);
+                respond(conn);
+                if conn.state() != State::Idle {
+                    return;
+                }
+            }
+            Outbound::PartialRequest => return,
+            Outbound::RequestErr(response) => {
+                assert_eq!(conn.state(), State::Closed);
+                events.push(Event::RequestErr(response.data));
+                return;
+            }
+            // Data after the connection is closed is dropped.
+            Outbound::InvalidState(e) => {
+                assert_eq!(e.state, State::Closed);
+                return;
+            }
+            output => panic!("unexpected output for request data: {:?}", output),
+        }
+    }
+}
+
+/// Feed every part through a fresh connection and collect the events.
+pub fn run_connection(parts: &[&[u8]]) -> Vec<Event> {
+    let mut conn = Http11Connection::new(Arc::default());
+    let mut events = Vec::new();
+    for part in parts {
+        feed(&mut conn, part, &mut events);
+    }
+    events
+}
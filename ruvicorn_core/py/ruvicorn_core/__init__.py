@@ -1 +1,3 @@
-from ._ruvicorn_core import Http11Protocol
\ No newline at end of file
+from ._ruvicorn_core import Server, ServerConfig, set_log_level
+
+__all__ = ["Server", "ServerConfig", "set_log_level"]
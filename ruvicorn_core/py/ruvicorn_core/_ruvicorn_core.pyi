@@ -0,0 +1,76 @@
+from typing import Any
+
+class ServerConfig:
+    host: str
+    port: int
+    backlog: int
+    uds: str | None
+    uds_mode: int
+    fd: int | None
+    max_headers: int
+    max_head_size: int
+    strict_headers: bool
+    timeout_keep_alive: float
+    timeout_request_head: float
+    timeout_request_body: float
+    keep_alive: bool
+    proxy_headers: bool
+    forwarded_allow_ips: list[str]
+    proxy_protocol: str
+    ssl_certfile: str | None
+    ssl_keyfile: str | None
+    ssl_ciphers: list[str]
+    ssl_min_version: str
+    ssl_ca_certs: str | None
+    ssl_cert_reqs: str
+    ssl_reload_interval: float
+    log_level: str
+    access_log: bool
+    access_log_format: str
+    root_path: str
+    allowed_hosts: list[str]
+    server_header: bool
+    date_header: bool
+
+    def __init__(
+        self,
+        *,
+        host: str = "127.0.0.1",
+        port: int = 8000,
+        backlog: int = 2048,
+        uds: str | None = None,
+        uds_mode: int = 0o666,
+        fd: int | None = None,
+        max_headers: int = 96,
+        max_head_size: int = 65536,
+        strict_headers: bool = False,
+        timeout_keep_alive: float = 5.0,
+        timeout_request_head: float = 10.0,
+        timeout_request_body: float = 30.0,
+        keep_alive: bool = True,
+        proxy_headers: bool = True,
+        forwarded_allow_ips: list[str] = ["127.0.0.1"],
+        proxy_protocol: str = "off",
+        ssl_certfile: str | None = None,
+        ssl_keyfile: str | None = None,
+        ssl_ciphers: list[str] = [],
+        ssl_min_version: str = "1.2",
+        ssl_ca_certs: str | None = None,
+        ssl_cert_reqs: str = "none",
+        ssl_reload_interval: float = 0.0,
+        log_level: str = "info",
+        access_log: bool = True,
+        access_log_format: str = "default",
+        root_path: str = "",
+        allowed_hosts: list[str] = [],
+        server_header: bool = True,
+        date_header: bool = True,
+    ) -> None: ...
+
+class Server:
+    def __init__(self, config: ServerConfig, app: Any, loop: Any) -> None: ...
+    def start(self) -> tuple[str, int | None]: ...
+    def close(self) -> None: ...
+    def reload_tls(self) -> None: ...
+
+def set_log_level(level: str) -> None: ...
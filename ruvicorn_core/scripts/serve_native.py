@@ -0,0 +1,35 @@
+from maturin import import_hook
+
+# install the import hook with default settings
+import_hook.install()
+# or you can specify bindings
+import_hook.install(bindings="pyo3")
+# and build in release mode instead of the default debug mode
+import_hook.install(release=True)
+
+
+from ruvicorn_core import Server, ServerConfig
+import asyncio as aio
+
+
+async def app(scope, receive, send):
+    body = b"Hello, world!"
+    await send({
+        "type": "http.response.start",
+        "status": 200,
+        "headers": [(b"content-length", str(len(body)).encode())],
+    })
+    await send({"type": "http.response.body", "body": body})
+
+
+async def main():
+    loop = aio.get_event_loop()
+
+    srv = Server(ServerConfig(host="127.0.0.1", port=8888), app, loop)
+    srv.start()
+    try:
+        await aio.Event().wait()
+    finally:
+        srv.close()
+
+aio.run(main(), debug=True)
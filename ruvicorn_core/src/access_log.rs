@@ -0,0 +1,246 @@
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+
+use crate::date::clf_time;
+use crate::logger::target;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessLogFormat {
+    // `127.0.0.1:50000 - "GET / HTTP/1.1" 200`, same as uvicorn.
+    Default,
+    // Apache combined log format.
+    Combined,
+    // One JSON object per line.
+    Json,
+}
+
+impl AccessLogFormat {
+    pub const NAMES: [&'static str; 3] = ["default", "combined", "json"];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "default" => Some(Self::Default),
+            "combined" => Some(Self::Combined),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::Combined => "combined",
+            Self::Json => "json",
+        }
+    }
+}
+
+/// Everything written to the access log about one request/response cycle.
+#[derive(Debug)]
+pub struct AccessRecord {
+    pub client: Option<SocketAddr>,
+    pub method: String,
+    pub path: String,
+    pub http_version: &'static str,
+    pub status: u16,
+    pub response_bytes: usize,
+    pub duration: Duration,
+    pub user_agent: Option<Bytes>,
+    pub referer: Option<Bytes>,
+    pub time: SystemTime,
+}
+
+impl AccessRecord {
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Default => self.format_default(),
+            AccessLogFormat::Combined => self.format_combined(),
+            AccessLogFormat::Json => self.format_json(),
+        }
+    }
+
+    fn format_default(&self) -> String {
+        let client = self
+            .client
+            .map_or_else(|| "-".to_owned(), |c| c.to_string());
+        format!(
+            "{} - \"{} {} HTTP/{}\" {}",
+            client, self.method, self.path, self.http_version, self.status
+        )
+    }
+
+    fn format_combined(&self) -> String {
+        let mut line = String::with_capacity(128);
+        let host = self
+            .client
+            .map_or_else(|| "-".to_owned(), |c| c.ip().to_string());
+        let _ = write!(
+            line,
+            "{} - - [{}] \"{} {} HTTP/{}\" {} ",
+            host,
+            clf_time(self.time),
+            self.method,
+            escape_quoted(self.path.as_bytes()),
+            self.http_version,
+            self.status
+        );
+        if self.response_bytes == 0 {
+            line.push('-');
+        } else {
+            let _ = write!(line, "{}", self.response_bytes);
+        }
+        for value in [&self.referer, &self.user_agent] {
+            let value = value
+                .as_deref()
+                .map_or_else(|| "-".to_owned(), escape_quoted);
+            let _ = write!(line, " \"{}\"", value);
+        }
+        line
+    }
+
+    fn format_json(&self) -> String {
+        let mut line = String::with_capacity(256);
+        line.push('{');
+        let client = self.client.map(|c| c.to_string());
+        push_json_field(&mut line, "client", client.as_deref().map(str::as_bytes));
+        line.push(',');
+        push_json_field(&mut line, "method", Some(self.method.as_bytes()));
+        line.push(',');
+        push_json_field(&mut line, "path", Some(self.path.as_bytes()));
+        line.push(',');
+        push_json_field(
+            &mut line,
+            "http_version",
+            Some(self.http_version.as_bytes()),
+        );
+        let _ = write!(
+            line,
+            ",\"status\":{},\"response_bytes\":{},\"duration_ms\":{:.3},",
+            self.status,
+            self.response_bytes,
+            self.duration.as_secs_f64() * 1000.0
+        );
+        push_json_field(&mut line, "user_agent", self.user_agent.as_deref());
+        line.push(',');
+        push_json_field(&mut line, "referer", self.referer.as_deref());
+        line.push('}');
+        line
+    }
+}
+
+/// Write the access log record if access logging is enabled.
+///
+/// Nothing is formatted when `ruvicorn.access` is filtered out.
+pub fn emit(record: &AccessRecord, format: AccessLogFormat) {
+    if log::log_enabled!(target: target::ACCESS, log::Level::Info) {
+        log::info!(target: target::ACCESS, "{}", record.format(format));
+    }
+}
+
+// Quoted field of the Apache log format, escaping like mod_log_config does.
+fn escape_quoted(value: &[u8]) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for &b in value {
+        match b {
+            b'"' => escaped.push_str("\\\""),
+            b'\\' => escaped.push_str("\\\\"),
+            0x20..=0x7e => escaped.push(b as char),
+            _ => {
+                let _ = write!(escaped, "\\x{:02x}", b);
+            }
+        }
+    }
+    escaped
+}
+
+fn push_json_field(line: &mut String, name: &str, value: Option<&[u8]>) {
+    let _ = write!(line, "\"{}\":", name);
+    let value = match value {
+        Some(value) => value,
+        None => {
+            line.push_str("null");
+            return;
+        }
+    };
+    line.push('"');
+    for c in String::from_utf8_lossy(value).chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(line, "\\u{:04x}", c as u32);
+            }
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn record() -> AccessRecord {
+        AccessRecord {
+            client: Some("127.0.0.1:50000".parse().unwrap()),
+            method: "GET".to_owned(),
+            path: "/test?a=\"b\"".to_owned(),
+            http_version: "1.1",
+            status: 200,
+            response_bytes: 1234,
+            duration: Duration::from_micros(1500),
+            user_agent: Some(Bytes::from_static(b"curl/8.0")),
+            referer: None,
+            time: UNIX_EPOCH + Duration::from_secs(971_186_136),
+        }
+    }
+
+    #[test]
+    fn test_default_format() {
+        assert_eq!(
+            record().format(AccessLogFormat::Default),
+            "127.0.0.1:50000 - \"GET /test?a=\"b\" HTTP/1.1\" 200"
+        );
+    }
+
+    #[test]
+    fn test_combined_format() {
+        assert_eq!(
+            record().format(AccessLogFormat::Combined),
+            "127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] \"GET /test?a=\\\"b\\\" HTTP/1.1\" 200 1234 \"-\" \"curl/8.0\""
+        );
+    }
+
+    #[test]
+    fn test_combined_format_without_body() {
+        let record = AccessRecord {
+            client: None,
+            response_bytes: 0,
+            ..record()
+        };
+
+        assert!(record
+            .format(AccessLogFormat::Combined)
+            .starts_with("- - - ["));
+        assert!(record.format(AccessLogFormat::Combined).contains(" 200 - "));
+    }
+
+    #[test]
+    fn test_json_format() {
+        assert_eq!(
+            record().format(AccessLogFormat::Json),
+            concat!(
+                r#"{"client":"127.0.0.1:50000","method":"GET","path":"/test?a=\"b\"","#,
+                r#""http_version":"1.1","status":200,"response_bytes":1234,"duration_ms":1.500,"#,
+                r#""user_agent":"curl/8.0","referer":null}"#
+            )
+        );
+    }
+}
@@ -0,0 +1,57 @@
+use std::fmt::Debug;
+use std::time::Instant;
+
+/// Source of the current time for connection deadlines.
+///
+/// Connections never read the system time directly so timeouts can be tested
+/// by moving a fake clock instead of sleeping.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Default)]
+pub struct MonotonicClock;
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use super::Clock;
+
+    /// Clock that only moves when told to.
+    #[derive(Debug)]
+    pub struct ManualClock {
+        now: Mutex<Instant>,
+    }
+
+    impl ManualClock {
+        pub fn new() -> Self {
+            Self {
+                now: Mutex::new(Instant::now()),
+            }
+        }
+
+        pub fn advance(&self, duration: Duration) {
+            *self.now.lock().unwrap() += duration;
+        }
+    }
+
+    impl Default for ManualClock {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+    }
+}
@@ -0,0 +1,651 @@
+// Emitted from inside the pyo3 0.19 `#[pymethods]` expansion, which an
+// attribute on the impl doesn't reach.
+#![allow(non_local_definitions)]
+
+use std::fmt;
+use std::time::Duration;
+use std::{error::Error, fmt::Display};
+
+use log::LevelFilter;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use crate::access_log::AccessLogFormat;
+use crate::conn::proxy_protocol::ProxyProtocolMode;
+use crate::conn::tls::{self, ClientCertMode};
+use crate::logger;
+use crate::proxy;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    // Value must be greater than zero.
+    Zero(&'static str),
+    // Timeout is negative, NaN or infinite.
+    InvalidTimeout(&'static str, f64),
+    InvalidLogLevel(String),
+    InvalidAccessLogFormat(String),
+    InvalidProxyAddress(String),
+    InvalidAllowedHost(String),
+    InvalidProxyProtocol(String),
+    InvalidTls(String),
+    InvalidClientCertMode(String),
+    InvalidSocket(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Zero(name) => write!(f, "{} must be greater than 0", name),
+            Self::InvalidTimeout(name, value) => {
+                write!(
+                    f,
+                    "{} must be a non-negative finite number, got {}",
+                    name, value
+                )
+            }
+            Self::InvalidLogLevel(level) => write!(
+                f,
+                "log_level must be one of {}, got {:?}",
+                logger::LEVELS.join(", "),
+                level
+            ),
+            Self::InvalidAccessLogFormat(format) => write!(
+                f,
+                "access_log_format must be one of {}, got {:?}",
+                AccessLogFormat::NAMES.join(", "),
+                format
+            ),
+            Self::InvalidProxyAddress(addr) => {
+                write!(f, "forwarded_allow_ips has invalid address {:?}", addr)
+            }
+            Self::InvalidProxyProtocol(mode) => write!(
+                f,
+                "proxy_protocol must be one of {}, got {:?}",
+                ProxyProtocolMode::NAMES.join(", "),
+                mode
+            ),
+            Self::InvalidAllowedHost(host) => {
+                write!(f, "allowed_hosts has invalid host pattern {:?}", host)
+            }
+            Self::InvalidTls(reason) => write!(f, "invalid TLS settings: {}", reason),
+            Self::InvalidClientCertMode(mode) => write!(
+                f,
+                "ssl_cert_reqs must be one of {}, got {:?}",
+                ClientCertMode::NAMES.join(", "),
+                mode
+            ),
+            Self::InvalidSocket(reason) => write!(f, "invalid socket settings: {}", reason),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl From<ConfigError> for PyErr {
+    fn from(error: ConfigError) -> Self {
+        PyValueError::new_err(error.to_string())
+    }
+}
+
+fn timeout(name: &'static str, secs: f64) -> Result<Duration, ConfigError> {
+    Duration::try_from_secs_f64(secs).map_err(|_| ConfigError::InvalidTimeout(name, secs))
+}
+
+/// Server wide settings shared by every connection.
+///
+/// Built once (from Python keyword arguments or `ServerConfig::default()`),
+/// validated on construction and never mutated afterwards, so connections can
+/// share it through an `Arc`.
+#[pyclass(frozen, module = "ruvicorn_core")]
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    #[pyo3(get)]
+    pub host: String,
+    #[pyo3(get)]
+    pub port: u16,
+    #[pyo3(get)]
+    pub backlog: u32,
+    /// Unix socket path to listen on instead of `host` and `port`. A socket
+    /// file left by a server that is gone is replaced.
+    #[pyo3(get)]
+    pub uds: Option<String>,
+    /// Permission bits of the `uds` socket file.
+    #[pyo3(get)]
+    pub uds_mode: u32,
+    /// Listening socket, TCP or Unix, to accept on instead of binding. The
+    /// server uses a duplicate, `fd` itself stays open.
+    #[pyo3(get)]
+    pub fd: Option<i32>,
+
+    // Limits
+    /// Maximum number of headers in a request head.
+    #[pyo3(get)]
+    pub max_headers: usize,
+    /// Maximum size of a request head in bytes, request line included.
+    #[pyo3(get)]
+    pub max_head_size: usize,
+    /// Reject header values with obs-text (bytes over 0x7f) instead of
+    /// passing them to the app.
+    #[pyo3(get)]
+    pub strict_headers: bool,
+
+    // Timeouts
+    /// Wait for the first byte of a request, on new and kept alive
+    /// connections.
+    pub timeout_keep_alive: Duration,
+    /// Wait for the whole request head, from its first byte.
+    pub timeout_request_head: Duration,
+    pub timeout_request_body: Duration,
+
+    // Connection
+    #[pyo3(get)]
+    pub keep_alive: bool,
+
+    // Proxy
+    #[pyo3(get)]
+    pub proxy_headers: bool,
+    /// Proxies whose `Forwarded` and `X-Forwarded-*` headers, and PROXY
+    /// protocol headers, are trusted. Addresses, CIDR networks like
+    /// `10.0.0.0/8`, or `*` for any.
+    #[pyo3(get)]
+    pub forwarded_allow_ips: Vec<String>,
+    /// Whether connections start with a HAProxy PROXY v1/v2 header. Only
+    /// read from peers in `forwarded_allow_ips`.
+    pub proxy_protocol: ProxyProtocolMode,
+
+    // TLS
+    /// PEM certificate chain, leaf first. TLS is enabled when set together
+    /// with `ssl_keyfile`.
+    #[pyo3(get)]
+    pub ssl_certfile: Option<String>,
+    #[pyo3(get)]
+    pub ssl_keyfile: Option<String>,
+    /// Cipher suite names like `TLS13_AES_256_GCM_SHA384`. Empty keeps the
+    /// rustls defaults.
+    #[pyo3(get)]
+    pub ssl_ciphers: Vec<String>,
+    /// Lowest TLS version accepted, `1.2` or `1.3`.
+    #[pyo3(get)]
+    pub ssl_min_version: String,
+    /// PEM bundle of the CAs client certificates are checked against.
+    #[pyo3(get)]
+    pub ssl_ca_certs: Option<String>,
+    pub ssl_cert_reqs: ClientCertMode,
+    /// How often the certificate, key and CA files are checked for changes.
+    /// Zero disables the check, `Server.reload_tls()` still works.
+    pub ssl_reload_interval: Duration,
+
+    // Logging
+    /// Level of records forwarded to Python `logging`, applied when a
+    /// `Server` is created.
+    #[pyo3(get)]
+    pub log_level: String,
+    #[pyo3(get)]
+    pub access_log: bool,
+    pub access_log_format: AccessLogFormat,
+
+    // Protocol
+    #[pyo3(get)]
+    pub root_path: String,
+    /// Hosts the server answers for, port excluded. `*.example.com` matches
+    /// subdomains and `*` any host. Empty allows any host.
+    #[pyo3(get)]
+    pub allowed_hosts: Vec<String>,
+    /// Add `Server: ruvicorn` to responses that don't have a `Server`.
+    #[pyo3(get)]
+    pub server_header: bool,
+    /// Add the current `Date` to responses that don't have one.
+    #[pyo3(get)]
+    pub date_header: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_owned(),
+            port: 8000,
+            backlog: 2048,
+            uds: None,
+            uds_mode: 0o666,
+            fd: None,
+            max_headers: 96,
+            max_head_size: 64 * 1024,
+            strict_headers: false,
+            timeout_keep_alive: Duration::from_secs(5),
+            timeout_request_head: Duration::from_secs(10),
+            timeout_request_body: Duration::from_secs(30),
+            keep_alive: true,
+            proxy_headers: true,
+            forwarded_allow_ips: vec!["127.0.0.1".to_owned()],
+            proxy_protocol: ProxyProtocolMode::Off,
+            ssl_certfile: None,
+            ssl_keyfile: None,
+            ssl_ciphers: Vec::new(),
+            ssl_min_version: "1.2".to_owned(),
+            ssl_ca_certs: None,
+            ssl_cert_reqs: ClientCertMode::None,
+            ssl_reload_interval: Duration::ZERO,
+            log_level: "info".to_owned(),
+            access_log: true,
+            access_log_format: AccessLogFormat::Default,
+            root_path: String::new(),
+            allowed_hosts: Vec::new(),
+            server_header: true,
+            date_header: true,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.backlog == 0 {
+            return Err(ConfigError::Zero("backlog"));
+        }
+        if self.uds.is_some() && self.fd.is_some() {
+            return Err(ConfigError::InvalidSocket(
+                "uds and fd can't be set together".to_owned(),
+            ));
+        }
+        if self.uds.as_deref() == Some("") {
+            return Err(ConfigError::InvalidSocket("uds is empty".to_owned()));
+        }
+        if self.uds_mode > 0o777 {
+            return Err(ConfigError::InvalidSocket(format!(
+                "uds_mode must be at most 0o777, got {:#o}",
+                self.uds_mode
+            )));
+        }
+        if self.fd.is_some_and(|fd| fd < 0) {
+            return Err(ConfigError::InvalidSocket(
+                "fd must not be negative".to_owned(),
+            ));
+        }
+        if self.max_headers == 0 {
+            return Err(ConfigError::Zero("max_headers"));
+        }
+        if self.max_head_size == 0 {
+            return Err(ConfigError::Zero("max_head_size"));
+        }
+        if logger::parse_level(&self.log_level).is_none() {
+            return Err(ConfigError::InvalidLogLevel(self.log_level.clone()));
+        }
+        for addr in &self.forwarded_allow_ips {
+            if !proxy::is_valid_entry(addr) {
+                return Err(ConfigError::InvalidProxyAddress(addr.clone()));
+            }
+        }
+        for host in &self.allowed_hosts {
+            let name = host.strip_prefix("*.").unwrap_or(host);
+            let bad_port = name.contains(':') && !name.starts_with('[');
+            if host != "*" && (name.is_empty() || name.contains(['*', '/']) || bad_port) {
+                return Err(ConfigError::InvalidAllowedHost(host.clone()));
+            }
+        }
+        if self.ssl_certfile.is_some() != self.ssl_keyfile.is_some() {
+            return Err(ConfigError::InvalidTls(
+                "ssl_certfile and ssl_keyfile must be set together".to_owned(),
+            ));
+        }
+        if self.ssl_cert_reqs != ClientCertMode::None && self.ssl_ca_certs.is_none() {
+            return Err(ConfigError::InvalidTls(
+                "ssl_cert_reqs needs ssl_ca_certs".to_owned(),
+            ));
+        }
+        tls::validate(self).map_err(|e| ConfigError::InvalidTls(e.to_string()))?;
+        Ok(())
+    }
+
+    /// `log_level` as a filter. It is checked by `validate`.
+    pub fn log_level_filter(&self) -> LevelFilter {
+        logger::parse_level(&self.log_level).unwrap_or(LevelFilter::Info)
+    }
+
+    /// Whether `host` (port excluded) matches `allowed_hosts`.
+    pub fn is_allowed_host(&self, host: &str) -> bool {
+        if self.allowed_hosts.is_empty() {
+            return true;
+        }
+        self.allowed_hosts.iter().any(|pattern| {
+            if pattern == "*" {
+                return true;
+            }
+            match pattern.strip_prefix('*') {
+                // `.example.com`, the leading dot keeps `badexample.com` out.
+                Some(suffix) => {
+                    host.len() > suffix.len()
+                        && host.is_char_boundary(host.len() - suffix.len())
+                        && host[host.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+                }
+                None => host.eq_ignore_ascii_case(pattern),
+            }
+        })
+    }
+}
+
+#[pymethods]
+impl ServerConfig {
+    #[new]
+    #[pyo3(signature = (
+        *,
+        host = "127.0.0.1".to_owned(),
+        port = 8000,
+        backlog = 2048,
+        uds = None,
+        uds_mode = 0o666,
+        fd = None,
+        max_headers = 96,
+        max_head_size = 64 * 1024,
+        strict_headers = false,
+        timeout_keep_alive = 5.0,
+        timeout_request_head = 10.0,
+        timeout_request_body = 30.0,
+        keep_alive = true,
+        proxy_headers = true,
+        forwarded_allow_ips = vec!["127.0.0.1".to_owned()],
+        proxy_protocol = "off",
+        ssl_certfile = None,
+        ssl_keyfile = None,
+        ssl_ciphers = Vec::new(),
+        ssl_min_version = "1.2".to_owned(),
+        ssl_ca_certs = None,
+        ssl_cert_reqs = "none",
+        ssl_reload_interval = 0.0,
+        log_level = "info".to_owned(),
+        access_log = true,
+        access_log_format = "default",
+        root_path = String::new(),
+        allowed_hosts = Vec::new(),
+        server_header = true,
+        date_header = true,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn py_new(
+        host: String,
+        port: u16,
+        backlog: u32,
+        uds: Option<String>,
+        uds_mode: u32,
+        fd: Option<i32>,
+        max_headers: usize,
+        max_head_size: usize,
+        strict_headers: bool,
+        timeout_keep_alive: f64,
+        timeout_request_head: f64,
+        timeout_request_body: f64,
+        keep_alive: bool,
+        proxy_headers: bool,
+        forwarded_allow_ips: Vec<String>,
+        proxy_protocol: &str,
+        ssl_certfile: Option<String>,
+        ssl_keyfile: Option<String>,
+        ssl_ciphers: Vec<String>,
+        ssl_min_version: String,
+        ssl_ca_certs: Option<String>,
+        ssl_cert_reqs: &str,
+        ssl_reload_interval: f64,
+        log_level: String,
+        access_log: bool,
+        access_log_format: &str,
+        root_path: String,
+        allowed_hosts: Vec<String>,
+        server_header: bool,
+        date_header: bool,
+    ) -> PyResult<Self> {
+        let config = Self {
+            host,
+            port,
+            backlog,
+            uds,
+            uds_mode,
+            fd,
+            max_headers,
+            max_head_size,
+            strict_headers,
+            timeout_keep_alive: timeout("timeout_keep_alive", timeout_keep_alive)?,
+            timeout_request_head: timeout("timeout_request_head", timeout_request_head)?,
+            timeout_request_body: timeout("timeout_request_body", timeout_request_body)?,
+            keep_alive,
+            proxy_headers,
+            forwarded_allow_ips,
+            proxy_protocol: ProxyProtocolMode::parse(proxy_protocol)
+                .ok_or_else(|| ConfigError::InvalidProxyProtocol(proxy_protocol.to_owned()))?,
+            ssl_certfile,
+            ssl_keyfile,
+            ssl_ciphers,
+            ssl_min_version,
+            ssl_ca_certs,
+            ssl_cert_reqs: ClientCertMode::parse(ssl_cert_reqs)
+                .ok_or_else(|| ConfigError::InvalidClientCertMode(ssl_cert_reqs.to_owned()))?,
+            ssl_reload_interval: timeout("ssl_reload_interval", ssl_reload_interval)?,
+            log_level: log_level.to_ascii_lowercase(),
+            access_log,
+            access_log_format: AccessLogFormat::parse(access_log_format)
+                .ok_or_else(|| ConfigError::InvalidAccessLogFormat(access_log_format.to_owned()))?,
+            root_path,
+            allowed_hosts,
+            server_header,
+            date_header,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[getter]
+    fn timeout_keep_alive(&self) -> f64 {
+        self.timeout_keep_alive.as_secs_f64()
+    }
+
+    #[getter]
+    fn timeout_request_head(&self) -> f64 {
+        self.timeout_request_head.as_secs_f64()
+    }
+
+    #[getter]
+    fn timeout_request_body(&self) -> f64 {
+        self.timeout_request_body.as_secs_f64()
+    }
+
+    #[getter]
+    fn access_log_format(&self) -> &'static str {
+        self.access_log_format.name()
+    }
+
+    #[getter]
+    fn proxy_protocol(&self) -> &'static str {
+        self.proxy_protocol.name()
+    }
+
+    #[getter]
+    fn ssl_cert_reqs(&self) -> &'static str {
+        self.ssl_cert_reqs.name()
+    }
+
+    #[getter]
+    fn ssl_reload_interval(&self) -> f64 {
+        self.ssl_reload_interval.as_secs_f64()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "ServerConfig(host={:?}, port={}, keep_alive={}, log_level={:?})",
+            self.host, self.port, self.keep_alive, self.log_level
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pyo3::types::PyDict;
+
+    #[test]
+    fn test_default_is_valid() {
+        assert!(ServerConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_zero_max_headers() {
+        let config = ServerConfig {
+            max_headers: 0,
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::Zero(name)) if name == "max_headers"
+        ));
+    }
+
+    #[test]
+    fn test_invalid_proxy_address() {
+        let config = ServerConfig {
+            forwarded_allow_ips: vec!["not-an-ip".to_owned()],
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidProxyAddress(_))
+        ));
+    }
+
+    #[test]
+    fn test_proxy_networks() {
+        let config = ServerConfig {
+            forwarded_allow_ips: vec!["10.0.0.0/8".to_owned(), "fd00::/8".to_owned()],
+            ..Default::default()
+        };
+
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_socket() {
+        let config = ServerConfig {
+            uds: Some("/tmp/ruvicorn.sock".to_owned()),
+            fd: Some(3),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidSocket(_))
+        ));
+
+        let config = ServerConfig {
+            uds_mode: 0o1777,
+            ..Default::default()
+        };
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "invalid socket settings: uds_mode must be at most 0o777, got 0o1777"
+        );
+
+        let config = ServerConfig {
+            fd: Some(-1),
+            ..Default::default()
+        };
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::InvalidSocket(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_tls() {
+        let config = ServerConfig {
+            ssl_certfile: Some("cert.pem".to_owned()),
+            ..Default::default()
+        };
+        assert!(matches!(config.validate(), Err(ConfigError::InvalidTls(_))));
+
+        let config = ServerConfig {
+            ssl_min_version: "1.0".to_owned(),
+            ..Default::default()
+        };
+        assert!(matches!(config.validate(), Err(ConfigError::InvalidTls(_))));
+
+        let config = ServerConfig {
+            ssl_cert_reqs: ClientCertMode::Required,
+            ..Default::default()
+        };
+        assert!(matches!(config.validate(), Err(ConfigError::InvalidTls(_))));
+    }
+
+    #[test]
+    fn test_invalid_allowed_host() {
+        for host in ["", "*.", "a.*.com", "example.com:8000"] {
+            let config = ServerConfig {
+                allowed_hosts: vec![host.to_owned()],
+                ..Default::default()
+            };
+
+            assert!(
+                matches!(config.validate(), Err(ConfigError::InvalidAllowedHost(_))),
+                "{:?}",
+                host
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_allowed_host() {
+        let config = ServerConfig {
+            allowed_hosts: vec!["example.com".to_owned(), "*.example.org".to_owned()],
+            ..Default::default()
+        };
+
+        assert!(config.is_allowed_host("example.com"));
+        assert!(config.is_allowed_host("EXAMPLE.com"));
+        assert!(config.is_allowed_host("api.example.org"));
+        assert!(config.is_allowed_host("a.b.example.org"));
+        assert!(!config.is_allowed_host("example.org"));
+        assert!(!config.is_allowed_host("badexample.org"));
+        assert!(!config.is_allowed_host("api.example.com"));
+        assert!(ServerConfig::default().is_allowed_host("anything"));
+    }
+
+    #[test]
+    fn test_log_level_filter() {
+        let config = ServerConfig {
+            log_level: "warning".to_owned(),
+            ..Default::default()
+        };
+
+        assert_eq!(config.log_level_filter(), LevelFilter::Warn);
+        assert_eq!(
+            ServerConfig::default().log_level_filter(),
+            LevelFilter::Info
+        );
+    }
+
+    #[test]
+    fn test_from_kwargs() {
+        Python::with_gil(|py| {
+            let cls = py.get_type::<ServerConfig>();
+            let kwargs = PyDict::new(py);
+            kwargs.set_item("port", 9000).unwrap();
+            kwargs.set_item("timeout_keep_alive", 1.5).unwrap();
+            kwargs.set_item("log_level", "DEBUG").unwrap();
+
+            let config: ServerConfig = cls.call((), Some(kwargs)).unwrap().extract().unwrap();
+
+            assert_eq!(config.port, 9000);
+            assert_eq!(config.timeout_keep_alive, Duration::from_millis(1500));
+            assert_eq!(config.log_level, "debug");
+            assert_eq!(config.max_headers, 96);
+        });
+    }
+
+    #[test]
+    fn test_from_kwargs_invalid() {
+        Python::with_gil(|py| {
+            let cls = py.get_type::<ServerConfig>();
+            let kwargs = PyDict::new(py);
+            kwargs.set_item("timeout_request_head", -1.0).unwrap();
+
+            let err = cls.call((), Some(kwargs)).unwrap_err();
+
+            assert!(err.is_instance_of::<PyValueError>(py));
+        });
+    }
+}
@@ -1,5 +1,7 @@
 use bytes::Bytes;
 
+use super::state::InvalidState;
+
 type Header = (Bytes, Bytes);
 
 #[derive(Debug)]
@@ -20,27 +22,68 @@ pub struct ResponseBody {
 }
 
 #[derive(Debug)]
-#[allow(dead_code)]
 pub enum Inbound {
     // Feed data
     RequestData(RequestData),
     // Notice physical connection is closed.
     Disconnect,
+    // Check deadline with the connection clock.
+    Tick,
 
     ResponseStart(ResponseStart),
     ResponseBody(ResponseBody),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpVersion {
+    Http10,
+    Http11,
+}
+
+impl HttpVersion {
+    // `http_version` of the ASGI scope.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Http10 => "1.0",
+            Self::Http11 => "1.1",
+        }
+    }
+}
+
+/// Form of the request-target, RFC 9112 section 3.2.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TargetForm {
+    // `/path?query`
+    Origin,
+    // `http://host/path?query`, mostly sent to proxies.
+    Absolute,
+    // `host:port`, only for CONNECT.
+    Authority,
+    // `*`, only for server-wide OPTIONS.
+    Asterisk,
+}
+
 #[derive(Debug)]
 pub struct RequestHead {
     pub method: String,
+    pub target: TargetForm,
+    // Target without the query. Authority for authority-form and `*` for
+    // asterisk-form.
     pub path: String,
+    // Part after `?`, without it.
+    pub query_string: String,
+    // Host header, or the target authority when there is none. Only HTTP/1.0
+    // requests with origin-form or asterisk-form target have no host.
+    pub host: Option<String>,
+    pub version: HttpVersion,
     pub headers: Vec<Header>,
 }
 
+/// Part of a request body, same as the ASGI `http.request` event.
 #[derive(Debug)]
 pub struct RequestBody {
     pub body: Bytes,
+    // `true` while more body is expected, `false` on the last part.
     pub more_body: bool,
 }
 
@@ -50,14 +93,13 @@ pub struct ResponseData {
 }
 
 #[derive(Debug)]
-#[allow(dead_code)]
 pub enum Outbound {
     // Request is not finished or invalid.
 
     // Need more data to finish request.
     PartialRequest,
-    // Request do not object HTTP spec.
-    RequestErr,
+    // Request do not object HTTP spec. Send the error response and close.
+    RequestErr(ResponseData),
 
     // Request is finished.
 
@@ -65,12 +107,22 @@ pub enum Outbound {
     RequestHead(RequestHead),
     RequestBody(RequestBody),
 
-    // Invalid response spec
-    ReseponseErr,
-
-    // Need more data to finish response
-    PartialResponse,
+    // Invalid response spec. Holds the 500 response to send before closing,
+    // if the response head is not sent yet.
+    ReseponseErr(Option<ResponseData>),
 
     ResponseStart(ResponseData),
     ResponseBody(ResponseData),
+
+    // Deadline is not passed yet.
+    Pending,
+    // Request head or body is not arrived in time. Send 408 response and close.
+    RequestTimeout(ResponseData),
+    // No new request on kept alive connection. Close without response.
+    KeepAliveTimeout,
+
+    // Connection is closed. Nothing more to send.
+    Closed,
+    // Inbound is not allowed in the current state. State is not changed.
+    InvalidState(InvalidState),
 }
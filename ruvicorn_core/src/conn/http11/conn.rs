@@ -1,347 +1,457 @@
-use bytes::{BufMut, Bytes, BytesMut};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::types::{PyHeader, RsHeader};
+use bytes::{Bytes, BytesMut};
+use http::StatusCode;
 
-use super::{
-    payload::{Payload as _, PayloadStepResult, PayloadType},
-    state::State,
-};
-
-const MAX_HEADERS: usize = 16;
-
-macro_rules! test_trace {
-    ($code:expr, $msg:expr) => {
-        #[cfg(test)]
-        {
-            println!("{}: {}", $code, $msg);
-        }
-    };
-}
+use crate::access_log::{self, AccessRecord};
+use crate::clock::{Clock, MonotonicClock};
+use crate::config::ServerConfig;
+use crate::conn::proxy_protocol::{self, Decoded, ProxyHeader, ProxyProtocolMode};
+use crate::handle::Handle;
+use crate::logger::target;
+use crate::proxy::TrustedProxies;
 
-#[derive(Debug)]
-#[allow(dead_code)]
-enum Input<'t> {
-    // Feed data
-    RequestData(&'t [u8]),
-    // Notice physical connection is closed.
-    Disconnect,
-
-    ResponseStart {
-        status: usize,
-        headers: Vec<PyHeader<'t>>,
-    },
-    ResponseBody {
-        body: &'t [u8],
-        more_body: bool,
-    },
-}
-
-#[derive(Debug)]
-#[allow(dead_code)]
-enum Output {
-    // Request is not finished or invalid.
-
-    // Need more data to finish request.
-    PartialRequest,
-    // Request do not object HTTP spec.
-    RequestErr,
-
-    // Request is finished.
-
-    // Request finished with Content-Length header.
-    RequestHead {
-        method: String,
-        path: String,
-        headers: Vec<RsHeader>,
+use super::{
+    bound::{
+        Inbound, Outbound, RequestData, RequestHead, ResponseBody, ResponseData, ResponseStart,
     },
-    RequestBody(Bytes, bool),
-
-    // Invalid response spec
-    ReseponseErr,
-
-    // Need more data to finish response
-    PartialResponse,
+    payload_handle::{LengthedPayload, PayloadError, PayloadType},
+    request::{KeepAlive, Request, RequestError},
+    response::{error_response, ResponseError, ResponseHead, ResponsePayload},
+    state::{Event, InvalidState, State},
+};
 
-    ResponseStart(Bytes),
-    ResponseBody(Bytes),
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timer {
+    // Waiting for the first byte of a request, on a new or kept alive
+    // connection.
+    KeepAlive,
+    // Waiting for the whole request head, started from its first byte.
+    RequestHead,
+    // Waiting for the next request body chunk.
+    RequestBody,
 }
 
-#[allow(dead_code)]
-impl Output {
-    fn is_request_head_finished(&self) -> bool {
-        return match self {
-            Self::PartialRequest => false,
-            Self::RequestErr => false,
-            _ => true,
-        };
-    }
-
-    #[allow(dead_code)]
-    fn is_error(&self) -> bool {
-        return match self {
-            Self::RequestErr => true,
-            _ => false,
-        };
-    }
+// Payload of a request without Content-Length and Transfer-Encoding.
+fn no_payload() -> PayloadType {
+    PayloadType::Lenghthed(LengthedPayload { remaining: 0 })
 }
 
+/// HTTP/1.1 connection driver.
+///
+/// Every step is delegated to the `Handle` implementations: `Request` for the
+/// head, `PayloadType` for the body and `ResponseHead`/`ResponsePayload` for
+/// the response. The driver only keeps buffers, state, deadlines and the
+/// access record between steps.
+///
+/// Body bytes that arrived with the head stay in the request buffer. Feed
+/// empty `RequestData` after `RequestHead` to get them. Data received while
+/// the response is sent is buffered as the next pipelined request, and is
+/// parsed by feeding empty `RequestData` once the connection is idle again.
+///
+/// Inbounds not allowed by `State::check` return `Outbound::InvalidState`
+/// and leave the connection untouched. `step` never panics.
 #[derive(Debug)]
-pub enum KeepAlive {
-    KeepAlive,
-    Close,
-    None,
-}
-
-struct Http11Connection {
+pub struct Http11Connection {
+    config: Arc<ServerConfig>,
+    clock: Arc<dyn Clock>,
+    deadline: Option<(Timer, Instant)>,
     req_buffer: BytesMut,
     res_buffer: BytesMut,
     state: State,
-    offset: usize,
-    keep_alive: KeepAlive,
+    request: Request,
     payload: PayloadType,
+    response_head: ResponseHead,
+    response_payload: ResponsePayload,
+    keep_alive: KeepAlive,
+    client: Option<SocketAddr>,
+    server: Option<SocketAddr>,
+    // PROXY header is expected before the first request.
+    proxy_pending: bool,
+    proxy_header: Option<ProxyHeader>,
+    // Peers allowed to send a PROXY header.
+    proxies: TrustedProxies,
+    // Current request for the access log, with the time it started.
+    access: Option<(Instant, AccessRecord)>,
 }
 
-mod special_headers {
-    pub const CONTENT_LENGTH: &str = "Content-Length";
-    pub const TRANSFER_ENCODING: &str = "Transfer-Encoding";
-    pub const CONNECTION: &str = "Connection";
-    #[allow(dead_code)]
-    pub const UPGRADE: &str = "Upgrade";
-}
-
-fn cast_header_to_rs_header(buffer: &BytesMut, header: &httparse::Header) -> RsHeader {
-    let buf_ptr = buffer.as_ptr() as usize;
-    dbg!(buf_ptr);
-
-    let name_ptr = header.name.as_ptr() as usize;
-    dbg!(name_ptr);
-    let name = buffer
-        .clone()
-        .split_off(name_ptr - buf_ptr)
-        .split_to(header.name.len())
-        .freeze();
-
-    let value_ptr = header.value.as_ptr() as usize;
-    dbg!(value_ptr);
-    let value = buffer
-        .clone()
-        .split_off(value_ptr - buf_ptr)
-        .split_to(header.value.len())
-        .freeze();
-
-    return (name, value);
-}
-
-fn cast_headers_to_rs_headers(buffer: &BytesMut, headers: &[httparse::Header]) -> Vec<RsHeader> {
-    let mut ret: Vec<RsHeader> = Vec::new();
-    ret.reserve(headers.len());
-
-    for header in headers {
-        if header.name.is_empty() {
-            break;
-        }
-        ret.push(cast_header_to_rs_header(buffer, header))
+impl Http11Connection {
+    pub fn new(config: Arc<ServerConfig>) -> Self {
+        Self::with_clock(config, Arc::new(MonotonicClock))
     }
 
-    return ret;
-}
-
-#[allow(dead_code)]
-impl Http11Connection {
-    fn new() -> Self {
+    pub fn with_clock(config: Arc<ServerConfig>, clock: Arc<dyn Clock>) -> Self {
+        // Like a kept alive connection, the head timer starts on the first byte.
+        let deadline = Some((Timer::KeepAlive, clock.now() + config.timeout_keep_alive));
         Self {
+            request: Request::new(config.clone()),
+            clock,
+            deadline,
             req_buffer: BytesMut::new(),
             res_buffer: BytesMut::new(),
             state: State::Idle,
-            offset: 0,
+            payload: no_payload(),
+            response_head: ResponseHead::default(),
+            response_payload: ResponsePayload::default(),
             keep_alive: KeepAlive::None,
-            payload: PayloadType::new_none(),
+            client: None,
+            server: None,
+            proxy_pending: config.proxy_protocol != ProxyProtocolMode::Off,
+            proxy_header: None,
+            proxies: TrustedProxies::new(&config.forwarded_allow_ips),
+            access: None,
+            config,
         }
     }
 
-    fn _iterate_headers(
-        &self,
-        headers: &[httparse::Header],
-    ) -> Result<(PayloadType, KeepAlive), ()> {
-        let mut content_length: u64 = 0;
+    pub fn set_client(&mut self, client: SocketAddr) {
+        self.client = Some(client);
+    }
+
+    pub fn set_server(&mut self, server: SocketAddr) {
+        self.server = Some(server);
+    }
+
+    /// Address of the client, from the PROXY header if there is one.
+    pub fn client(&self) -> Option<SocketAddr> {
+        self.client
+    }
 
-        let mut handled_te = false;
-        let mut chunked = false;
+    /// Address the client connected to, from the PROXY header if there is one.
+    pub fn server(&self) -> Option<SocketAddr> {
+        self.server
+    }
 
-        let mut keep_alive = KeepAlive::None;
+    pub fn proxy_header(&self) -> Option<&ProxyHeader> {
+        self.proxy_header.as_ref()
+    }
 
-        for header in headers.as_ref() {
-            let name = header.name;
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline.map(|(_, at)| at)
+    }
 
-            let value = if let Ok(v) = std::str::from_utf8(header.value) {
-                v
+    fn start_access_record(&mut self, head: &RequestHead) {
+        if !self.config.access_log {
+            return;
+        }
+        let find = |name: &str| {
+            head.headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name.as_bytes()))
+                .map(|(_, v)| v.clone())
+        };
+        let record = AccessRecord {
+            client: self.client,
+            method: head.method.clone(),
+            path: if head.query_string.is_empty() {
+                head.path.clone()
             } else {
-                return Result::Err(());
-            }
-            .trim();
-
-            if special_headers::CONTENT_LENGTH.eq_ignore_ascii_case(name) {
-                // Content Length Header duplicate.
-                if content_length != 0 || chunked {
-                    test_trace!(
-                        "h11.header.content-length-duplicate",
-                        "Content-Length header duplicate or Transfer-Encoding is already set chunked."
-                    );
-                    return Result::Err(());
-                }
+                format!("{}?{}", head.path, head.query_string)
+            },
+            http_version: head.version.as_str(),
+            status: 0,
+            response_bytes: 0,
+            duration: Duration::ZERO,
+            user_agent: find("user-agent"),
+            referer: find("referer"),
+            time: SystemTime::now(),
+        };
+        self.access = Some((self.clock.now(), record));
+    }
 
-                if let Ok(len) = value.parse::<u64>() {
-                    content_length = len;
-                } else {
-                    test_trace!(
-                        "h11.header.invalid-content-length",
-                        "Invalid Content-Length Header"
-                    );
-                    return Result::Err(());
-                }
-            } else if special_headers::TRANSFER_ENCODING.eq_ignore_ascii_case(name) {
-                if handled_te {
-                    test_trace!(
-                        "h11.header.transfer-encoding-duplicate",
-                        "Tranfer-Encoding header is duplicated."
-                    );
-                    return Result::Err(());
-                } else {
-                    handled_te = true;
-                }
+    fn finish_access_record(&mut self) {
+        if let Some((started, mut record)) = self.access.take() {
+            record.duration = self.clock.now().saturating_duration_since(started);
+            access_log::emit(&record, self.config.access_log_format);
+        }
+    }
 
-                for eachv in value.split(',').map(str::trim) {
-                    if "chunked".eq_ignore_ascii_case(eachv) {
-                        if content_length > 0 {
-                            test_trace!(
-                                "h11.header.content-length-with-chunked",
-                                "Content-Length headerris already set."
-                            );
-                            return Result::Err(());
-                        }
-                        chunked = true;
-                    } else if "identify".eq_ignore_ascii_case(eachv) {
-                        // Pass
-                    } else {
-                        return Result::Err(());
-                    }
-                }
-            } else if special_headers::CONNECTION.eq_ignore_ascii_case(name) {
-                keep_alive = if "keep-alive".eq_ignore_ascii_case(value) {
-                    KeepAlive::KeepAlive
-                } else if "close".eq_ignore_ascii_case(value) {
-                    KeepAlive::Close
-                } else {
-                    KeepAlive::None
-                }
-            }
+    fn close(&mut self) {
+        self.deadline = None;
+        self.state = State::Closed;
+    }
+
+    fn set_deadline(&mut self, timer: Timer, timeout: Duration) {
+        self.deadline = Some((timer, self.clock.now() + timeout));
+    }
+
+    fn check_deadline(&mut self) -> Outbound {
+        let (timer, at) = match self.deadline {
+            Some(deadline) => deadline,
+            None => return Outbound::Pending,
+        };
+        if self.clock.now() < at {
+            return Outbound::Pending;
         }
 
-        if content_length > 0 {
-            return Ok((
-                PayloadType::new_lengthed(content_length as usize),
-                keep_alive,
-            ));
-        } else if chunked {
-            return Ok((PayloadType::new_chunked(), keep_alive));
-        } else {
-            return Ok((PayloadType::new_none(), keep_alive));
+        self.close();
+        match timer {
+            Timer::KeepAlive => Outbound::KeepAliveTimeout,
+            Timer::RequestHead | Timer::RequestBody => Outbound::RequestTimeout(ResponseData {
+                data: error_response(StatusCode::REQUEST_TIMEOUT),
+            }),
         }
     }
 
-    fn parse_request_head(&mut self) -> Output {
-        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
-        let mut req = httparse::Request::new(&mut headers);
-
-        match req.parse(self.req_buffer.as_ref()) {
-            Ok(status) => match status {
-                httparse::Status::Complete(offset) => {
-                    self.offset = offset;
-                    self.state = State::RequestHeadFinished;
-                    if let Ok((payload, keep_alive)) = self._iterate_headers(&req.headers) {
-                        self.payload = payload;
-                        self.keep_alive = keep_alive;
-
-                        Output::RequestHead {
-                            method: req.method.unwrap().to_owned(),
-                            path: req.path.unwrap().to_owned(),
-                            headers: cast_headers_to_rs_headers(&self.req_buffer, &headers),
-                        }
-                    } else {
-                        self.state = State::Closed;
-                        Output::RequestErr
-                    }
+    // PROXY header in front of the first request. `None` once it is read and
+    // the request head can be parsed.
+    fn read_proxy_header(&mut self, data: Bytes) -> Option<Outbound> {
+        self.req_buffer.extend_from_slice(&data);
+        // Any client could claim another address. Only the socket peer is
+        // checked, the header is read before anything else.
+        let mode = if self.proxies.contains_peer(self.client.map(|c| c.ip())) {
+            self.config.proxy_protocol
+        } else if self.config.proxy_protocol == ProxyProtocolMode::Required {
+            log::debug!(
+                target: target::PROTOCOL,
+                code = "proxy.untrusted";
+                "PROXY protocol header from untrusted peer {:?}", self.client
+            );
+            self.close();
+            return Some(Outbound::Closed);
+        } else {
+            // A PROXY header is then parsed as a request, and rejected.
+            ProxyProtocolMode::Off
+        };
+        match proxy_protocol::decode(&mut self.req_buffer, mode) {
+            Ok(Decoded::Partial) => return Some(Outbound::PartialRequest),
+            Ok(Decoded::NoHeader) => {}
+            Ok(Decoded::Header(header)) => {
+                if let Some(source) = header.source {
+                    self.client = Some(source);
                 }
-                httparse::Status::Partial => Output::PartialRequest,
-            },
+                if let Some(destination) = header.destination {
+                    self.server = Some(destination);
+                }
+                self.proxy_header = Some(header);
+            }
             Err(e) => {
-                println!("Parsing failed with \"{}\"", e);
-                self.state = State::Closed;
-                Output::RequestErr
+                log::debug!(
+                    target: target::PROTOCOL,
+                    code = "proxy.invalid";
+                    "Invalid PROXY protocol header: {:?}", e
+                );
+                self.close();
+                return Some(Outbound::Closed);
             }
         }
+        self.proxy_pending = false;
+        None
     }
 
-    fn parse_body(&mut self) -> Output {
-        match self.payload.step(&mut self.req_buffer, self.offset) {
-            PayloadStepResult::Partial(body, offset) => {
-                self.offset = offset;
-                Output::RequestBody(body, false)
+    fn read_head(&mut self, mut data: Bytes) -> Outbound {
+        if self.proxy_pending {
+            if let Some(outbound) = self.read_proxy_header(std::mem::take(&mut data)) {
+                return outbound;
+            }
+        }
+        match self
+            .request
+            .step(&mut self.req_buffer, self.state, RequestData { data })
+        {
+            Ok((head, state, info)) => {
+                self.state = state;
+                self.keep_alive = info.keep_alive;
+                self.deadline = match &info.payload_type {
+                    PayloadType::Lenghthed(payload) if payload.remaining == 0 => None,
+                    _ => Some((
+                        Timer::RequestBody,
+                        self.clock.now() + self.config.timeout_request_body,
+                    )),
+                };
+                self.payload = info.payload_type;
+                self.response_head = ResponseHead::new(
+                    head.version,
+                    info.keep_alive == KeepAlive::KeepAlive,
+                    head.method == "HEAD",
+                )
+                .with_default_headers(self.config.server_header, self.config.date_header);
+                self.start_access_record(&head);
+                Outbound::RequestHead(head)
+            }
+            Err((RequestError::PartialRequest, state)) => {
+                self.state = state;
+                Outbound::PartialRequest
             }
-            PayloadStepResult::Finished(body, offset) => {
-                self.offset = offset;
-                self.state = State::RequestBodyFinished;
-                Output::RequestBody(body, true)
+            Err((RequestError::InvalidState(e), _)) => Outbound::InvalidState(e),
+            Err((e, _)) => {
+                self.close();
+                Outbound::RequestErr(ResponseData {
+                    data: error_response(e.status()),
+                })
             }
-            PayloadStepResult::Err => Output::RequestErr,
         }
     }
 
-    fn _feed(&mut self, data: &[u8]) -> Output {
-        self.req_buffer.extend(data);
+    fn read_body(&mut self, data: Bytes) -> Outbound {
+        let received = !data.is_empty() || !self.req_buffer.is_empty();
+        match self
+            .payload
+            .step(&mut self.req_buffer, self.state, RequestData { data })
+        {
+            Ok((body, state, ())) => {
+                self.state = state;
+                if !body.more_body {
+                    self.deadline = None;
+                } else if received {
+                    self.set_deadline(Timer::RequestBody, self.config.timeout_request_body);
+                }
+                Outbound::RequestBody(body)
+            }
+            Err((PayloadError::InvalidState(e), _)) => Outbound::InvalidState(e),
+            Err((e, _)) => {
+                log::debug!(
+                    target: target::PROTOCOL,
+                    code = "h11.body.invalid";
+                    "Invalid HTTP request body: {:?}", e
+                );
+                self.close();
+                Outbound::RequestErr(ResponseData {
+                    data: error_response(StatusCode::BAD_REQUEST),
+                })
+            }
+        }
+    }
+
+    fn feed(&mut self, data: Bytes) -> Outbound {
+        // Head timer starts with the first byte of the request, not each byte,
+        // so a client can't keep the connection by sending headers slowly.
+        if !data.is_empty() && matches!(self.deadline, Some((Timer::KeepAlive, _))) {
+            self.set_deadline(Timer::RequestHead, self.config.timeout_request_head);
+        }
         match self.state {
-            State::Idle => self.parse_request_head(),
-            State::RequestHeadFinished => self.parse_body(),
-            _ => todo!(),
+            State::Idle => self.read_head(data),
+            State::RequestHeadFinished => self.read_body(data),
+            State::RequestBodyFinished | State::ResponseHeadFinished => {
+                // Pipelined request, parsed after the response is finished.
+                // Bounded like a request head, so a client can't grow the
+                // buffer while the app runs.
+                if self.req_buffer.len() + data.len() > self.config.max_head_size {
+                    log::debug!(
+                        target: target::PROTOCOL,
+                        code = "h11.pipeline_too_large";
+                        "Pipelined data is bigger than max_head_size"
+                    );
+                    self.access = None;
+                    self.close();
+                    return Outbound::Closed;
+                }
+                self.req_buffer.extend_from_slice(&data);
+                Outbound::PartialRequest
+            }
+            State::Closed => Outbound::InvalidState(InvalidState {
+                state: State::Closed,
+                event: Event::RequestHead,
+            }),
         }
     }
 
-    fn start_response(&mut self, status: usize, headers: Vec<PyHeader>) -> Output {
-        // Write response
-        self.res_buffer.put_slice(b"HTTP/1.1 ");
-        let status_code = status.to_string();
-        self.res_buffer.put_slice(status_code.as_bytes());
-        self.res_buffer.put_slice(b"\r\n");
+    fn start_response(&mut self, start: ResponseStart) -> Outbound {
+        let status = start.status;
+        match self
+            .response_head
+            .step(&mut self.res_buffer, self.state, start)
+        {
+            Ok((data, state, info)) => {
+                self.state = state;
+                self.keep_alive = info.keep_alive;
+                self.response_payload = info.payload;
+                if let Some((_, record)) = &mut self.access {
+                    record.status = status;
+                }
+                Outbound::ResponseStart(data)
+            }
+            Err((ResponseError::InvalidState(e), _)) => Outbound::InvalidState(e),
+            Err((e, _)) => {
+                log::error!(
+                    target: target::ERROR,
+                    code = "h11.response.invalid";
+                    "Invalid HTTP response: {:?}", e
+                );
+                if let Some((_, record)) = &mut self.access {
+                    record.status = StatusCode::INTERNAL_SERVER_ERROR.as_u16();
+                }
+                self.finish_access_record();
+                self.close();
+                Outbound::ReseponseErr(Some(ResponseData {
+                    data: error_response(StatusCode::INTERNAL_SERVER_ERROR),
+                }))
+            }
+        }
+    }
 
-        for (name, value) in headers {
-            self.res_buffer.put_slice(name);
-            self.res_buffer.put_slice(b": ");
-            self.res_buffer.put_slice(value);
-            self.res_buffer.put_slice(b"\r\n");
+    fn finish_cycle(&mut self) {
+        if matches!(self.keep_alive, KeepAlive::KeepAlive) {
+            self.payload = no_payload();
+            self.keep_alive = KeepAlive::None;
+            self.state = State::Idle;
+            self.set_deadline(Timer::KeepAlive, self.config.timeout_keep_alive);
+        } else {
+            self.close();
         }
-        self.res_buffer.put_slice(b"\r\n");
+    }
 
-        let res_bytes = self.res_buffer.clone().freeze();
-        self.state = State::ResponseHeadFinished;
-        self.res_buffer.clear();
-        return Output::ResponseStart(res_bytes);
+    fn send_body(&mut self, body: ResponseBody) -> Outbound {
+        match self
+            .response_payload
+            .step(&mut self.res_buffer, self.state, body)
+        {
+            Ok((data, state, ())) => {
+                self.state = state;
+                if let Some((_, record)) = &mut self.access {
+                    record.response_bytes += data.data.len();
+                }
+                if state == State::Closed {
+                    if !self.response_payload.is_complete() {
+                        log::debug!(
+                            target: target::PROTOCOL,
+                            code = "h11.response.short_body";
+                            "Response body is shorter than Content-Length"
+                        );
+                        self.keep_alive = KeepAlive::Close;
+                    }
+                    // Final body is written, the request is complete.
+                    self.finish_access_record();
+                    self.finish_cycle();
+                }
+                Outbound::ResponseBody(data)
+            }
+            Err((ResponseError::InvalidState(e), _)) => Outbound::InvalidState(e),
+            Err((e, _)) => {
+                log::error!(target: target::ERROR, "Invalid HTTP response body: {:?}", e);
+                self.close();
+                Outbound::ReseponseErr(None)
+            }
+        }
     }
 
-    fn send_body(&mut self, body: &[u8], more_body: bool) -> Output {
-        if !more_body {
-            self.state = State::Closed;
+    fn disconnect(&mut self) -> Outbound {
+        if self.state != State::Closed {
+            log::debug!(
+                target: target::PROTOCOL,
+                code = "h11.disconnect";
+                "Connection lost in {:?}", self.state
+            );
         }
-        let mut bytes = BytesMut::new();
-        bytes.extend(body);
-        return Output::ResponseBody(bytes.freeze());
+        // Response is never finished, so there is nothing to log.
+        self.access = None;
+        self.close();
+        Outbound::Closed
     }
 
-    fn step(&mut self, input: Input) -> Output {
-        match input {
-            Input::RequestData(data) => self._feed(data),
-            Input::Disconnect => todo!(),
-            Input::ResponseStart { status, headers } => self.start_response(status, headers),
-            Input::ResponseBody { body, more_body } => self.send_body(body, more_body),
+    pub fn step(&mut self, inbound: Inbound) -> Outbound {
+        match inbound {
+            Inbound::RequestData(RequestData { data }) => self.feed(data),
+            Inbound::Disconnect => self.disconnect(),
+            Inbound::Tick => self.check_deadline(),
+            Inbound::ResponseStart(start) => self.start_response(start),
+            Inbound::ResponseBody(body) => self.send_body(body),
         }
     }
 }
@@ -349,186 +459,814 @@ impl Http11Connection {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::clock::mock::ManualClock;
 
-    #[test]
-    fn test_partial_request() {
-        let mut conn = Http11Connection::new();
+    fn data(data: &'static [u8]) -> Inbound {
+        Inbound::RequestData(RequestData {
+            data: Bytes::from_static(data),
+        })
+    }
 
-        let output = dbg!(conn.step(Input::RequestData(b"GET /")));
-        assert!(matches!(output, Output::PartialRequest))
+    fn response_start(status: u16, content_length: usize) -> Inbound {
+        Inbound::ResponseStart(ResponseStart {
+            status,
+            headers: vec![(
+                Bytes::from_static(b"Content-Length"),
+                Bytes::from(content_length.to_string()),
+            )],
+        })
     }
 
-    #[test]
-    fn test_get_request() {
-        let mut conn = Http11Connection::new();
+    fn response_body(body: &'static [u8], more_body: bool) -> Inbound {
+        Inbound::ResponseBody(ResponseBody {
+            body: Bytes::from_static(body),
+            more_body,
+        })
+    }
 
-        let output = dbg!(conn.step(Input::RequestData(
-            b"GET /test HTTP/1.1\r\nHost:localhost\r\n\r\n"
-        )));
-        assert!(matches!(output, Output::RequestHead { .. }));
-        assert!(matches!(conn.payload, PayloadType::None(_)));
-        assert!(matches!(conn.state, State::RequestHeadFinished));
+    fn conn_with_clock() -> (Http11Connection, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        let conn = Http11Connection::with_clock(Arc::default(), clock.clone());
+        (conn, clock)
     }
 
     #[test]
-    fn test_chunked() {
-        let mut conn = Http11Connection::new();
+    fn test_partial_request() {
+        let mut conn = Http11Connection::new(Arc::default());
 
-        let output = dbg!(conn.step(Input::RequestData(
-            b"GET /test HTTP/1.1\r\nTransfer-Encoding:chunked\r\nHost:localhost\r\n\r\n"
-        )));
-        assert!(matches!(output, Output::RequestHead { .. }));
-        assert!(matches!(conn.payload, PayloadType::ChunkedPayload(_)));
-        assert!(matches!(conn.state, State::RequestHeadFinished));
+        let output = conn.step(data(b"GET /"));
+
+        assert!(matches!(output, Outbound::PartialRequest));
+        assert_eq!(conn.state(), State::Idle);
     }
 
     #[test]
-    fn test_post_request() {
-        let mut conn = Http11Connection::new();
+    fn test_get_request() {
+        let mut conn = Http11Connection::new(Arc::default());
 
-        let output = dbg!(conn.step(Input::RequestData(
-            b"POST /test HTTP/1.1\r\nContent-Length:1\r\nHost:localhost\r\n\r\na"
-        )));
-        assert!(matches!(output, Output::RequestHead { .. }));
+        let output = conn.step(data(b"GET /test HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+        assert!(matches!(output, Outbound::RequestHead(head) if head.path == "/test"));
+        assert_eq!(conn.state(), State::RequestHeadFinished);
 
-        if let PayloadType::LengthedPayload(p) = conn.payload {
-            assert_eq!(p.to_consume, 1)
-        }
-        assert!(matches!(conn.state, State::RequestHeadFinished));
+        let output = conn.step(data(b""));
+        assert!(
+            matches!(output, Outbound::RequestBody(body) if body.body.is_empty() && !body.more_body)
+        );
+        assert_eq!(conn.state(), State::RequestBodyFinished);
     }
 
     #[test]
-    fn test_content_length_duplicate() {
-        let mut conn = Http11Connection::new();
+    fn test_post_request_with_body_in_head_packet() {
+        let mut conn = Http11Connection::new(Arc::default());
 
-        let output = dbg!(conn.step(Input::RequestData(
-            b"GET /test HTTP/1.1\r\nContent-Length:1\r\nContent-Length:1\r\nHost:localhost\r\n\r\n"
-        )));
-        assert!(matches!(output, Output::RequestErr));
-        assert!(matches!(conn.state, State::Closed));
+        let output = conn.step(data(
+            b"POST /test HTTP/1.1\r\nContent-Length:4\r\nHost:localhost\r\n\r\ndata",
+        ));
+        assert!(matches!(output, Outbound::RequestHead(_)));
+        assert!(matches!(&conn.payload, PayloadType::Lenghthed(p) if p.remaining == 4));
+
+        let output = conn.step(data(b""));
+        assert!(
+            matches!(output, Outbound::RequestBody(body) if body.body == "data" && !body.more_body)
+        );
+        assert_eq!(conn.state(), State::RequestBodyFinished);
+    }
+
+    fn body_events(conn: &mut Http11Connection, parts: [&'static [u8]; 3]) -> Vec<(Bytes, bool)> {
+        let mut events = Vec::new();
+        for part in parts {
+            match conn.step(data(part)) {
+                Outbound::RequestBody(body) => events.push((body.body, body.more_body)),
+                output => panic!("unexpected {:?}", output),
+            }
+        }
+        events
     }
 
     #[test]
-    fn test_content_length_invalid() {
-        let mut conn = Http11Connection::new();
+    fn test_lengthed_body_split_across_three_reads() {
+        let mut conn = Http11Connection::new(Arc::default());
+        conn.step(data(
+            b"POST /test HTTP/1.1\r\nContent-Length:6\r\nHost:localhost\r\n\r\n",
+        ));
+
+        let events = body_events(&mut conn, [b"ab", b"cd", b"ef"]);
+
+        assert_eq!(
+            events,
+            vec![
+                (Bytes::from_static(b"ab"), true),
+                (Bytes::from_static(b"cd"), true),
+                (Bytes::from_static(b"ef"), false),
+            ]
+        );
+        assert_eq!(conn.state(), State::RequestBodyFinished);
+    }
 
-        let output = dbg!(conn.step(Input::RequestData(
-            b"GET /test HTTP/1.1\r\nContent-Length:s\r\nHost:localhost\r\n\r\n"
-        )));
-        assert!(matches!(output, Output::RequestErr));
-        assert!(matches!(conn.state, State::Closed));
+    #[test]
+    fn test_chunked_body_split_across_three_reads() {
+        let mut conn = Http11Connection::new(Arc::default());
+        conn.step(data(
+            b"POST /test HTTP/1.1\r\nTransfer-Encoding: chunked\r\nHost:localhost\r\n\r\n",
+        ));
+
+        let events = body_events(&mut conn, [b"6\r\nab", b"cdef\r\n0", b"\r\n\r\n"]);
+
+        assert_eq!(
+            events,
+            vec![
+                (Bytes::from_static(b"ab"), true),
+                (Bytes::from_static(b"cdef"), true),
+                (Bytes::new(), false),
+            ]
+        );
+        assert_eq!(conn.state(), State::RequestBodyFinished);
     }
 
     #[test]
-    fn test_tranfer_encoding_duplicate() {
-        let mut conn = Http11Connection::new();
+    fn test_chunked_request() {
+        let mut conn = Http11Connection::new(Arc::default());
+
+        conn.step(data(
+            b"POST /test HTTP/1.1\r\nTransfer-Encoding: chunked\r\nHost:localhost\r\n\r\n",
+        ));
+        assert!(matches!(conn.payload, PayloadType::Chunked(_)));
 
-        let output = dbg!(conn.step(Input::RequestData(
-            b"GET /test HTTP/1.1\r\nTransfer-Encoding:chunked\r\nTransfer-Encoding:dup\r\nHost:localhost\r\n\r\n",
-        )));
-        assert!(matches!(output, Output::RequestErr));
-        assert!(matches!(conn.state, State::Closed));
+        let output = conn.step(data(b"4\r\ndata\r\n"));
+        assert!(
+            matches!(output, Outbound::RequestBody(body) if body.body == "data" && body.more_body)
+        );
+        let output = conn.step(data(b"0\r\n\r\n"));
+        assert!(matches!(output, Outbound::RequestBody(body) if !body.more_body));
+        assert_eq!(conn.state(), State::RequestBodyFinished);
     }
 
     #[test]
-    fn test_content_length_with_chunked() {
-        let mut conn = Http11Connection::new();
+    fn test_invalid_request() {
+        let mut conn = Http11Connection::new(Arc::default());
 
-        let output = dbg!(conn.step(Input::RequestData(
-            b"GET /test HTTP/1.1\r\nContent-Length:1\r\nTransfer-Encoding::chunked\r\nHost:localhost\r\n\r\n"
-        )));
-        assert!(matches!(output, Output::RequestErr));
-        assert!(matches!(conn.state, State::Closed));
+        let output = conn.step(data(
+            b"GET /test HTTP/1.1\r\nContent-Length:s\r\nHost:localhost\r\n\r\n",
+        ));
+
+        assert!(matches!(output, Outbound::RequestErr(_)));
+        assert_eq!(conn.state(), State::Closed);
+        assert_eq!(conn.deadline(), None);
     }
 
     #[test]
-    fn test_keep_alive() {
-        let mut conn = Http11Connection::new();
+    fn test_unsupported_transfer_coding() {
+        let mut conn = Http11Connection::new(Arc::default());
+
+        let output = conn.step(data(
+            b"POST /test HTTP/1.1\r\nTransfer-Encoding: gzip\r\nHost:localhost\r\n\r\n",
+        ));
 
-        let output = dbg!(conn.step(Input::RequestData(
-            b"GET /test HTTP/1.1\r\nConnection: keep-alive\r\nHost:localhost\r\n\r\n"
-        )));
-        assert!(matches!(output, Output::RequestHead { .. }));
-        assert!(matches!(conn.keep_alive, KeepAlive::KeepAlive));
-        assert!(matches!(conn.state, State::RequestHeadFinished));
+        assert!(
+            matches!(output, Outbound::RequestErr(res) if res.data.starts_with(b"HTTP/1.1 501 "))
+        );
+        assert_eq!(conn.state(), State::Closed);
     }
 
     #[test]
-    fn test_close_connection() {
-        let mut conn = Http11Connection::new();
+    fn test_invalid_body() {
+        let mut conn = Http11Connection::new(Arc::default());
+
+        conn.step(data(
+            b"POST /test HTTP/1.1\r\nTransfer-Encoding: chunked\r\nHost:localhost\r\n\r\n",
+        ));
+        let output = conn.step(data(b"zz\r\n"));
 
-        let output = dbg!(conn.step(Input::RequestData(
-            b"GET /test HTTP/1.1\r\nConnection: close\r\nHost:localhost\r\n\r\n"
-        )));
-        assert!(matches!(output, Output::RequestHead { .. }));
-        assert!(matches!(conn.keep_alive, KeepAlive::Close));
-        assert!(matches!(conn.state, State::RequestHeadFinished));
+        assert!(matches!(output, Outbound::RequestErr(_)));
+        assert_eq!(conn.state(), State::Closed);
     }
 
     #[test]
     fn test_request_with_too_many_headers() {
-        let mut conn = Http11Connection::new();
+        let mut conn = Http11Connection::new(Arc::default());
         let data = [
             Vec::from(b"GET /test HTTP/1.1\r\n"),
-            Vec::from(b"X:X\r\n".repeat(MAX_HEADERS + 1)),
+            b"X:X\r\n".repeat(ServerConfig::default().max_headers + 1),
             Vec::from(b"\r\n".to_owned()),
         ]
         .concat();
 
-        let output = dbg!(conn.step(Input::RequestData(data.as_ref())));
-        assert!(matches!(output, Output::RequestErr));
-        assert!(matches!(conn.state, State::Closed));
+        let output = conn.step(Inbound::RequestData(RequestData {
+            data: Bytes::from(data),
+        }));
+        assert!(matches!(output, Outbound::RequestErr(_)));
+        assert_eq!(conn.state(), State::Closed);
     }
 
     #[test]
     fn test_response_head() {
-        let mut conn = Http11Connection::new();
+        let mut conn = Http11Connection::new(Arc::default());
         conn.state = State::RequestBodyFinished;
 
-        let output = conn.step(Input::ResponseStart {
+        let output = conn.step(Inbound::ResponseStart(ResponseStart {
             status: 200,
-            headers: vec![(b"Name", b"Value"), (b"Name2", b"Value2")],
-        });
-        assert!(matches!(conn.state, State::ResponseHeadFinished));
+            headers: vec![(Bytes::from_static(b"Name"), Bytes::from_static(b"Value"))],
+        }));
+
+        assert_eq!(conn.state(), State::ResponseHeadFinished);
         assert_eq!(conn.res_buffer.len(), 0);
+        assert!(
+            matches!(output, Outbound::ResponseStart(res) if res.data == "HTTP/1.1 200 OK\r\nName: Value\r\nConnection: close\r\n\r\n")
+        );
+    }
+
+    #[test]
+    fn test_default_headers_from_config() {
+        let config = ServerConfig {
+            date_header: false,
+            ..Default::default()
+        };
+        let mut conn = Http11Connection::new(Arc::new(config));
+        conn.step(data(b"GET /test HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+        conn.step(data(b""));
+
+        let output = conn.step(response_start(200, 0));
+
+        assert!(
+            matches!(output, Outbound::ResponseStart(res) if res.data == "HTTP/1.1 200 OK\r\nServer: ruvicorn\r\nContent-Length: 0\r\n\r\n")
+        );
+    }
+
+    #[test]
+    fn test_response_invalid_status() {
+        let mut conn = Http11Connection::new(Arc::default());
+        conn.state = State::RequestBodyFinished;
+
+        let output = conn.step(response_start(1001, 0));
+
+        assert!(
+            matches!(output, Outbound::ReseponseErr(Some(res)) if res.data.starts_with(b"HTTP/1.1 500 "))
+        );
+        assert_eq!(conn.state(), State::Closed);
+    }
+
+    #[test]
+    fn test_response_header_injection() {
+        let mut conn = Http11Connection::new(Arc::default());
+        conn.step(data(b"GET /test HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+        conn.step(data(b""));
+
+        let output = conn.step(Inbound::ResponseStart(ResponseStart {
+            status: 200,
+            headers: vec![(
+                Bytes::from_static(b"Location"),
+                Bytes::from_static(b"/\r\nSet-Cookie: session=stolen"),
+            )],
+        }));
+
         match output {
-            Output::ResponseStart(data) => {
-                assert_eq!(
-                    data.clone(),
-                    Bytes::from_static(b"HTTP/1.1 200\r\nName: Value\r\nName2: Value2\r\n\r\n")
-                );
+            Outbound::ReseponseErr(Some(res)) => {
+                assert!(res.data.starts_with(b"HTTP/1.1 500 "));
+                assert!(!res.data.windows(10).any(|w| w == b"Set-Cookie"));
             }
-            _ => assert_eq!(1, 0),
+            output => panic!("unexpected {:?}", output),
         }
+        assert_eq!(conn.state(), State::Closed);
+        assert!(conn.access.is_none());
     }
 
     #[test]
     fn test_response_body_without_extra_data() {
-        let mut conn = Http11Connection::new();
+        let mut conn = Http11Connection::new(Arc::default());
         conn.state = State::ResponseHeadFinished;
 
-        let output = conn.step(Input::ResponseBody {
-            body: b"data",
-            more_body: false,
-        });
-        assert!(matches!(conn.state, State::Closed));
-        assert!(
-            matches!(output, Output::ResponseBody(data) if (data == Bytes::from_static(b"data")))
-        );
+        let output = conn.step(response_body(b"data", false));
+
+        assert_eq!(conn.state(), State::Closed);
+        assert!(matches!(output, Outbound::ResponseBody(res) if res.data == "data"));
     }
 
     #[test]
     fn test_response_body_with_extra_data() {
-        let mut conn = Http11Connection::new();
+        let mut conn = Http11Connection::new(Arc::default());
         conn.state = State::ResponseHeadFinished;
 
-        let output = conn.step(Input::ResponseBody {
-            body: b"data",
-            more_body: true,
-        });
-        assert!(matches!(conn.state, State::ResponseHeadFinished));
+        let output = conn.step(response_body(b"data", true));
+
+        assert_eq!(conn.state(), State::ResponseHeadFinished);
+        assert!(matches!(output, Outbound::ResponseBody(res) if res.data == "data"));
+    }
+
+    #[test]
+    fn test_response_body_in_idle() {
+        let mut conn = Http11Connection::new(Arc::default());
+
+        let output = conn.step(response_body(b"data", false));
+
+        assert!(matches!(
+            output,
+            Outbound::InvalidState(InvalidState {
+                state: State::Idle,
+                event: Event::ResponseBody
+            })
+        ));
+        assert_eq!(conn.state(), State::Idle);
+    }
+
+    #[test]
+    fn test_response_start_twice() {
+        let mut conn = Http11Connection::new(Arc::default());
+        conn.state = State::RequestBodyFinished;
+
+        conn.step(response_start(200, 0));
+        let output = conn.step(response_start(200, 0));
+
+        assert!(matches!(output, Outbound::InvalidState(_)));
+        assert_eq!(conn.state(), State::ResponseHeadFinished);
+    }
+
+    #[test]
+    fn test_request_data_after_close() {
+        let mut conn = Http11Connection::new(Arc::default());
+        conn.step(data(b"INVALID\r\n\r\n"));
+
+        let output = conn.step(data(b"GET / HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+
+        assert!(matches!(output, Outbound::InvalidState(e) if e.state == State::Closed));
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let mut conn = Http11Connection::new(Arc::default());
+        conn.step(data(b"GET /test HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+
+        assert!(matches!(conn.step(Inbound::Disconnect), Outbound::Closed));
+        assert_eq!(conn.state(), State::Closed);
+        assert!(conn.access.is_none());
+        assert!(matches!(conn.step(Inbound::Disconnect), Outbound::Closed));
+        assert!(matches!(conn.step(Inbound::Tick), Outbound::Pending));
+    }
+
+    #[test]
+    fn test_pipelined_request() {
+        let mut conn = Http11Connection::new(Arc::default());
+
+        conn.step(data(
+            b"GET /first HTTP/1.1\r\nConnection: keep-alive\r\nHost:localhost\r\n\r\n",
+        ));
+        conn.step(data(b""));
+        let output = conn.step(data(b"GET /second HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+        assert!(matches!(output, Outbound::PartialRequest));
+
+        conn.step(response_start(200, 0));
+        conn.step(response_body(b"", false));
+        assert_eq!(conn.state(), State::Idle);
+
+        let output = conn.step(data(b""));
+        assert!(matches!(output, Outbound::RequestHead(head) if head.path == "/second"));
+    }
+
+    #[test]
+    fn test_pipelined_data_limit() {
+        let config = ServerConfig {
+            max_head_size: 64,
+            ..Default::default()
+        };
+        let mut conn = Http11Connection::new(Arc::new(config));
+
+        conn.step(data(b"GET /first HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+        conn.step(data(b""));
+        let output = conn.step(data(b"GET /second HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+        assert!(matches!(output, Outbound::PartialRequest));
+
+        let output = conn.step(data(b"GET /third HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+        assert!(matches!(output, Outbound::Closed));
+        assert_eq!(conn.state(), State::Closed);
+        assert!(conn.access.is_none());
+    }
+
+    #[test]
+    fn test_pipelined_requests_in_one_read() {
+        let mut conn = Http11Connection::new(Arc::default());
+
+        conn.step(data(
+            concat!(
+                "POST /first HTTP/1.1\r\nHost:localhost\r\nContent-Length: 2\r\n\r\nab",
+                "GET /second HTTP/1.1\r\nHost:localhost\r\n\r\n"
+            )
+            .as_bytes(),
+        ));
+        let output = conn.step(data(b""));
+        assert!(matches!(output, Outbound::RequestBody(body) if body.body == "ab"));
+
+        conn.step(response_start(200, 0));
+        conn.step(response_body(b"", false));
+        let output = conn.step(data(b""));
+        assert!(matches!(output, Outbound::RequestHead(head) if head.path == "/second"));
+    }
+
+    #[test]
+    fn test_keep_alive_cycle() {
+        let mut conn = Http11Connection::new(Arc::default());
+
+        conn.step(data(
+            b"GET /first HTTP/1.1\r\nConnection: keep-alive\r\nHost:localhost\r\n\r\n",
+        ));
+        conn.step(data(b""));
+        conn.step(response_start(200, 4));
+        conn.step(response_body(b"data", false));
+        assert_eq!(conn.state(), State::Idle);
+
+        let output = conn.step(data(b"GET /second HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+        assert!(matches!(output, Outbound::RequestHead(head) if head.path == "/second"));
+    }
+
+    #[test]
+    fn test_keep_alive_disabled() {
+        let config = ServerConfig {
+            keep_alive: false,
+            ..Default::default()
+        };
+        let mut conn = Http11Connection::new(Arc::new(config));
+
+        conn.step(data(
+            b"GET /test HTTP/1.1\r\nConnection: keep-alive\r\nHost:localhost\r\n\r\n",
+        ));
+        conn.step(data(b""));
+        conn.step(response_start(200, 0));
+        conn.step(response_body(b"", false));
+
+        assert_eq!(conn.state(), State::Closed);
+    }
+
+    #[test]
+    fn test_http10_request() {
+        let mut conn = Http11Connection::new(Arc::default());
+
+        conn.step(data(b"GET /test HTTP/1.0\r\n\r\n"));
+        conn.step(data(b""));
+        let output = conn.step(response_start(200, 0));
+        assert!(
+            matches!(output, Outbound::ResponseStart(ref head) if head.data.starts_with(b"HTTP/1.0 200 OK\r\n")),
+            "{:?}",
+            output
+        );
+        conn.step(response_body(b"", false));
+
+        assert_eq!(conn.state(), State::Closed);
+    }
+
+    #[test]
+    fn test_http10_keep_alive() {
+        let mut conn = Http11Connection::new(Arc::default());
+
+        conn.step(data(
+            b"GET /first HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+        ));
+        conn.step(data(b""));
+        conn.step(response_start(200, 0));
+        conn.step(response_body(b"", false));
+        assert_eq!(conn.state(), State::Idle);
+
+        let output = conn.step(data(b"GET /second HTTP/1.0\r\n\r\n"));
+        assert!(matches!(output, Outbound::RequestHead(head) if head.path == "/second"));
+    }
+
+    #[test]
+    fn test_unframed_response_closes() {
+        let mut conn = Http11Connection::new(Arc::default());
+
+        conn.step(data(b"GET /test HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+        conn.step(data(b""));
+        conn.step(Inbound::ResponseStart(ResponseStart {
+            status: 200,
+            headers: vec![],
+        }));
+        conn.step(response_body(b"data", false));
+
+        assert_eq!(conn.state(), State::Closed);
+    }
+
+    #[test]
+    fn test_bodyless_responses_drop_body() {
+        let mut conn = Http11Connection::new(Arc::default());
+
+        conn.step(data(b"HEAD /test HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+        conn.step(data(b""));
+        conn.step(response_start(200, 4));
+        let output = conn.step(response_body(b"data", false));
+        assert!(matches!(output, Outbound::ResponseBody(res) if res.data.is_empty()));
+        assert_eq!(conn.state(), State::Idle);
+
+        conn.step(data(b"GET /test HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+        conn.step(data(b""));
+        conn.step(Inbound::ResponseStart(ResponseStart {
+            status: 304,
+            headers: vec![],
+        }));
+        let output = conn.step(response_body(b"da", true));
+        assert!(matches!(output, Outbound::ResponseBody(res) if res.data.is_empty()));
+        let output = conn.step(response_body(b"ta", false));
+        assert!(matches!(output, Outbound::ResponseBody(res) if res.data.is_empty()));
+        assert_eq!(conn.state(), State::Idle);
+    }
+
+    #[test]
+    fn test_chunked_response_keeps_alive() {
+        let mut conn = Http11Connection::new(Arc::default());
+
+        conn.step(data(b"GET /test HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+        conn.step(data(b""));
+        conn.step(Inbound::ResponseStart(ResponseStart {
+            status: 200,
+            headers: vec![(
+                Bytes::from_static(b"Transfer-Encoding"),
+                Bytes::from_static(b"chunked"),
+            )],
+        }));
+        let output = conn.step(response_body(b"data", false));
+
+        assert!(
+            matches!(output, Outbound::ResponseBody(res) if res.data == b"4\r\ndata\r\n0\r\n\r\n"[..])
+        );
+        assert_eq!(conn.state(), State::Idle);
+    }
+
+    #[test]
+    fn test_response_body_longer_than_content_length() {
+        let mut conn = Http11Connection::new(Arc::default());
+
+        conn.step(data(b"GET /test HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+        conn.step(data(b""));
+        conn.step(response_start(200, 4));
+        let output = conn.step(response_body(b"data\r\n\r\nHTTP/1.1 200 OK", false));
+
+        assert!(matches!(output, Outbound::ReseponseErr(None)));
+        assert_eq!(conn.state(), State::Closed);
+    }
+
+    #[test]
+    fn test_response_body_shorter_than_content_length() {
+        let mut conn = Http11Connection::new(Arc::default());
+
+        conn.step(data(b"GET /test HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+        conn.step(data(b""));
+        conn.step(response_start(200, 4));
+        let output = conn.step(response_body(b"dat", false));
+
+        assert!(matches!(output, Outbound::ResponseBody(res) if res.data == b"dat"[..]));
+        // The client still waits for a byte, so the connection can't be reused.
+        assert_eq!(conn.state(), State::Closed);
+    }
+
+    fn proxy_conn(mode: ProxyProtocolMode) -> Http11Connection {
+        let config = ServerConfig {
+            proxy_protocol: mode,
+            forwarded_allow_ips: vec!["10.0.0.0/8".to_owned()],
+            ..Default::default()
+        };
+        let mut conn = Http11Connection::new(Arc::new(config));
+        conn.set_client("10.0.0.1:40000".parse().unwrap());
+        conn.set_server("10.0.0.2:8000".parse().unwrap());
+        conn
+    }
+
+    #[test]
+    fn test_proxy_protocol_header() {
+        let mut conn = proxy_conn(ProxyProtocolMode::Required);
+
+        let output = conn.step(data(b"PROXY TCP4 192.0.2.1 192.0.2.2 5"));
+        assert!(matches!(output, Outbound::PartialRequest));
+        let output = conn.step(data(
+            b"6324 443\r\nGET /test HTTP/1.1\r\nHost:localhost\r\n\r\n",
+        ));
+
+        assert!(matches!(output, Outbound::RequestHead(head) if head.path == "/test"));
+        assert_eq!(conn.client(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(conn.server(), Some("192.0.2.2:443".parse().unwrap()));
+        assert!(conn.proxy_header().is_some());
+    }
+
+    #[test]
+    fn test_proxy_protocol_read_once() {
+        let mut conn = proxy_conn(ProxyProtocolMode::Optional);
+
+        conn.step(data(b"GET /first HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+        conn.step(data(b""));
+        conn.step(response_start(200, 0));
+        conn.step(response_body(b"", false));
+        // Only the first bytes of the connection can be a PROXY header.
+        let output = conn.step(data(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n"));
+
+        assert!(matches!(output, Outbound::RequestErr(_)));
+        assert_eq!(conn.client(), Some("10.0.0.1:40000".parse().unwrap()));
+        assert!(conn.proxy_header().is_none());
+    }
+
+    #[test]
+    fn test_proxy_protocol_required() {
+        let mut conn = proxy_conn(ProxyProtocolMode::Required);
+
+        let output = conn.step(data(b"GET /test HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+
+        assert!(matches!(output, Outbound::Closed));
+        assert_eq!(conn.state(), State::Closed);
+    }
+
+    #[test]
+    fn test_proxy_protocol_untrusted_peer() {
+        let mut conn = proxy_conn(ProxyProtocolMode::Optional);
+        conn.set_client("192.0.2.9:40000".parse().unwrap());
+
+        let output = conn.step(data(
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET /test HTTP/1.1\r\nHost:localhost\r\n\r\n",
+        ));
+
+        assert!(matches!(output, Outbound::RequestErr(_)));
+        assert_eq!(conn.client(), Some("192.0.2.9:40000".parse().unwrap()));
+        assert!(conn.proxy_header().is_none());
+
+        let mut conn = proxy_conn(ProxyProtocolMode::Optional);
+        conn.set_client("192.0.2.9:40000".parse().unwrap());
+        let output = conn.step(data(b"GET /test HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+        assert!(matches!(output, Outbound::RequestHead(_)));
+
+        let mut conn = proxy_conn(ProxyProtocolMode::Required);
+        conn.set_client("192.0.2.9:40000".parse().unwrap());
+        let output = conn.step(data(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n"));
+        assert!(matches!(output, Outbound::Closed));
+        assert_eq!(conn.client(), Some("192.0.2.9:40000".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_proxy_protocol_local_keeps_addresses() {
+        let mut conn = proxy_conn(ProxyProtocolMode::Required);
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00".to_vec();
+        header.extend_from_slice(b"GET /health HTTP/1.1\r\nHost:localhost\r\n\r\n");
+
+        let output = conn.step(Inbound::RequestData(RequestData {
+            data: Bytes::from(header),
+        }));
+
+        assert!(matches!(output, Outbound::RequestHead(head) if head.path == "/health"));
+        assert_eq!(conn.client(), Some("10.0.0.1:40000".parse().unwrap()));
+        assert_eq!(conn.server(), Some("10.0.0.2:8000".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_deadline_not_passed() {
+        let (mut conn, clock) = conn_with_clock();
+        let config = ServerConfig::default();
+
+        conn.step(data(b"GET /"));
+        clock.advance(config.timeout_request_head - Duration::from_millis(1));
+
+        assert!(matches!(conn.step(Inbound::Tick), Outbound::Pending));
+        assert_eq!(conn.state(), State::Idle);
+    }
+
+    #[test]
+    fn test_request_head_timer_starts_on_first_byte() {
+        let (mut conn, clock) = conn_with_clock();
+        let config = ServerConfig::default();
+        assert!(matches!(conn.deadline, Some((Timer::KeepAlive, _))));
+
+        clock.advance(config.timeout_keep_alive - Duration::from_millis(1));
+        conn.step(data(b"GET /"));
+        clock.advance(config.timeout_request_head - Duration::from_millis(1));
+        assert!(matches!(conn.step(Inbound::Tick), Outbound::Pending));
+
+        clock.advance(Duration::from_millis(1));
+        assert!(matches!(
+            conn.step(Inbound::Tick),
+            Outbound::RequestTimeout(_)
+        ));
+
+        // A new connection that sends nothing is closed without a response.
+        let (mut conn, clock) = conn_with_clock();
+        clock.advance(config.timeout_keep_alive);
+        assert!(matches!(
+            conn.step(Inbound::Tick),
+            Outbound::KeepAliveTimeout
+        ));
+    }
+
+    #[test]
+    fn test_request_head_timeout() {
+        let (mut conn, clock) = conn_with_clock();
+        let config = ServerConfig::default();
+
+        conn.step(data(b"GET /"));
+        clock.advance(config.timeout_request_head);
+
+        let output = conn.step(Inbound::Tick);
         assert!(
-            matches!(output, Output::ResponseBody(data) if (data == Bytes::from_static(b"data")))
+            matches!(output, Outbound::RequestTimeout(res) if res.data.starts_with(b"HTTP/1.1 408 "))
         );
+        assert_eq!(conn.state(), State::Closed);
+        assert_eq!(conn.deadline(), None);
+    }
+
+    #[test]
+    fn test_request_head_timeout_is_not_reset_by_data() {
+        let (mut conn, clock) = conn_with_clock();
+        let half = ServerConfig::default().timeout_request_head / 2;
+
+        conn.step(data(b"GET /"));
+        clock.advance(half);
+        conn.step(data(b"test HTTP/1.1\r\nHost"));
+        clock.advance(half);
+
+        assert!(matches!(
+            conn.step(Inbound::Tick),
+            Outbound::RequestTimeout(_)
+        ));
+    }
+
+    #[test]
+    fn test_request_body_timeout_per_chunk() {
+        let (mut conn, clock) = conn_with_clock();
+        let timeout = ServerConfig::default().timeout_request_body;
+        let almost = timeout - Duration::from_millis(1);
+
+        conn.step(data(
+            b"POST /test HTTP/1.1\r\nContent-Length:8\r\nHost:localhost\r\n\r\n",
+        ));
+        clock.advance(almost);
+        conn.step(data(b"da"));
+        clock.advance(almost);
+        assert!(matches!(conn.step(Inbound::Tick), Outbound::Pending));
+
+        clock.advance(Duration::from_millis(1));
+        assert!(matches!(
+            conn.step(Inbound::Tick),
+            Outbound::RequestTimeout(_)
+        ));
+        assert_eq!(conn.state(), State::Closed);
+    }
+
+    #[test]
+    fn test_keep_alive_timeout() {
+        let (mut conn, clock) = conn_with_clock();
+
+        conn.step(data(
+            b"GET /test HTTP/1.1\r\nConnection: keep-alive\r\nHost:localhost\r\n\r\n",
+        ));
+        assert_eq!(conn.deadline(), None);
+        conn.step(data(b""));
+        conn.step(response_start(200, 4));
+        conn.step(response_body(b"data", false));
+        assert_eq!(conn.state(), State::Idle);
+        assert!(matches!(conn.deadline, Some((Timer::KeepAlive, _))));
+
+        clock.advance(ServerConfig::default().timeout_keep_alive);
+
+        assert!(matches!(
+            conn.step(Inbound::Tick),
+            Outbound::KeepAliveTimeout
+        ));
+        assert_eq!(conn.state(), State::Closed);
+    }
+
+    #[test]
+    fn test_keep_alive_timer_switches_to_request_head() {
+        let (mut conn, _) = conn_with_clock();
+        conn.deadline = Some((Timer::KeepAlive, conn.clock.now()));
+
+        conn.step(data(b"GET /"));
+
+        assert!(matches!(conn.deadline, Some((Timer::RequestHead, _))));
+    }
+
+    #[test]
+    fn test_access_record() {
+        let mut conn = Http11Connection::new(Arc::default());
+        conn.set_client("127.0.0.1:50000".parse().unwrap());
+
+        conn.step(data(
+            b"GET /test HTTP/1.1\r\nHost:localhost\r\nUser-Agent: curl/8.0\r\n\r\n",
+        ));
+        conn.step(data(b""));
+        conn.step(response_start(404, 4));
+        conn.step(response_body(b"data", true));
+
+        let (_, record) = conn.access.as_ref().unwrap();
+        assert_eq!(record.client, conn.client);
+        assert_eq!(record.method, "GET");
+        assert_eq!(record.path, "/test");
+        assert_eq!(record.status, 404);
+        assert_eq!(record.response_bytes, 4);
+        assert_eq!(record.user_agent, Some(Bytes::from_static(b"curl/8.0")));
+
+        conn.step(response_body(b"", false));
+        assert!(conn.access.is_none());
+    }
+
+    #[test]
+    fn test_access_log_disabled() {
+        let config = ServerConfig {
+            access_log: false,
+            ..Default::default()
+        };
+        let mut conn = Http11Connection::new(Arc::new(config));
+
+        conn.step(data(b"GET /test HTTP/1.1\r\nHost:localhost\r\n\r\n"));
+
+        assert!(conn.access.is_none());
     }
 }
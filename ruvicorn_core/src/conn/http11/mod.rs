@@ -1,7 +1,6 @@
-mod bound;
-mod conn;
-mod payload;
-mod payload_handle;
-mod request;
-mod response;
-mod state;
+pub mod bound;
+pub mod conn;
+pub mod payload_handle;
+pub mod request;
+pub mod response;
+pub mod state;
@@ -1,22 +1,42 @@
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 
+use crate::config::ServerConfig;
 use crate::handle::Handle;
 
 use super::{
     bound::{RequestBody, RequestData},
-    state::State,
+    state::{Event, InvalidState, State},
 };
 
+// Longest chunk size line (size and extensions) or trailer line accepted.
+const MAX_CHUNK_LINE: usize = 4096;
+
+#[derive(Debug)]
+pub enum PayloadError {
+    // Chunk size line, chunk data terminator or trailer is malformed.
+    InvalidChunk(&'static str),
+    InvalidState(InvalidState),
+}
+
+impl From<InvalidState> for PayloadError {
+    fn from(error: InvalidState) -> Self {
+        Self::InvalidState(error)
+    }
+}
+
 #[derive(Debug)]
 pub struct EmptyPayload {}
 
-impl Handle<RequestData, RequestBody, State, (), ()> for EmptyPayload {
+impl Handle<RequestData, RequestBody, State, (), PayloadError> for EmptyPayload {
     fn step(
         &mut self,
-        buffer: &mut bytes::BytesMut,
+        buffer: &mut BytesMut,
         state: State,
         _: RequestData,
-    ) -> Result<(RequestBody, State, ()), ((), State)> {
+    ) -> Result<(RequestBody, State, ()), (PayloadError, State)> {
+        if let Err(e) = state.check(Event::RequestBody) {
+            return Err((e.into(), state));
+        }
         buffer.clear();
         Ok((
             RequestBody {
@@ -34,14 +54,16 @@ pub struct LengthedPayload {
     pub remaining: usize,
 }
 
-impl Handle<RequestData, RequestBody, State, (), ()> for LengthedPayload {
+impl Handle<RequestData, RequestBody, State, (), PayloadError> for LengthedPayload {
     fn step(
         &mut self,
-        buffer: &mut bytes::BytesMut,
+        buffer: &mut BytesMut,
         state: State,
         inbound: RequestData,
-    ) -> Result<(RequestBody, State, ()), ((), State)> {
-        debug_assert!(matches!(state, State::RequestHeadFinished));
+    ) -> Result<(RequestBody, State, ()), (PayloadError, State)> {
+        if let Err(e) = state.check(Event::RequestBody) {
+            return Err((e.into(), state));
+        }
         let buf;
         buffer.extend(inbound.data);
 
@@ -60,12 +82,13 @@ impl Handle<RequestData, RequestBody, State, (), ()> for LengthedPayload {
                 State::RequestHeadFinished,
                 (),
             ))
-        } else if buffer.len() > self.remaining {
-            Err(((), State::Closed))
         } else {
+            // Bytes after Content-Length belong to the next request.
+            buf = buffer.split_to(self.remaining).freeze();
+            self.remaining = 0;
             Ok((
                 RequestBody {
-                    body: buffer.split().freeze(),
+                    body: buf,
                     more_body: false,
                 },
                 State::RequestBodyFinished,
@@ -75,10 +98,194 @@ impl Handle<RequestData, RequestBody, State, (), ()> for LengthedPayload {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ChunkPart {
+    // Waiting for `chunk-size [ chunk-ext ] CRLF`.
+    Size,
+    // Inside chunk data, with bytes left.
+    Data(usize),
+    // Waiting for CRLF after chunk data.
+    DataEnd,
+    // After the last chunk. Trailer fields are read and dropped.
+    Trailer,
+    Finished,
+}
+
+/// Decoder of `Transfer-Encoding: chunked` body.
+///
+/// Bytes after the last chunk are left in the buffer.
+#[derive(Debug)]
+pub struct ChunkedPayload {
+    part: ChunkPart,
+    // Bytes of the trailer section read so far, and the most allowed.
+    trailer_size: usize,
+    max_trailer_size: usize,
+}
+
+impl Default for ChunkedPayload {
+    fn default() -> Self {
+        Self::new(ServerConfig::default().max_head_size)
+    }
+}
+
+fn find_crlf(buffer: &[u8]) -> Option<usize> {
+    buffer.windows(2).position(|w| w == b"\r\n")
+}
+
+// Bare CR or LF, and other control characters, in a chunk line. Some servers
+// end the line there and others don't, so they disagree on the framing.
+fn has_control(line: &[u8]) -> bool {
+    line.iter().any(|&b| (b < 0x20 && b != b'\t') || b == 0x7f)
+}
+
+fn parse_chunk_size(line: &[u8]) -> Result<usize, PayloadError> {
+    let digits = line
+        .iter()
+        .position(|b| !b.is_ascii_hexdigit())
+        .unwrap_or(line.len());
+    if digits == 0 {
+        return Err(PayloadError::InvalidChunk("chunk size is missing"));
+    }
+    // Only whitespace and chunk extensions may follow the size.
+    let rest = line[digits..].trim_ascii_start();
+    if !(rest.is_empty() || rest[0] == b';') {
+        return Err(PayloadError::InvalidChunk("chunk size is not hex"));
+    }
+
+    let mut size: usize = 0;
+    for &b in &line[..digits] {
+        let digit = (b as char).to_digit(16).unwrap_or(0) as usize;
+        size = size
+            .checked_mul(16)
+            .and_then(|s| s.checked_add(digit))
+            .ok_or(PayloadError::InvalidChunk("chunk size overflow"))?;
+    }
+    Ok(size)
+}
+
+impl ChunkedPayload {
+    pub fn new(max_trailer_size: usize) -> Self {
+        Self {
+            part: ChunkPart::Size,
+            trailer_size: 0,
+            max_trailer_size,
+        }
+    }
+
+    // Decode as much as possible, appending chunk data to `body`.
+    fn decode(&mut self, buffer: &mut BytesMut, body: &mut BytesMut) -> Result<(), PayloadError> {
+        loop {
+            match self.part {
+                ChunkPart::Size | ChunkPart::Trailer => {
+                    // Same limit whether the line came in one read or many.
+                    let end = match find_crlf(buffer) {
+                        Some(end) if end <= MAX_CHUNK_LINE => end,
+                        None if buffer.len() <= MAX_CHUNK_LINE + 1 => return Ok(()),
+                        _ => return Err(PayloadError::InvalidChunk("chunk line is too long")),
+                    };
+                    let line = buffer.split_to(end + 2);
+                    if self.part == ChunkPart::Trailer {
+                        self.trailer_size += line.len();
+                        if self.trailer_size > self.max_trailer_size {
+                            return Err(PayloadError::InvalidChunk("trailer section is too large"));
+                        }
+                    }
+                    let line = &line[..end];
+                    if has_control(line) {
+                        return Err(PayloadError::InvalidChunk(
+                            "chunk line has control characters",
+                        ));
+                    }
+                    self.part = match self.part {
+                        ChunkPart::Size => match parse_chunk_size(line)? {
+                            0 => ChunkPart::Trailer,
+                            size => ChunkPart::Data(size),
+                        },
+                        _ if line.is_empty() => ChunkPart::Finished,
+                        _ => ChunkPart::Trailer,
+                    };
+                }
+                ChunkPart::Data(remaining) => {
+                    if buffer.is_empty() {
+                        return Ok(());
+                    }
+                    let size = remaining.min(buffer.len());
+                    body.extend_from_slice(&buffer.split_to(size));
+                    self.part = if size == remaining {
+                        ChunkPart::DataEnd
+                    } else {
+                        ChunkPart::Data(remaining - size)
+                    };
+                }
+                ChunkPart::DataEnd => {
+                    if buffer.len() < 2 {
+                        return Ok(());
+                    }
+                    if &buffer[..2] != b"\r\n" {
+                        return Err(PayloadError::InvalidChunk(
+                            "chunk data is not ended with CRLF",
+                        ));
+                    }
+                    let _ = buffer.split_to(2);
+                    self.part = ChunkPart::Size;
+                }
+                ChunkPart::Finished => return Ok(()),
+            }
+        }
+    }
+}
+
+impl Handle<RequestData, RequestBody, State, (), PayloadError> for ChunkedPayload {
+    fn step(
+        &mut self,
+        buffer: &mut BytesMut,
+        state: State,
+        inbound: RequestData,
+    ) -> Result<(RequestBody, State, ()), (PayloadError, State)> {
+        if let Err(e) = state.check(Event::RequestBody) {
+            return Err((e.into(), state));
+        }
+        buffer.extend(inbound.data);
+
+        let mut body = BytesMut::new();
+        if let Err(e) = self.decode(buffer, &mut body) {
+            return Err((e, State::Closed));
+        }
+
+        let finished = self.part == ChunkPart::Finished;
+        Ok((
+            RequestBody {
+                body: body.freeze(),
+                more_body: !finished,
+            },
+            if finished {
+                State::RequestBodyFinished
+            } else {
+                State::RequestHeadFinished
+            },
+            (),
+        ))
+    }
+}
+
 #[derive(Debug)]
 pub enum PayloadType {
     Lenghthed(LengthedPayload),
-    Chunked,
+    Chunked(ChunkedPayload),
+}
+
+impl Handle<RequestData, RequestBody, State, (), PayloadError> for PayloadType {
+    fn step(
+        &mut self,
+        buffer: &mut BytesMut,
+        state: State,
+        inbound: RequestData,
+    ) -> Result<(RequestBody, State, ()), (PayloadError, State)> {
+        match self {
+            Self::Lenghthed(payload) => payload.step(buffer, state, inbound),
+            Self::Chunked(payload) => payload.step(buffer, state, inbound),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -103,11 +310,25 @@ mod test {
             .unwrap();
 
         assert_eq!(body.body, Bytes::new());
-        assert_eq!(body.more_body, false);
+        assert!(!body.more_body);
         assert!(buffer.is_empty());
         assert!(matches!(next_state, State::RequestHeadFinished));
     }
 
+    #[test]
+    fn test_empty_payload_in_idle() {
+        let mut payload = EmptyPayload {};
+        let mut buffer = BytesMut::from(&b"data"[..]);
+
+        let result = payload.step(&mut buffer, State::Idle, RequestData { data: Bytes::new() });
+
+        assert!(matches!(
+            result,
+            Err((PayloadError::InvalidState(_), State::Idle))
+        ));
+        assert_eq!(buffer.as_ref(), b"data");
+    }
+
     #[test]
     fn test_lengthed_payload() {
         let size = 4;
@@ -125,7 +346,7 @@ mod test {
             .unwrap();
 
         assert_eq!(body.body, Bytes::from_static(b"data"));
-        assert_eq!(body.more_body, false);
+        assert!(!body.more_body);
         assert!(buffer.is_empty());
         assert!(matches!(next_state, State::RequestBodyFinished));
     }
@@ -147,19 +368,51 @@ mod test {
             .unwrap();
 
         assert_eq!(body.body, Bytes::from_static(b"data"));
-        assert_eq!(body.more_body, true);
+        assert!(body.more_body);
         assert_eq!(payload.remaining, 4);
         assert!(buffer.is_empty());
         assert!(matches!(next_state, State::RequestHeadFinished));
     }
 
     #[test]
-    fn test_lengthed_payload_with_too_much_data() {
+    fn test_lengthed_payload_split_across_three_reads() {
+        let mut payload = LengthedPayload { remaining: 6 };
+        let mut buffer = BytesMut::new();
+        let mut state = State::RequestHeadFinished;
+        let mut events = Vec::new();
+
+        for part in [&b"ab"[..], b"cd", b"ef"] {
+            let (body, next_state, _) = payload
+                .step(
+                    &mut buffer,
+                    state,
+                    RequestData {
+                        data: Bytes::copy_from_slice(part),
+                    },
+                )
+                .unwrap();
+            state = next_state;
+            events.push((body.body, body.more_body));
+        }
+
+        assert_eq!(
+            events,
+            vec![
+                (Bytes::from_static(b"ab"), true),
+                (Bytes::from_static(b"cd"), true),
+                (Bytes::from_static(b"ef"), false),
+            ]
+        );
+        assert!(matches!(state, State::RequestBodyFinished));
+    }
+
+    #[test]
+    fn test_lengthed_payload_keeps_next_request() {
         let size = 4;
         let mut payload = LengthedPayload { remaining: size };
         let mut buffer = BytesMut::new();
 
-        payload
+        let (body, next_state, _) = payload
             .step(
                 &mut buffer,
                 State::RequestHeadFinished,
@@ -167,6 +420,198 @@ mod test {
                     data: Bytes::from_static(b"testdata"),
                 },
             )
-            .unwrap_err();
+            .unwrap();
+
+        assert_eq!(body.body, Bytes::from_static(b"test"));
+        assert!(!body.more_body);
+        assert_eq!(buffer.as_ref(), b"data");
+        assert!(matches!(next_state, State::RequestBodyFinished));
+    }
+
+    fn chunked_step(
+        payload: &mut ChunkedPayload,
+        buffer: &mut BytesMut,
+        data: &'static [u8],
+    ) -> Result<(RequestBody, State, ()), (PayloadError, State)> {
+        payload.step(
+            buffer,
+            State::RequestHeadFinished,
+            RequestData {
+                data: Bytes::from_static(data),
+            },
+        )
+    }
+
+    #[test]
+    fn test_chunked_payload() {
+        let mut payload = ChunkedPayload::default();
+        let mut buffer = BytesMut::new();
+
+        let (body, next_state, _) = chunked_step(
+            &mut payload,
+            &mut buffer,
+            b"4\r\ndata\r\n6;ext=1\r\n-chunk\r\n0\r\n\r\n",
+        )
+        .unwrap();
+
+        assert_eq!(body.body, Bytes::from_static(b"data-chunk"));
+        assert!(!body.more_body);
+        assert!(buffer.is_empty());
+        assert!(matches!(next_state, State::RequestBodyFinished));
+    }
+
+    #[test]
+    fn test_chunked_payload_split() {
+        let mut payload = ChunkedPayload::default();
+        let mut buffer = BytesMut::new();
+
+        let (body, next_state, _) = chunked_step(&mut payload, &mut buffer, b"a\r\n01234").unwrap();
+        assert_eq!(body.body, Bytes::from_static(b"01234"));
+        assert!(body.more_body);
+        assert!(matches!(next_state, State::RequestHeadFinished));
+
+        let (body, _, _) = chunked_step(&mut payload, &mut buffer, b"56789\r").unwrap();
+        assert_eq!(body.body, Bytes::from_static(b"56789"));
+        assert!(body.more_body);
+
+        let (body, next_state, _) =
+            chunked_step(&mut payload, &mut buffer, b"\n0\r\nTrailer: x\r\n\r\n").unwrap();
+        assert_eq!(body.body, Bytes::new());
+        assert!(!body.more_body);
+        assert!(matches!(next_state, State::RequestBodyFinished));
+    }
+
+    #[test]
+    fn test_chunked_payload_keeps_next_request() {
+        let mut payload = ChunkedPayload::default();
+        let mut buffer = BytesMut::new();
+
+        chunked_step(&mut payload, &mut buffer, b"0\r\n\r\nGET / HTTP/1.1\r\n").unwrap();
+
+        assert_eq!(buffer.as_ref(), b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn test_chunked_payload_invalid_size() {
+        // Only whitespace before `;` may follow the size.
+        for line in [&b"zz\r\n"[..], b"1 x\r\n", b"1\tx\r\n", b"1 =a\r\n"] {
+            let mut payload = ChunkedPayload::default();
+            let mut buffer = BytesMut::new();
+
+            let result = chunked_step(&mut payload, &mut buffer, line);
+
+            assert!(
+                matches!(result, Err((PayloadError::InvalidChunk(_), State::Closed))),
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn test_chunked_payload_size_overflow() {
+        let mut payload = ChunkedPayload::default();
+        let mut buffer = BytesMut::new();
+
+        let result = chunked_step(&mut payload, &mut buffer, b"fffffffffffffffffffff\r\n");
+
+        assert!(matches!(
+            result,
+            Err((PayloadError::InvalidChunk(_), State::Closed))
+        ));
+    }
+
+    #[test]
+    fn test_chunked_payload_without_data_crlf() {
+        let mut payload = ChunkedPayload::default();
+        let mut buffer = BytesMut::new();
+
+        let result = chunked_step(&mut payload, &mut buffer, b"4\r\ndataXX");
+
+        assert!(matches!(
+            result,
+            Err((PayloadError::InvalidChunk(_), State::Closed))
+        ));
+    }
+
+    #[test]
+    fn test_payload_in_idle() {
+        let mut payload = LengthedPayload { remaining: 4 };
+        let mut buffer = BytesMut::new();
+
+        let result = payload.step(
+            &mut buffer,
+            State::Idle,
+            RequestData {
+                data: Bytes::from_static(b"data"),
+            },
+        );
+
+        assert!(matches!(
+            result,
+            Err((PayloadError::InvalidState(_), State::Idle))
+        ));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_chunked_payload_control_characters() {
+        for data in [
+            &b"1;a\nb\r\nx\r\n0\r\n\r\n"[..],
+            b"1;a\rb\r\nx\r\n0\r\n\r\n",
+            b"1;a=\"\0\"\r\nx\r\n0\r\n\r\n",
+            b"0\r\nTrailer: a\nb\r\n\r\n",
+        ] {
+            let mut payload = ChunkedPayload::default();
+            let mut buffer = BytesMut::new();
+
+            let result = payload.step(
+                &mut buffer,
+                State::RequestHeadFinished,
+                RequestData {
+                    data: Bytes::copy_from_slice(data),
+                },
+            );
+
+            assert!(
+                matches!(result, Err((PayloadError::InvalidChunk(_), State::Closed))),
+                "{:?}",
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn test_chunked_payload_trailer_too_large() {
+        let mut payload = ChunkedPayload::new(32);
+        let mut buffer = BytesMut::new();
+
+        chunked_step(&mut payload, &mut buffer, b"0\r\nTrailer-1: 0123456789\r\n").unwrap();
+        let result = chunked_step(&mut payload, &mut buffer, b"Trailer-2: 0123456789\r\n");
+
+        assert!(matches!(
+            result,
+            Err((PayloadError::InvalidChunk(_), State::Closed))
+        ));
+    }
+
+    #[test]
+    fn test_chunked_payload_line_too_long() {
+        let line = [b"1;", &b"x".repeat(MAX_CHUNK_LINE)[..], b"\r\n"].concat();
+        let mut payload = ChunkedPayload::default();
+        let mut buffer = BytesMut::new();
+
+        let result = payload.step(
+            &mut buffer,
+            State::RequestHeadFinished,
+            RequestData {
+                data: Bytes::from(line),
+            },
+        );
+
+        assert!(matches!(
+            result,
+            Err((PayloadError::InvalidChunk(_), State::Closed))
+        ));
     }
 }
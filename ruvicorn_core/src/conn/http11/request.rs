@@ -1,22 +1,28 @@
+use std::ops::Range;
+use std::sync::Arc;
+
 use bytes::{Bytes, BytesMut};
+use http::StatusCode;
 
+use crate::config::ServerConfig;
 use crate::handle::Handle;
+use crate::logger::target;
+use crate::types::RsHeader;
 
 use super::{
-    bound::{RequestData, RequestHead},
-    payload_handle::{LengthedPayload, PayloadType},
-    state::State,
+    bound::{HttpVersion, RequestData, RequestHead, TargetForm},
+    payload_handle::{ChunkedPayload, LengthedPayload, PayloadType},
+    state::{Event, InvalidState, State},
 };
 
-const MAX_HEADERS: usize = 96;
-
 mod special_headers {
     pub const CONTENT_LENGTH: &str = "Content-Length";
     pub const TRANSFER_ENCODING: &str = "Transfer-Encoding";
     pub const CONNECTION: &str = "Connection";
+    pub const HOST: &str = "Host";
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum KeepAlive {
     KeepAlive,
     Close,
@@ -32,14 +38,37 @@ pub struct ConnectionInfo {
 #[derive(Debug)]
 pub enum HeaderError {
     Duplicate(&'static str),
+    Missing(&'static str),
     InvalidValue(&'static str, &'static str),
+    // Transfer coding other than chunked. Answered with 501.
+    UnsupportedTransferCoding,
 }
 
 #[derive(Debug)]
 pub enum RequestError {
     InvalidRequest,
+    // Request-target form is not allowed for the method.
+    InvalidTarget(&'static str),
     InvalidHeader(HeaderError),
+    // Host is not in `ServerConfig::allowed_hosts`. Holds the Host.
+    UnknownHost(String),
     PartialRequest,
+    // Request head is bigger than `ServerConfig::max_head_size`.
+    HeadTooLarge,
+    InvalidState(InvalidState),
+}
+
+impl RequestError {
+    /// Status of the error response sent before closing the connection.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::InvalidHeader(HeaderError::UnsupportedTransferCoding) => {
+                StatusCode::NOT_IMPLEMENTED
+            }
+            Self::HeadTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
 }
 
 impl From<HeaderError> for RequestError {
@@ -48,163 +77,445 @@ impl From<HeaderError> for RequestError {
     }
 }
 
+impl From<InvalidState> for RequestError {
+    fn from(error: InvalidState) -> Self {
+        Self::InvalidState(error)
+    }
+}
+
 #[derive(Debug)]
-pub struct Request {}
-
-fn cast_header(buffer: &BytesMut, header: &httparse::Header) -> (Bytes, Bytes) {
-    let buf_ptr = buffer.as_ptr() as usize;
-    dbg!(buf_ptr);
-
-    let name_ptr = header.name.as_ptr() as usize;
-    dbg!(name_ptr);
-    let name = buffer
-        .clone()
-        .split_off(name_ptr - buf_ptr)
-        .split_to(header.name.len())
-        .freeze();
-
-    let value_ptr = header.value.as_ptr() as usize;
-    dbg!(value_ptr);
-    let value = buffer
-        .clone()
-        .split_off(value_ptr - buf_ptr)
-        .split_to(header.value.len())
-        .freeze();
-
-    return (name, value);
+pub struct Request {
+    config: Arc<ServerConfig>,
+}
+
+// Headers deciding the framing or host are read as text, and only ASCII is
+// valid.
+fn framing_value<'h>(name: &'static str, value: &'h [u8]) -> Result<&'h str, HeaderError> {
+    match std::str::from_utf8(value) {
+        Ok(v) if v.is_ascii() => Ok(v.trim()),
+        _ => Err(HeaderError::InvalidValue(
+            name,
+            "Header value is not ASCII.",
+        )),
+    }
+}
+
+// `Content-Length = 1*DIGIT`. `str::parse` alone also takes a leading `+`.
+fn parse_content_length(value: &str) -> Result<usize, HeaderError> {
+    if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(HeaderError::InvalidValue(
+            special_headers::CONTENT_LENGTH,
+            "Content-Length header is not valid integer",
+        ));
+    }
+    value.parse::<usize>().map_err(|_| {
+        HeaderError::InvalidValue(
+            special_headers::CONTENT_LENGTH,
+            "Content-Length header is too large",
+        )
+    })
+}
+
+// RFC 9112 section 6.1 and 6.3. Only chunked is decoded, so it must be the
+// one and only coding.
+fn check_transfer_codings(
+    codings: &[&str],
+    version: u8,
+    has_content_length: bool,
+) -> Result<(), HeaderError> {
+    let invalid = |reason| HeaderError::InvalidValue(special_headers::TRANSFER_ENCODING, reason);
+
+    if version == 0 {
+        return Err(invalid("Transfer-Encoding is not allowed in HTTP/1.0"));
+    }
+    if has_content_length {
+        return Err(invalid(
+            "Transfer-Encoding header can't be with Content-Length header",
+        ));
+    }
+    let is_chunked = |coding: &&str| "chunked".eq_ignore_ascii_case(coding);
+    match codings.iter().filter(|c| is_chunked(c)).count() {
+        0 if codings.is_empty() => return Err(invalid("Transfer-Encoding header is empty")),
+        0 => return Err(HeaderError::UnsupportedTransferCoding),
+        1 => {}
+        _ => return Err(invalid("chunked is applied more than once")),
+    }
+    if !codings.last().is_some_and(is_chunked) {
+        return Err(invalid("chunked must be the final transfer coding"));
+    }
+    if codings.len() > 1 {
+        return Err(HeaderError::UnsupportedTransferCoding);
+    }
+    Ok(())
+}
+
+// Host name of `uri-host [ ":" port ]`, RFC 9110 section 7.2. `None` if the
+// authority is not valid.
+fn host_name(authority: &str) -> Option<&str> {
+    let (name, port) = if authority.starts_with('[') {
+        let end = authority.find(']')?;
+        let (name, port) = authority.split_at(end + 1);
+        let literal = &name[1..end];
+        if literal.is_empty()
+            || !literal
+                .bytes()
+                .all(|b| b.is_ascii_hexdigit() || b == b':' || b == b'.')
+        {
+            return None;
+        }
+        (name, port)
+    } else {
+        let (name, port) = authority.split_at(authority.find(':').unwrap_or(authority.len()));
+        // reg-name: unreserved, sub-delims and pct-encoded.
+        let is_reg_name = |b: u8| b.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=%".contains(&b);
+        if name.is_empty() || !name.bytes().all(is_reg_name) {
+            return None;
+        }
+        (name, port)
+    };
+    match port.strip_prefix(':') {
+        None if port.is_empty() => Some(name),
+        Some(port) if port.bytes().all(|b| b.is_ascii_digit()) => Some(name),
+        _ => None,
+    }
+}
+
+// Request-target split into the parts given to the app.
+#[derive(Debug, PartialEq)]
+struct Target {
+    form: TargetForm,
+    path: String,
+    query_string: String,
+    host: Option<String>,
+    // Default port of the absolute-form scheme, which `host` may leave out.
+    default_port: Option<&'static str>,
+}
+
+fn split_query(target: &str) -> (String, String) {
+    match target.split_once('?') {
+        Some((path, query)) => (path.to_owned(), query.to_owned()),
+        None => (target.to_owned(), String::new()),
+    }
+}
+
+// `host:port` of authority-form, RFC 9112 section 3.2.3.
+fn is_authority_form(target: &str) -> bool {
+    match target.rsplit_once(':') {
+        Some((host, port)) => {
+            !host.is_empty()
+                && !host.contains(['/', '?', '#', '@'])
+                && !port.is_empty()
+                && port.bytes().all(|b| b.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
+// RFC 9112 section 3.2. CONNECT takes only authority-form and `*` is only for
+// OPTIONS. `httparse` already checked the target has no spaces or controls.
+fn parse_target(method: &str, target: &str) -> Result<Target, RequestError> {
+    if method == "CONNECT" {
+        if !is_authority_form(target) {
+            return Err(RequestError::InvalidTarget(
+                "CONNECT request-target must be authority-form",
+            ));
+        }
+        return Ok(Target {
+            form: TargetForm::Authority,
+            path: target.to_owned(),
+            query_string: String::new(),
+            host: Some(target.to_owned()),
+            default_port: None,
+        });
+    }
+
+    if target == "*" {
+        if method != "OPTIONS" {
+            return Err(RequestError::InvalidTarget(
+                "asterisk-form is only allowed for OPTIONS",
+            ));
+        }
+        return Ok(Target {
+            form: TargetForm::Asterisk,
+            path: target.to_owned(),
+            query_string: String::new(),
+            host: None,
+            default_port: None,
+        });
+    }
+
+    if target.starts_with('/') {
+        let (path, query_string) = split_query(target);
+        return Ok(Target {
+            form: TargetForm::Origin,
+            path,
+            query_string,
+            host: None,
+            default_port: None,
+        });
+    }
+
+    let (rest, default_port) = match target.split_once("://") {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => (rest, "80"),
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("https") => (rest, "443"),
+        _ => return Err(RequestError::InvalidTarget("Unknown request-target form")),
+    };
+    let (authority, path_query) = match rest.find(['/', '?']) {
+        Some(i) => rest.split_at(i),
+        None => (rest, ""),
+    };
+    // Userinfo is deprecated for http URIs, RFC 9110 section 4.2.4.
+    if host_name(authority).is_none() {
+        return Err(RequestError::InvalidTarget(
+            "absolute-form request-target has invalid authority",
+        ));
+    }
+    let (path, query_string) = split_query(path_query);
+    Ok(Target {
+        form: TargetForm::Absolute,
+        path: if path.is_empty() {
+            "/".to_owned()
+        } else {
+            path
+        },
+        query_string,
+        host: Some(authority.to_owned()),
+        default_port: Some(default_port),
+    })
+}
+
+// `authority` without its port if that is the default one, as both name the
+// same origin, RFC 9110 section 4.2.3. An empty port is the default too.
+fn without_default_port<'a>(authority: &'a str, default_port: &str) -> &'a str {
+    match authority.rsplit_once(':') {
+        Some((name, port)) if port.is_empty() || port == default_port => name,
+        _ => authority,
+    }
+}
+
+/// Position of header name and value in the request head.
+pub type HeaderSpan = (Range<usize>, Range<usize>);
+
+fn span_of(buffer: &[u8], part: &[u8]) -> Range<usize> {
+    let start = part.as_ptr() as usize - buffer.as_ptr() as usize;
+    start..start + part.len()
+}
+
+/// Record where each parsed header lives in `buffer`.
+///
+/// `httparse` borrows the buffer, so headers can't be sliced out of the frozen
+/// head until parsing is done. Keeping offsets instead lets the head be frozen
+/// once and shared by every header without copying.
+pub fn header_spans(buffer: &[u8], headers: &[httparse::Header]) -> Vec<HeaderSpan> {
+    let mut spans = Vec::with_capacity(headers.len());
+    for header in headers {
+        spans.push((
+            span_of(buffer, header.name.as_bytes()),
+            span_of(buffer, header.value),
+        ));
+    }
+    spans
+}
+
+pub fn slice_headers(head: &Bytes, spans: Vec<HeaderSpan>) -> Vec<RsHeader> {
+    spans
+        .into_iter()
+        .map(|(name, value)| (head.slice(name), head.slice(value)))
+        .collect()
 }
 
 impl Request {
+    pub fn new(config: Arc<ServerConfig>) -> Self {
+        Self { config }
+    }
+
+    /// Find the payload framing and connection option of a request.
+    ///
+    /// Framing follows RFC 9112 section 6 strictly, anything ambiguous is an
+    /// error so no other server in front of or behind us can read a
+    /// different request boundary.
     fn iterate_headers(
         &self,
-        buffer: &BytesMut,
         headers: &[httparse::Header],
-    ) -> Result<(ConnectionInfo, Vec<(Bytes, Bytes)>), HeaderError> {
-        let mut content_length: Option<u64> = None;
+        version: u8,
+    ) -> Result<ConnectionInfo, HeaderError> {
+        let mut content_length: Option<usize> = None;
 
-        let mut handled_te = false;
-        let mut chunked = false;
+        // Codings of every Transfer-Encoding field line, in order.
+        let mut codings: Vec<&str> = Vec::new();
+        let mut has_te = false;
 
         let mut keep_alive = KeepAlive::None;
 
-        let mut headers_vec: Vec<(Bytes, Bytes)> = Vec::new();
-
-        for header in headers.as_ref() {
+        for header in headers {
             let name = header.name;
-            let value = if let Ok(v) = std::str::from_utf8(header.value) {
-                v
-            } else {
+            // Other values are passed to the app as bytes, obs-text included.
+            if self.config.strict_headers && !header.value.is_ascii() {
                 return Result::Err(HeaderError::InvalidValue(
                     ":value:",
-                    "Not valid utf8 encoding.",
+                    "Header value is not ASCII.",
                 ));
             }
-            .trim();
 
             if special_headers::CONTENT_LENGTH.eq_ignore_ascii_case(name) {
-                if chunked {
-                    return Result::Err(HeaderError::InvalidValue(
-                        special_headers::CONTENT_LENGTH,
-                        "Content-Length header can'be with Transfer-Encoding: chunked header",
-                    ));
-                }
-
-                if content_length.is_some() {
-                    return Result::Err(HeaderError::Duplicate(special_headers::CONTENT_LENGTH));
-                }
-
-                if let Ok(len) = value.parse::<u64>() {
-                    content_length = Some(len);
-                } else {
-                    return Result::Err(HeaderError::InvalidValue(
-                        &special_headers::CONTENT_LENGTH,
-                        "Content-Length header is not valid integer",
-                    ));
-                }
-            } else if special_headers::TRANSFER_ENCODING.eq_ignore_ascii_case(name) {
-                if handled_te {
-                    return Result::Err(HeaderError::Duplicate(
-                        &special_headers::TRANSFER_ENCODING,
-                    ));
-                } else {
-                    handled_te = true;
-                }
-
+                let value = framing_value(special_headers::CONTENT_LENGTH, header.value)?;
+                // `Content-Length: 42, 42` and repeated identical lines are
+                // the same as one `Content-Length: 42`.
                 for eachv in value.split(',').map(str::trim) {
-                    if "chunked".eq_ignore_ascii_case(eachv) {
-                        if content_length.is_some_and(|v| v > 0) {
-                            return Result::Err(HeaderError::InvalidValue(
-                                &special_headers::TRANSFER_ENCODING,
-                                "Transfer-Encoding header can't be with Content-Length header",
-                            ));
-                        }
-                        chunked = true;
-                    } else if "identify".eq_ignore_ascii_case(eachv) {
-                        // Pass
-                    } else {
-                        return Result::Err(HeaderError::InvalidValue(
-                            &special_headers::TRANSFER_ENCODING,
-                            "Transfer-Encoding header has invalild value",
+                    let len = parse_content_length(eachv)?;
+                    if content_length.is_some_and(|v| v != len) {
+                        return Result::Err(HeaderError::Duplicate(
+                            special_headers::CONTENT_LENGTH,
                         ));
                     }
+                    content_length = Some(len);
                 }
+            } else if special_headers::TRANSFER_ENCODING.eq_ignore_ascii_case(name) {
+                let value = framing_value(special_headers::TRANSFER_ENCODING, header.value)?;
+                has_te = true;
+                codings.extend(value.split(',').map(str::trim).filter(|v| !v.is_empty()));
             } else if special_headers::CONNECTION.eq_ignore_ascii_case(name) {
-                keep_alive = if "keep-alive".eq_ignore_ascii_case(value) {
-                    KeepAlive::KeepAlive
-                } else if "close".eq_ignore_ascii_case(value) {
-                    KeepAlive::Close
-                } else {
-                    KeepAlive::None
+                let value = framing_value(special_headers::CONNECTION, header.value)?;
+                // `close` wins over `keep-alive` in any order or header line.
+                for token in value.split(',').map(str::trim) {
+                    if "close".eq_ignore_ascii_case(token) {
+                        keep_alive = KeepAlive::Close;
+                    } else if "keep-alive".eq_ignore_ascii_case(token)
+                        && keep_alive == KeepAlive::None
+                    {
+                        keep_alive = KeepAlive::KeepAlive;
+                    }
                 }
             }
+        }
 
-            let casted_header = cast_header(buffer, header);
-            headers_vec.push(casted_header);
+        // Connections are persistent by default from HTTP/1.1 only.
+        if keep_alive == KeepAlive::None {
+            keep_alive = if version == 1 {
+                KeepAlive::KeepAlive
+            } else {
+                KeepAlive::Close
+            };
+        }
+        if !self.config.keep_alive {
+            keep_alive = KeepAlive::Close;
         }
 
-        let payloadtype = if content_length.is_some_and(|v| v > 0) {
+        let payloadtype = if has_te {
+            check_transfer_codings(&codings, version, content_length.is_some())?;
+            PayloadType::Chunked(ChunkedPayload::new(self.config.max_head_size))
+        } else {
             PayloadType::Lenghthed(LengthedPayload {
-                remaining: content_length.unwrap() as usize,
+                remaining: content_length.unwrap_or(0),
             })
-        } else if chunked {
-            PayloadType::Chunked
-        } else {
-            PayloadType::Lenghthed(LengthedPayload { remaining: 0 })
         };
 
-        Ok((
-            ConnectionInfo {
-                keep_alive: keep_alive,
-                payload_type: payloadtype,
-            },
-            headers_vec,
-        ))
+        Ok(ConnectionInfo {
+            keep_alive,
+            payload_type: payloadtype,
+        })
+    }
+
+    // RFC 9112 section 3.2: HTTP/1.1 requests have exactly one Host, and it
+    // agrees with an absolute-form target.
+    fn check_host(
+        &self,
+        headers: &[httparse::Header],
+        version: u8,
+        target: &Target,
+    ) -> Result<Option<String>, RequestError> {
+        let mut values = headers
+            .iter()
+            .filter(|h| special_headers::HOST.eq_ignore_ascii_case(h.name));
+        let header = values.next();
+        if values.next().is_some() {
+            return Err(HeaderError::Duplicate(special_headers::HOST).into());
+        }
+
+        let host = match header {
+            Some(header) => {
+                let value = framing_value(special_headers::HOST, header.value)?;
+                if host_name(value).is_none() {
+                    return Err(HeaderError::InvalidValue(
+                        special_headers::HOST,
+                        "Host header is not a valid authority",
+                    )
+                    .into());
+                }
+                let matches_target = match (&target.host, target.default_port) {
+                    (Some(authority), Some(port)) => without_default_port(authority, port)
+                        .eq_ignore_ascii_case(without_default_port(value, port)),
+                    _ => true,
+                };
+                if !matches_target {
+                    return Err(HeaderError::InvalidValue(
+                        special_headers::HOST,
+                        "Host header does not match the request-target",
+                    )
+                    .into());
+                }
+                Some(value.to_owned())
+            }
+            None if version == 1 => return Err(HeaderError::Missing(special_headers::HOST).into()),
+            None => target.host.clone(),
+        };
+
+        let allowed = match host.as_deref().and_then(host_name) {
+            Some(name) => self.config.is_allowed_host(name),
+            None => self.config.allowed_hosts.is_empty(),
+        };
+        if !allowed {
+            return Err(RequestError::UnknownHost(host.unwrap_or_default()));
+        }
+        Ok(host)
     }
 
     fn parse(
         &mut self,
         buffer: &mut BytesMut,
     ) -> Result<(RequestHead, ConnectionInfo), RequestError> {
-        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut headers = vec![httparse::EMPTY_HEADER; self.config.max_headers];
         let mut req = httparse::Request::new(&mut headers);
 
-        match req.parse(buffer.as_ref()) {
-            Ok(status) => match status {
-                httparse::Status::Complete(_) => {
-                    let (info, headers) = self.iterate_headers(buffer, &req.headers)?;
-                    return Ok((
-                        RequestHead {
-                            method: req.method.unwrap().to_owned(),
-                            path: req.path.unwrap().to_owned(),
-                            headers: headers,
-                        },
-                        info,
-                    ));
-                }
-                httparse::Status::Partial => return Err(RequestError::PartialRequest),
-            },
+        // Complete head is limited too, so the result doesn't depend on how
+        // the head was split into reads.
+        let head_len = match req.parse(buffer.as_ref()) {
+            Ok(httparse::Status::Complete(len)) if len > self.config.max_head_size => {
+                return Err(RequestError::HeadTooLarge)
+            }
+            Ok(httparse::Status::Complete(len)) => len,
+            Ok(httparse::Status::Partial) if buffer.len() > self.config.max_head_size => {
+                return Err(RequestError::HeadTooLarge)
+            }
+            Ok(httparse::Status::Partial) => return Err(RequestError::PartialRequest),
             Err(_) => return Err(RequestError::InvalidRequest),
-        }
+        };
+
+        let version = req.version.unwrap();
+        let info = self.iterate_headers(req.headers, version)?;
+        let method = req.method.unwrap().to_owned();
+        let target = parse_target(&method, req.path.unwrap())?;
+        let host = self.check_host(req.headers, version, &target)?;
+        let spans = header_spans(buffer, req.headers);
+
+        // Leave only the payload in the buffer.
+        let head = buffer.split_to(head_len).freeze();
+        Ok((
+            RequestHead {
+                method,
+                target: target.form,
+                path: target.path,
+                query_string: target.query_string,
+                host,
+                version: if version == 0 {
+                    HttpVersion::Http10
+                } else {
+                    HttpVersion::Http11
+                },
+                headers: slice_headers(&head, spans),
+            },
+            info,
+        ))
     }
 }
 
@@ -215,13 +526,17 @@ impl Handle<RequestData, RequestHead, State, ConnectionInfo, RequestError> for R
         state: State,
         inbound: RequestData,
     ) -> Result<(RequestHead, State, ConnectionInfo), (RequestError, State)> {
-        debug_assert!(matches!(state, State::Idle));
-        dbg!(buffer.as_ptr() as usize);
+        if let Err(e) = state.check(Event::RequestHead) {
+            return Err((e.into(), state));
+        }
         buffer.extend(&inbound.data);
-        dbg!(buffer.as_ptr() as usize);
         match self.parse(buffer) {
             Ok((head, info)) => Ok((head, State::RequestHeadFinished, info)),
-            Err(e) => Err((e, State::Closed)),
+            Err(RequestError::PartialRequest) => Err((RequestError::PartialRequest, State::Idle)),
+            Err(e) => {
+                log::debug!(target: target::PROTOCOL, "Invalid HTTP request: {:?}", e);
+                Err((e, State::Closed))
+            }
         }
     }
 }
@@ -229,6 +544,7 @@ impl Handle<RequestData, RequestHead, State, ConnectionInfo, RequestError> for R
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::alloc_counter::count_allocations;
 
     macro_rules! data {
         ($code:expr) => {
@@ -240,18 +556,21 @@ mod test {
 
     #[test]
     fn test_partial_request() {
-        let mut request = Request {};
+        let mut request = Request::new(Arc::default());
         let mut buffer = BytesMut::new();
         let state = State::Idle;
 
-        request
-            .step(&mut buffer, state, data!(b"GET /"))
-            .expect_err("Invalid request head");
+        let result = request.step(&mut buffer, state, data!(b"GET /"));
+
+        assert!(matches!(
+            result,
+            Err((RequestError::PartialRequest, State::Idle))
+        ));
     }
 
     #[test]
     fn test_get_request() {
-        let mut request = Request {};
+        let mut request = Request::new(Arc::default());
         let mut buffer = BytesMut::new();
         let state = State::Idle;
 
@@ -264,17 +583,224 @@ mod test {
             .unwrap();
 
         assert_eq!(head.method, "GET");
+        assert_eq!(head.target, TargetForm::Origin);
         assert_eq!(head.path, "/test");
+        assert_eq!(head.query_string, "");
         assert_eq!(head.headers.len(), 1);
 
         assert!(matches!(state, State::RequestHeadFinished));
-        assert!(matches!(conninfo.keep_alive, KeepAlive::None));
+        assert_eq!(head.version, HttpVersion::Http11);
+        assert!(matches!(conninfo.keep_alive, KeepAlive::KeepAlive));
         assert!(matches!(conninfo.payload_type, PayloadType::Lenghthed(len) if len.remaining == 0 ))
     }
 
+    #[test]
+    fn test_request_target_forms() {
+        let target = |form, path: &str, query_string: &str, host: Option<&str>| Target {
+            form,
+            path: path.to_owned(),
+            query_string: query_string.to_owned(),
+            host: host.map(str::to_owned),
+            default_port: None,
+        };
+        let absolute = |path, query_string, host, default_port| Target {
+            default_port: Some(default_port),
+            ..target(TargetForm::Absolute, path, query_string, Some(host))
+        };
+        let cases = [
+            (
+                "GET",
+                "/a/b?c=d&e",
+                target(TargetForm::Origin, "/a/b", "c=d&e", None),
+            ),
+            ("GET", "/a?", target(TargetForm::Origin, "/a", "", None)),
+            (
+                "GET",
+                "http://example.com/a?b",
+                absolute("/a", "b", "example.com", "80"),
+            ),
+            (
+                "GET",
+                "HTTPS://example.com:8443",
+                absolute("/", "", "example.com:8443", "443"),
+            ),
+            (
+                "GET",
+                "http://example.com?b",
+                absolute("/", "b", "example.com", "80"),
+            ),
+            (
+                "CONNECT",
+                "example.com:443",
+                target(
+                    TargetForm::Authority,
+                    "example.com:443",
+                    "",
+                    Some("example.com:443"),
+                ),
+            ),
+            (
+                "CONNECT",
+                "[::1]:443",
+                target(TargetForm::Authority, "[::1]:443", "", Some("[::1]:443")),
+            ),
+            ("OPTIONS", "*", target(TargetForm::Asterisk, "*", "", None)),
+        ];
+
+        for (method, raw, expected) in cases {
+            assert_eq!(
+                parse_target(method, raw).unwrap(),
+                expected,
+                "{} {}",
+                method,
+                raw
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_request_target() {
+        let cases = [
+            "GET * HTTP/1.1\r\n\r\n",
+            "GET example.com:443 HTTP/1.1\r\n\r\n",
+            "GET ftp://example.com/ HTTP/1.1\r\n\r\n",
+            "GET http:///a HTTP/1.1\r\n\r\n",
+            "GET http://user@example.com/ HTTP/1.1\r\n\r\n",
+            "GET a/b HTTP/1.1\r\n\r\n",
+            "CONNECT /a HTTP/1.1\r\n\r\n",
+            "CONNECT example.com HTTP/1.1\r\n\r\n",
+            "CONNECT http://example.com:443 HTTP/1.1\r\n\r\n",
+            "OPTIONS ** HTTP/1.1\r\n\r\n",
+        ];
+
+        for case in cases {
+            let mut request = Request::new(Arc::default());
+            let mut buffer = BytesMut::new();
+            let inbound = RequestData {
+                data: Bytes::from_static(case.as_bytes()),
+            };
+
+            let (error, state) = request.step(&mut buffer, State::Idle, inbound).unwrap_err();
+
+            assert!(matches!(error, RequestError::InvalidTarget(_)), "{}", case);
+            assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(state, State::Closed);
+        }
+    }
+
+    fn host_of(
+        config: ServerConfig,
+        request: &'static [u8],
+    ) -> Result<Option<String>, RequestError> {
+        let mut request_handle = Request::new(Arc::new(config));
+        let mut buffer = BytesMut::new();
+        request_handle
+            .step(&mut buffer, State::Idle, data!(request))
+            .map(|(head, _, _)| head.host)
+            .map_err(|(e, _)| e)
+    }
+
+    #[test]
+    fn test_host() {
+        let cases: [(&[u8], Option<&str>); 8] = [
+            (
+                b"GET / HTTP/1.1\r\nHost: example.com:8000\r\n\r\n",
+                Some("example.com:8000"),
+            ),
+            (
+                b"GET / HTTP/1.1\r\nHost: [::1]:8000\r\n\r\n",
+                Some("[::1]:8000"),
+            ),
+            (
+                b"GET http://example.com/ HTTP/1.1\r\nHost: EXAMPLE.com\r\n\r\n",
+                Some("EXAMPLE.com"),
+            ),
+            (
+                b"GET http://example.com/ HTTP/1.0\r\n\r\n",
+                Some("example.com"),
+            ),
+            (b"GET / HTTP/1.0\r\n\r\n", None),
+            // The default port of the scheme can be left out of either.
+            (
+                b"GET http://example.com/ HTTP/1.1\r\nHost: example.com:80\r\n\r\n",
+                Some("example.com:80"),
+            ),
+            (
+                b"GET https://example.com:443/ HTTP/1.1\r\nHost: example.com\r\n\r\n",
+                Some("example.com"),
+            ),
+            (
+                b"GET http://[::1]/ HTTP/1.1\r\nHost: [::1]:80\r\n\r\n",
+                Some("[::1]:80"),
+            ),
+        ];
+
+        for (request, expected) in cases {
+            let host = host_of(ServerConfig::default(), request).unwrap();
+            assert_eq!(
+                host.as_deref(),
+                expected,
+                "{}",
+                String::from_utf8_lossy(request)
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_host() {
+        let cases: [&[u8]; 10] = [
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a\r\nHost: a\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost:\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a/b\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: user@a\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: a:80x\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: [zz]\r\n\r\n",
+            b"GET http://example.com/ HTTP/1.1\r\nHost: other.com\r\n\r\n",
+            b"GET http://example.com/ HTTP/1.1\r\nHost: example.com:443\r\n\r\n",
+            b"GET https://example.com/ HTTP/1.1\r\nHost: example.com:80\r\n\r\n",
+        ];
+
+        for request in cases {
+            let error = host_of(ServerConfig::default(), request).unwrap_err();
+            assert!(
+                matches!(error, RequestError::InvalidHeader(_)),
+                "{}",
+                String::from_utf8_lossy(request)
+            );
+            assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn test_allowed_hosts() {
+        let config = || ServerConfig {
+            allowed_hosts: vec!["example.com".to_owned(), "*.example.org".to_owned()],
+            ..Default::default()
+        };
+
+        assert!(host_of(
+            config(),
+            b"GET / HTTP/1.1\r\nHost: example.com:8000\r\n\r\n"
+        )
+        .is_ok());
+        assert!(host_of(config(), b"GET / HTTP/1.1\r\nHost: api.example.org\r\n\r\n").is_ok());
+        assert!(host_of(config(), b"GET http://example.com/ HTTP/1.0\r\n\r\n").is_ok());
+
+        for request in [
+            &b"GET / HTTP/1.1\r\nHost: evil.com\r\n\r\n"[..],
+            b"GET / HTTP/1.1\r\nHost: example.org\r\n\r\n",
+            b"GET / HTTP/1.0\r\n\r\n",
+        ] {
+            let error = host_of(config(), request).unwrap_err();
+            assert!(matches!(error, RequestError::UnknownHost(_)));
+            assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
     #[test]
     fn test_chunked() {
-        let mut request = Request {};
+        let mut request = Request::new(Arc::default());
         let mut buffer = BytesMut::new();
         let state = State::Idle;
 
@@ -282,18 +808,18 @@ mod test {
             .step(
                 &mut buffer,
                 state,
-                data!(b"GET /test HTTP/1.1\r\nTransfer-Encoding:chunked\r\n\r\n"),
+                data!(b"GET /test HTTP/1.1\r\nHost:localhost\r\nTransfer-Encoding:chunked\r\n\r\n"),
             )
             .unwrap();
 
         assert!(matches!(head, RequestHead { .. }));
-        assert!(matches!(conninfo.payload_type, PayloadType::Chunked));
+        assert!(matches!(conninfo.payload_type, PayloadType::Chunked(_)));
         assert!(matches!(state, State::RequestHeadFinished));
     }
 
     #[test]
     fn test_post_request() {
-        let mut request = Request {};
+        let mut request = Request::new(Arc::default());
         let mut buffer = BytesMut::new();
         let state = State::Idle;
 
@@ -312,14 +838,31 @@ mod test {
 
     #[test]
     fn test_content_length_duplicate() {
-        let mut request = Request {};
+        let mut request = Request::new(Arc::default());
+        let mut buffer = BytesMut::new();
+        let state = State::Idle;
+
+        let (_, _, conninfo) = request
+            .step(
+                &mut buffer,
+                state,
+                data!(b"GET /test HTTP/1.1\r\nContent-Length:1\r\nContent-Length:1\r\nHost:localhost\r\n\r\n"),
+            )
+            .unwrap();
+
+        assert!(matches!(conninfo.payload_type, PayloadType::Lenghthed(len) if len.remaining == 1));
+    }
+
+    #[test]
+    fn test_content_length_duplicate_different() {
+        let mut request = Request::new(Arc::default());
         let mut buffer = BytesMut::new();
         let state = State::Idle;
 
         let result = dbg!(request.step(
             &mut buffer,
             state,
-            data!(b"GET /test HTTP/1.1\r\nContent-Length:1\r\nContent-Length:1\r\nHost:localhost\r\n\r\n"),
+            data!(b"GET /test HTTP/1.1\r\nContent-Length:1\r\nContent-Length:2\r\nHost:localhost\r\n\r\n"),
         ));
 
         assert!(result.is_err_and(|e| 
@@ -330,7 +873,7 @@ mod test {
 
     #[test]
     fn test_content_length_invalid() {
-        let mut request = Request {};
+        let mut request = Request::new(Arc::default());
         let mut buffer = BytesMut::new();
         let state = State::Idle;
 
@@ -349,7 +892,7 @@ mod test {
 
     #[test]
     fn test_tranfer_encoding_duplicate() {
-        let mut request = Request {};
+        let mut request = Request::new(Arc::default());
         let mut buffer = BytesMut::new();
         let state = State::Idle;
 
@@ -361,14 +904,14 @@ mod test {
         ));
 
         assert!(result.is_err_and(|e| matches!(e.0,
-                    RequestError::InvalidHeader(he) if 
-                        matches!(he, HeaderError::Duplicate(name) if name == "Transfer-Encoding"))
+                    RequestError::InvalidHeader(he) if
+                        matches!(he, HeaderError::InvalidValue(name, _) if name == "Transfer-Encoding"))
             && matches!(e.1, State::Closed)));
     }
 
     #[test]
     fn test_content_length_with_chunked() {
-        let mut request = Request {};
+        let mut request = Request::new(Arc::default());
         let mut buffer = BytesMut::new();
         let state = State::Idle;
 
@@ -387,7 +930,7 @@ mod test {
 
     #[test]
     fn test_keep_alive() {
-        let mut request = Request {};
+        let mut request = Request::new(Arc::default());
         let mut buffer = BytesMut::new();
         let state = State::Idle;
 
@@ -405,7 +948,7 @@ mod test {
 
     #[test]
     fn test_close_connection() {
-        let mut request = Request {};
+        let mut request = Request::new(Arc::default());
         let mut buffer = BytesMut::new();
         let state = State::Idle;
 
@@ -421,24 +964,355 @@ mod test {
         assert!(matches!(state, State::RequestHeadFinished));
     }
 
+    fn keep_alive_of(request: &'static [u8]) -> (HttpVersion, KeepAlive) {
+        let mut request_handle = Request::new(Arc::default());
+        let mut buffer = BytesMut::new();
+        let (head, _, conninfo) = request_handle
+            .step(&mut buffer, State::Idle, data!(request))
+            .unwrap();
+        (head.version, conninfo.keep_alive)
+    }
+
+    #[test]
+    fn test_connection_token_list() {
+        assert_eq!(
+            keep_alive_of(b"GET / HTTP/1.0\r\nConnection: keep-alive, Upgrade\r\n\r\n"),
+            (HttpVersion::Http10, KeepAlive::KeepAlive)
+        );
+        assert_eq!(
+            keep_alive_of(b"GET / HTTP/1.1\r\nHost:localhost\r\nConnection: Upgrade,CLOSE\r\n\r\n"),
+            (HttpVersion::Http11, KeepAlive::Close)
+        );
+        assert_eq!(
+            keep_alive_of(b"GET / HTTP/1.1\r\nHost:localhost\r\nConnection: keep-alive\r\nConnection: close\r\n\r\n"),
+            (HttpVersion::Http11, KeepAlive::Close)
+        );
+    }
+
+    #[test]
+    fn test_http10_defaults_to_close() {
+        assert_eq!(
+            keep_alive_of(b"GET / HTTP/1.0\r\n\r\n"),
+            (HttpVersion::Http10, KeepAlive::Close)
+        );
+        assert_eq!(
+            keep_alive_of(b"GET / HTTP/1.1\r\nHost:localhost\r\nConnection: Upgrade\r\n\r\n"),
+            (HttpVersion::Http11, KeepAlive::KeepAlive)
+        );
+    }
+
     #[test]
     fn test_request_with_too_many_headers() {
-        let mut request = Request {};
+        let mut request = Request::new(Arc::default());
         let mut buffer = BytesMut::new();
         let state = State::Idle;
         let data = Bytes::from(
             [
                 Vec::from(b"GET /test HTTP/1.1\r\n"),
-                Vec::from(b"X:X\r\n".repeat(MAX_HEADERS + 1)),
+                b"X:X\r\n".repeat(ServerConfig::default().max_headers + 1),
                 Vec::from(b"\r\n".to_owned()),
             ]
             .concat(),
         );
 
         assert!(request
-            .step(&mut buffer, state, RequestData { data: data },)
+            .step(&mut buffer, state, RequestData { data },)
             .is_err_and(
                 |e| matches!(e.0, RequestError::InvalidRequest) && matches!(e.1, State::Closed)
             ));
     }
+
+    #[test]
+    fn test_complete_head_too_large() {
+        let config = ServerConfig {
+            max_head_size: 32,
+            ..Default::default()
+        };
+        let mut request = Request::new(Arc::new(config));
+        let mut buffer = BytesMut::new();
+
+        let result = request.step(
+            &mut buffer,
+            State::Idle,
+            data!(b"GET /test HTTP/1.1\r\nHost: localhost\r\n\r\n"),
+        );
+
+        assert!(matches!(
+            result,
+            Err((RequestError::HeadTooLarge, State::Closed))
+        ));
+    }
+
+    // Known request smuggling payloads, and near misses that must be accepted.
+    // `Ok` is the body framing, `Err` the status of the error response.
+    const SMUGGLING_CORPUS: &[(&[u8], Result<Framing, u16>)] = &[
+        // CL.TE and TE.CL
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 6\r\nTransfer-Encoding: chunked\r\n\r\n",
+            Err(400),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nContent-Length: 6\r\n\r\n",
+            Err(400),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 0\r\nTransfer-Encoding: chunked\r\n\r\n",
+            Err(400),
+        ),
+        // TE.TE obfuscation
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: x\r\n\r\n",
+            Err(400),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked, identity\r\n\r\n",
+            Err(400),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked, chunked\r\n\r\n",
+            Err(400),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: xchunked\r\n\r\n",
+            Err(501),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: identity\r\n\r\n",
+            Err(501),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: identify\r\n\r\n",
+            Err(501),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: gzip, chunked\r\n\r\n",
+            Err(501),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: \"chunked\"\r\n\r\n",
+            Err(501),
+        ),
+        (b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding:\r\n\r\n", Err(400)),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding : chunked\r\n\r\n",
+            Err(400),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding:\r\n chunked\r\n\r\n",
+            Err(400),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding\x00: chunked\r\n\r\n",
+            Err(400),
+        ),
+        (
+            b"POST / HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n",
+            Err(400),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: CHUNKED\r\n\r\n",
+            Ok(Framing::Chunked),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding:\tchunked \r\n\r\n",
+            Ok(Framing::Chunked),
+        ),
+        // Content-Length
+        (b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 1, 2\r\n\r\n", Err(400)),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n",
+            Err(400),
+        ),
+        (b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: +5\r\n\r\n", Err(400)),
+        (b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: -1\r\n\r\n", Err(400)),
+        (b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 0x5\r\n\r\n", Err(400)),
+        (b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5 5\r\n\r\n", Err(400)),
+        (b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5,\r\n\r\n", Err(400)),
+        (b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length:\r\n\r\n", Err(400)),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 99999999999999999999999\r\n\r\n",
+            Err(400),
+        ),
+        (b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length : 5\r\n\r\n", Err(400)),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5, 5\r\n\r\n",
+            Ok(Framing::Length(5)),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\n",
+            Ok(Framing::Length(5)),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 007\r\n\r\n",
+            Ok(Framing::Length(7)),
+        ),
+        // Chunk lines, decoded after the head
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n1;a\nb\r\nx\r\n0\r\n\r\n",
+            Err(400),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n1;a\rb\r\nx\r\n0\r\n\r\n",
+            Err(400),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nX: a\nb\r\n\r\n",
+            Err(400),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n1;a=b\r\nx\r\n0\r\n\r\n",
+            Ok(Framing::Chunked),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n1 x\r\nx\r\n0\r\n\r\n",
+            Err(400),
+        ),
+        (
+            b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n1 ;a=b\r\nx\r\n0\r\n\r\n",
+            Ok(Framing::Chunked),
+        ),
+        (
+            concat!(
+                "POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n",
+                "X-1: 0123456789012345678901234567890123456789012345678901234567890123456789\r\n",
+                "X-2: 0123456789012345678901234567890123456789012345678901234567890123456789\r\n",
+                "X-3: 0123456789012345678901234567890123456789012345678901234567890123456789\r\n",
+                "X-4: 0123456789012345678901234567890123456789012345678901234567890123456789\r\n\r\n",
+            )
+            .as_bytes(),
+            Err(400),
+        ),
+    ];
+
+    #[derive(Debug, PartialEq)]
+    enum Framing {
+        Length(usize),
+        Chunked,
+    }
+
+    #[test]
+    fn test_smuggling_corpus() {
+        // Small enough for a short payload to go over it.
+        let config = Arc::new(ServerConfig {
+            max_head_size: 256,
+            ..Default::default()
+        });
+        for (payload, expected) in SMUGGLING_CORPUS {
+            let mut request = Request::new(config.clone());
+            let mut buffer = BytesMut::new();
+
+            let result = request
+                .step(&mut buffer, State::Idle, data!(payload))
+                .map_err(|(e, _)| e.status().as_u16())
+                .and_then(|(_, state, mut info)| {
+                    // Body bytes are decoded too. Errors are answered with 400.
+                    info.payload_type
+                        .step(&mut buffer, state, data!(b""))
+                        .map_err(|_| 400u16)?;
+                    Ok(match info.payload_type {
+                        PayloadType::Lenghthed(p) => Framing::Length(p.remaining),
+                        PayloadType::Chunked(_) => Framing::Chunked,
+                    })
+                });
+
+            assert_eq!(&result, expected, "{}", String::from_utf8_lossy(payload));
+        }
+    }
+
+    #[test]
+    fn test_obs_text_header_value() {
+        let mut request = Request::new(Arc::default());
+        let mut buffer = BytesMut::new();
+
+        let (head, _, _) = request
+            .step(
+                &mut buffer,
+                State::Idle,
+                data!(b"GET /test HTTP/1.1\r\nHost:localhost\r\nX-Name: caf\xe9\r\n\r\n"),
+            )
+            .unwrap();
+
+        assert_eq!(head.headers[1].1, Bytes::from_static(b"caf\xe9"));
+    }
+
+    #[test]
+    fn test_obs_text_framing_header() {
+        let mut request = Request::new(Arc::default());
+        let mut buffer = BytesMut::new();
+
+        let result = request.step(
+            &mut buffer,
+            State::Idle,
+            data!(b"POST /test HTTP/1.1\r\nTransfer-Encoding: chunked\xff\r\n\r\n"),
+        );
+
+        assert!(matches!(
+            result,
+            Err((
+                RequestError::InvalidHeader(HeaderError::InvalidValue("Transfer-Encoding", _)),
+                State::Closed
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_obs_text_header_value_strict() {
+        let config = ServerConfig {
+            strict_headers: true,
+            ..Default::default()
+        };
+        let mut request = Request::new(Arc::new(config));
+        let mut buffer = BytesMut::new();
+
+        let result = request.step(
+            &mut buffer,
+            State::Idle,
+            data!(b"GET /test HTTP/1.1\r\nHost:localhost\r\nX-Name: caf\xe9\r\n\r\n"),
+        );
+
+        assert!(matches!(
+            result,
+            Err((RequestError::InvalidHeader(_), State::Closed))
+        ));
+    }
+
+    fn request_with_headers(count: usize) -> BytesMut {
+        let mut data = BytesMut::from(&b"GET /test HTTP/1.1\r\nHost: localhost\r\n"[..]);
+        for i in 1..count {
+            data.extend(format!("X-Header-{}: value {}\r\n", i, i).as_bytes());
+        }
+        data.extend(b"\r\n");
+        data
+    }
+
+    #[test]
+    fn test_headers_share_request_head() {
+        let mut request = Request::new(Arc::default());
+        let mut buffer = request_with_headers(3);
+        buffer.extend(b"body");
+        let head_range = buffer.as_ptr_range();
+
+        let (head, _) = request.parse(&mut buffer).unwrap();
+
+        assert_eq!(buffer.as_ref(), b"body");
+        for (name, value) in head.headers {
+            assert!(head_range.contains(&name.as_ptr()));
+            assert!(head_range.contains(&value.as_ptr()));
+        }
+    }
+
+    #[test]
+    fn test_parse_allocations_do_not_grow_with_headers() {
+        let mut request = Request::new(Arc::default());
+        let mut few = request_with_headers(3);
+        let mut many = request_with_headers(30);
+
+        let (few_head, few_allocs) = count_allocations(|| request.parse(&mut few).unwrap().0);
+        let (many_head, many_allocs) = count_allocations(|| request.parse(&mut many).unwrap().0);
+
+        assert_eq!(few_head.headers.len(), 3);
+        assert_eq!(many_head.headers.len(), 30);
+        assert_eq!(few_allocs, many_allocs);
+        assert!(many_allocs <= 8, "{} allocations", many_allocs);
+    }
 }
@@ -1,15 +1,35 @@
+use std::time::SystemTime;
+
+use crate::date::http_date;
 use crate::handle::Handle;
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use http::{status::InvalidStatusCode, StatusCode};
 
 use super::{
-    bound::{ResponseData, ResponseStart},
-    state::State,
+    bound::{HttpVersion, ResponseBody, ResponseData, ResponseStart},
+    request::KeepAlive,
+    state::{Event, InvalidState, State},
 };
 
 #[derive(Debug)]
-enum ResponseError {
+pub enum ResponseError {
     InvalidStatusCode(InvalidStatusCode),
+    // Header name is not a token. Holds the name.
+    InvalidHeaderName(Bytes),
+    // Header value has CR, LF or other control characters. Holds the name.
+    InvalidHeaderValue(Bytes),
+    // Content-Length is not a number, differs between headers or comes with
+    // Transfer-Encoding. Holds the value.
+    InvalidContentLength(Bytes),
+    // Body is longer than Content-Length.
+    BodyTooLong,
+    InvalidState(InvalidState),
+}
+
+impl From<InvalidState> for ResponseError {
+    fn from(error: InvalidState) -> Self {
+        Self::InvalidState(error)
+    }
 }
 
 impl From<InvalidStatusCode> for ResponseError {
@@ -18,55 +38,329 @@ impl From<InvalidStatusCode> for ResponseError {
     }
 }
 
-struct ResponseHead {}
+// RFC 9110 `token`.
+fn is_token(name: &[u8]) -> bool {
+    !name.is_empty()
+        && name.iter().all(|&b| {
+            b.is_ascii_alphanumeric()
+                || matches!(
+                    b,
+                    b'!' | b'#'
+                        | b'$'
+                        | b'%'
+                        | b'&'
+                        | b'\''
+                        | b'*'
+                        | b'+'
+                        | b'-'
+                        | b'.'
+                        | b'^'
+                        | b'_'
+                        | b'`'
+                        | b'|'
+                        | b'~'
+                )
+        })
+}
+
+// RFC 9110 `field-value`: visible characters, obs-text, SP and HTAB.
+fn is_field_value(value: &[u8]) -> bool {
+    value
+        .iter()
+        .all(|&b| b == b'\t' || (b >= 0x20 && b != 0x7f))
+}
+
+// Every header is checked before anything is written, so a bad header from
+// the app can't inject headers or split the response.
+fn check_headers(headers: &[(Bytes, Bytes)]) -> Result<(), ResponseError> {
+    for (name, value) in headers {
+        if !is_token(name) {
+            return Err(ResponseError::InvalidHeaderName(name.clone()));
+        }
+        if !is_field_value(value) {
+            return Err(ResponseError::InvalidHeaderValue(name.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Empty response sent by the server itself before closing the connection.
+pub fn error_response(status: StatusCode) -> Bytes {
+    let mut buffer = BytesMut::with_capacity(96);
+    buffer.put_slice(b"HTTP/1.1 ");
+    buffer.put_slice(status.as_str().as_bytes());
+    buffer.put_slice(b" ");
+    buffer.put_slice(status.canonical_reason().unwrap_or("").as_bytes());
+    buffer.put_slice(b"\r\nConnection: close\r\nContent-Length: 0\r\n\r\n");
+    buffer.freeze()
+}
+
+// What the app's response headers say about the connection and framing.
+#[derive(Debug, Default)]
+struct HeaderInfo {
+    // `Connection` has `close`.
+    close: bool,
+    // `Connection` has `keep-alive`.
+    keep_alive: bool,
+    content_length: Option<u64>,
+    // `Transfer-Encoding` is set, and whether its last coding is `chunked`.
+    transfer_encoding: Option<bool>,
+    // App sent its own `Server` and `Date`.
+    server: bool,
+    date: bool,
+}
+
+fn header_info(headers: &[(Bytes, Bytes)]) -> Result<HeaderInfo, ResponseError> {
+    let mut info = HeaderInfo::default();
+    let mut content_length = None;
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case(b"connection") {
+            for token in value.split(|b| *b == b',') {
+                let token = token.trim_ascii();
+                info.close |= token.eq_ignore_ascii_case(b"close");
+                info.keep_alive |= token.eq_ignore_ascii_case(b"keep-alive");
+            }
+        } else if name.eq_ignore_ascii_case(b"content-length") {
+            let length = parse_content_length(value)
+                .filter(|length| info.content_length.unwrap_or(*length) == *length)
+                .ok_or_else(|| ResponseError::InvalidContentLength(value.clone()))?;
+            info.content_length = Some(length);
+            content_length = Some(value.clone());
+        } else if name.eq_ignore_ascii_case(b"transfer-encoding") {
+            // Codings of later headers come after those of earlier ones.
+            let last = value.rsplit(|b| *b == b',').next().unwrap_or_default();
+            info.transfer_encoding = Some(last.trim_ascii().eq_ignore_ascii_case(b"chunked"));
+        } else if name.eq_ignore_ascii_case(b"server") {
+            info.server = true;
+        } else if name.eq_ignore_ascii_case(b"date") {
+            info.date = true;
+        }
+    }
+    match content_length {
+        // The client can't tell which one frames the body.
+        Some(value) if info.transfer_encoding.is_some() => {
+            Err(ResponseError::InvalidContentLength(value))
+        }
+        _ => Ok(info),
+    }
+}
+
+fn parse_content_length(value: &[u8]) -> Option<u64> {
+    let value = value.trim_ascii();
+    if value.is_empty() || !value.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+#[derive(Debug)]
+pub struct ResponseInfo {
+    pub keep_alive: KeepAlive,
+    pub payload: ResponsePayload,
+}
+
+/// Serializer of the response head.
+///
+/// Built for each request, as the status line echoes the request version and
+/// the `Connection` header depends on both the request and the response.
+#[derive(Debug)]
+pub struct ResponseHead {
+    version: HttpVersion,
+    // Request allows the connection to be kept alive.
+    keep_alive: bool,
+    // Response to HEAD never has a body.
+    head_request: bool,
+    // Add `Server` and `Date` unless the app sent them.
+    server_header: bool,
+    date_header: bool,
+}
+
+impl Default for ResponseHead {
+    fn default() -> Self {
+        Self::new(HttpVersion::Http11, true, false)
+    }
+}
 
-impl Handle<ResponseStart, ResponseData, State, (), ResponseError> for ResponseHead {
+impl ResponseHead {
+    pub fn new(version: HttpVersion, keep_alive: bool, head_request: bool) -> Self {
+        Self {
+            version,
+            keep_alive,
+            head_request,
+            server_header: false,
+            date_header: false,
+        }
+    }
+
+    /// Add the `Server` and `Date` headers of `ServerConfig`.
+    pub fn with_default_headers(mut self, server_header: bool, date_header: bool) -> Self {
+        self.server_header = server_header;
+        self.date_header = date_header;
+        self
+    }
+}
+
+impl Handle<ResponseStart, ResponseData, State, ResponseInfo, ResponseError> for ResponseHead {
+    /// Returns whether the connection is kept alive after this response, and
+    /// the handle of its body.
     fn step(
         &mut self,
         buffer: &mut BytesMut,
         state: State,
         inbound: ResponseStart,
-    ) -> Result<(ResponseData, State, ()), (ResponseError, State)> {
-        debug_assert!(matches!(state, State::RequestBodyFinished));
+    ) -> Result<(ResponseData, State, ResponseInfo), (ResponseError, State)> {
+        if let Err(e) = state.check(Event::ResponseStart) {
+            return Err((e.into(), state));
+        }
 
         let status_code = match StatusCode::from_u16(inbound.status) {
             Ok(code) => code,
             Err(e) => return Err((ResponseError::from(e), State::Closed)),
         };
-        buffer.put_slice(b"HTTP/1.1 ");
+        if let Err(e) = check_headers(&inbound.headers) {
+            return Err((e, State::Closed));
+        }
+
+        let info = match header_info(&inbound.headers) {
+            Ok(info) => info,
+            Err(e) => return Err((e, State::Closed)),
+        };
+        let bodyless = self.head_request
+            || status_code.is_informational()
+            || status_code == StatusCode::NO_CONTENT
+            || status_code == StatusCode::NOT_MODIFIED;
+        let framing = match (info.content_length, info.transfer_encoding) {
+            _ if bodyless => Framing::Bodyless,
+            (Some(length), _) => Framing::Length(length),
+            // HTTP/1.0 clients don't know chunked encoding.
+            (_, Some(true)) if self.version == HttpVersion::Http11 => Framing::Chunked,
+            _ => Framing::UntilClose,
+        };
+        let keep_alive = self.keep_alive && !info.close && framing != Framing::UntilClose;
+
+        buffer.put_slice(b"HTTP/");
+        buffer.put_slice(self.version.as_str().as_bytes());
+        buffer.put_slice(b" ");
         buffer.put_slice(status_code.as_str().as_bytes());
         buffer.put_slice(b" ");
-        buffer.put_slice(
-            status_code
-                .canonical_reason()
-                .map_or_else(|| "", |s| s)
-                .as_bytes(),
-        );
+        buffer.put_slice(status_code.canonical_reason().unwrap_or("").as_bytes());
         buffer.put_slice(b"\r\n");
 
+        if self.server_header && !info.server {
+            buffer.put_slice(b"Server: ruvicorn\r\n");
+        }
+        if self.date_header && !info.date {
+            buffer.put_slice(b"Date: ");
+            buffer.put_slice(http_date(SystemTime::now()).as_bytes());
+            buffer.put_slice(b"\r\n");
+        }
         for (name, value) in inbound.headers {
             buffer.put_slice(name.as_ref());
             buffer.put_slice(b": ");
             buffer.put_slice(value.as_ref());
             buffer.put_slice(b"\r\n");
         }
+        if !keep_alive && !info.close {
+            buffer.put_slice(b"Connection: close\r\n");
+        } else if keep_alive && self.version == HttpVersion::Http10 && !info.keep_alive {
+            buffer.put_slice(b"Connection: keep-alive\r\n");
+        }
         buffer.put_slice(b"\r\n");
 
         let res_bytes = buffer.clone().freeze();
         buffer.clear();
 
-        return Ok((
+        Ok((
             ResponseData { data: res_bytes },
             State::ResponseHeadFinished,
-            (),
-        ));
+            ResponseInfo {
+                keep_alive: if keep_alive {
+                    KeepAlive::KeepAlive
+                } else {
+                    KeepAlive::Close
+                },
+                payload: ResponsePayload { framing },
+            },
+        ))
+    }
+}
+
+// How the client finds the end of the response body.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+enum Framing {
+    // Response to HEAD, 1xx, 204 or 304. Body bytes from the app are dropped,
+    // the client doesn't read them.
+    Bodyless,
+    // Body bytes still expected by `Content-Length`.
+    Length(u64),
+    // `Transfer-Encoding: chunked`, the body is encoded here.
+    Chunked,
+    // Body ends when the connection is closed.
+    #[default]
+    UntilClose,
+}
+
+/// Serializer of the response body, framed as the response head says.
+#[derive(Debug, Default)]
+pub struct ResponsePayload {
+    framing: Framing,
+}
+
+impl ResponsePayload {
+    /// Whether the body sent so far is as long as `Content-Length`. A shorter
+    /// body leaves the client waiting, so the connection can't be reused.
+    pub fn is_complete(&self) -> bool {
+        !matches!(self.framing, Framing::Length(remaining) if remaining > 0)
+    }
+}
+
+impl Handle<ResponseBody, ResponseData, State, (), ResponseError> for ResponsePayload {
+    fn step(
+        &mut self,
+        buffer: &mut BytesMut,
+        state: State,
+        inbound: ResponseBody,
+    ) -> Result<(ResponseData, State, ()), (ResponseError, State)> {
+        if let Err(e) = state.check(Event::ResponseBody) {
+            return Err((e.into(), state));
+        }
+
+        // Last body finishes the request/response cycle.
+        let next_state = if inbound.more_body {
+            State::ResponseHeadFinished
+        } else {
+            State::Closed
+        };
+        let data = match &mut self.framing {
+            Framing::Bodyless => Bytes::new(),
+            Framing::Length(remaining) => {
+                let length = inbound.body.len() as u64;
+                if length > *remaining {
+                    return Err((ResponseError::BodyTooLong, State::Closed));
+                }
+                *remaining -= length;
+                inbound.body
+            }
+            Framing::Chunked => {
+                if !inbound.body.is_empty() {
+                    buffer.put_slice(format!("{:x}\r\n", inbound.body.len()).as_bytes());
+                    buffer.put_slice(&inbound.body);
+                    buffer.put_slice(b"\r\n");
+                }
+                if !inbound.more_body {
+                    buffer.put_slice(b"0\r\n\r\n");
+                }
+                buffer.split().freeze()
+            }
+            Framing::UntilClose => inbound.body,
+        };
+        Ok((ResponseData { data }, next_state, ()))
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use bytes::Bytes;
 
     macro_rules! header {
         ($name:expr, $value:expr) => {
@@ -76,7 +370,7 @@ mod test {
 
     #[test]
     fn test_response_head() {
-        let mut response = ResponseHead {};
+        let mut response = ResponseHead::default();
         let mut buffer = BytesMut::new();
         let state = State::RequestBodyFinished;
         let inbound = ResponseStart {
@@ -89,13 +383,25 @@ mod test {
         assert!(matches!(state, State::ResponseHeadFinished));
         assert_eq!(
             data.data,
-            Bytes::from_static(b"HTTP/1.1 200 OK\r\nName: Value\r\nName2: Value2\r\n\r\n")
+            Bytes::from_static(
+                b"HTTP/1.1 200 OK\r\nName: Value\r\nName2: Value2\r\nConnection: close\r\n\r\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_error_response() {
+        assert_eq!(
+            error_response(StatusCode::NOT_IMPLEMENTED),
+            Bytes::from_static(
+                b"HTTP/1.1 501 Not Implemented\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+            )
         );
     }
 
     #[test]
     fn test_response_head_invalid_status_code() {
-        let mut response = ResponseHead {};
+        let mut response = ResponseHead::default();
         let mut buffer = BytesMut::new();
         let state = State::RequestBodyFinished;
         let inbound = ResponseStart {
@@ -109,9 +415,328 @@ mod test {
             matches!(e.0, ResponseError::InvalidStatusCode(_)) && matches!(e.1, State::Closed)))
     }
 
+    #[test]
+    fn test_response_head_header_injection() {
+        let cases = [
+            header!(b"Name", b"Value\r\nSet-Cookie: injected"),
+            header!(b"Name", b"Value\nInjected: 1"),
+            header!(b"Name", b"Value\0"),
+            header!(b"Name", b"Value\x7f"),
+            header!(b"Name\r\nInjected", b"Value"),
+            header!(b"Na me", b"Value"),
+            header!(b"Name:", b"Value"),
+            header!(b"", b"Value"),
+        ];
+
+        for header in cases {
+            let mut response = ResponseHead::default();
+            let mut buffer = BytesMut::new();
+            let inbound = ResponseStart {
+                status: 200,
+                headers: vec![header!(b"Valid", b"Value"), header.clone()],
+            };
+
+            let result = response.step(&mut buffer, State::RequestBodyFinished, inbound);
+
+            assert!(
+                matches!(
+                    result,
+                    Err((
+                        ResponseError::InvalidHeaderName(_) | ResponseError::InvalidHeaderValue(_),
+                        State::Closed
+                    ))
+                ),
+                "{:?}",
+                header
+            );
+            assert!(buffer.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_response_head_accepts_tab_and_obs_text() {
+        let mut response = ResponseHead::default();
+        let mut buffer = BytesMut::new();
+        let inbound = ResponseStart {
+            status: 200,
+            headers: vec![header!(b"X-Custom_Name!", b"a\tb \xe2\x9c\x93")],
+        };
+
+        let (data, _, _) = response
+            .step(&mut buffer, State::RequestBodyFinished, inbound)
+            .unwrap();
+
+        assert!(data
+            .data
+            .windows(25)
+            .any(|w| w == b"X-Custom_Name!: a\tb \xe2\x9c\x93\r\n"));
+    }
+
+    fn connection_of(
+        mut response: ResponseHead,
+        status: u16,
+        headers: Vec<(Bytes, Bytes)>,
+    ) -> (Bytes, KeepAlive) {
+        let mut buffer = BytesMut::new();
+        let inbound = ResponseStart { status, headers };
+        let (data, _, info) = response
+            .step(&mut buffer, State::RequestBodyFinished, inbound)
+            .unwrap();
+        (data.data, info.keep_alive)
+    }
+
+    #[test]
+    fn test_response_head_keep_alive() {
+        let (data, keep_alive) = connection_of(
+            ResponseHead::default(),
+            200,
+            vec![header!(b"Content-Length", b"0")],
+        );
+
+        assert_eq!(
+            data,
+            Bytes::from_static(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+        );
+        assert_eq!(keep_alive, KeepAlive::KeepAlive);
+    }
+
+    #[test]
+    fn test_response_head_http10() {
+        let (data, keep_alive) = connection_of(
+            ResponseHead::new(HttpVersion::Http10, true, false),
+            200,
+            vec![header!(b"Content-Length", b"0")],
+        );
+        assert_eq!(
+            data,
+            Bytes::from_static(
+                b"HTTP/1.0 200 OK\r\nContent-Length: 0\r\nConnection: keep-alive\r\n\r\n"
+            )
+        );
+        assert_eq!(keep_alive, KeepAlive::KeepAlive);
+
+        let (data, keep_alive) = connection_of(
+            ResponseHead::new(HttpVersion::Http10, false, false),
+            200,
+            vec![header!(b"Content-Length", b"0")],
+        );
+        assert_eq!(
+            data,
+            Bytes::from_static(
+                b"HTTP/1.0 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            )
+        );
+        assert_eq!(keep_alive, KeepAlive::Close);
+    }
+
+    #[test]
+    fn test_response_head_app_closes_connection() {
+        let (data, keep_alive) = connection_of(
+            ResponseHead::default(),
+            200,
+            vec![
+                header!(b"Content-Length", b"0"),
+                header!(b"Connection", b"Close"),
+            ],
+        );
+
+        assert_eq!(
+            data,
+            Bytes::from_static(
+                b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: Close\r\n\r\n"
+            )
+        );
+        assert_eq!(keep_alive, KeepAlive::Close);
+    }
+
+    #[test]
+    fn test_response_head_without_body_keeps_alive() {
+        let (_, keep_alive) = connection_of(ResponseHead::default(), 204, vec![]);
+        assert_eq!(keep_alive, KeepAlive::KeepAlive);
+
+        let (_, keep_alive) = connection_of(ResponseHead::default(), 304, vec![]);
+        assert_eq!(keep_alive, KeepAlive::KeepAlive);
+
+        let (_, keep_alive) = connection_of(
+            ResponseHead::new(HttpVersion::Http11, true, true),
+            200,
+            vec![],
+        );
+        assert_eq!(keep_alive, KeepAlive::KeepAlive);
+    }
+
+    #[test]
+    fn test_response_head_default_headers() {
+        let response = ResponseHead::default().with_default_headers(true, true);
+        let (data, _) = connection_of(response, 200, vec![header!(b"Content-Length", b"0")]);
+
+        let data = std::str::from_utf8(&data).unwrap();
+        assert!(data.starts_with("HTTP/1.1 200 OK\r\nServer: ruvicorn\r\nDate: "));
+        assert!(data.ends_with(" GMT\r\nContent-Length: 0\r\n\r\n"));
+
+        // Headers of the app are kept instead.
+        let response = ResponseHead::default().with_default_headers(true, true);
+        let (data, _) = connection_of(
+            response,
+            200,
+            vec![
+                header!(b"server", b"app"),
+                header!(b"date", b"Sun, 06 Nov 1994 08:49:37 GMT"),
+            ],
+        );
+        assert_eq!(
+            data,
+            Bytes::from_static(
+                b"HTTP/1.1 200 OK\r\nserver: app\r\ndate: Sun, 06 Nov 1994 08:49:37 GMT\r\nConnection: close\r\n\r\n"
+            )
+        );
+    }
+
+    fn body_of(
+        mut response: ResponseHead,
+        headers: Vec<(Bytes, Bytes)>,
+        bodies: &[&'static [u8]],
+    ) -> (KeepAlive, Vec<Result<Bytes, ResponseError>>, bool) {
+        let mut buffer = BytesMut::new();
+        let inbound = ResponseStart {
+            status: 200,
+            headers,
+        };
+        let (_, state, info) = response
+            .step(&mut buffer, State::RequestBodyFinished, inbound)
+            .unwrap();
+        let mut payload = info.payload;
+        let outputs = bodies
+            .iter()
+            .enumerate()
+            .map(|(i, body)| {
+                let inbound = ResponseBody {
+                    body: Bytes::from_static(body),
+                    more_body: i + 1 < bodies.len(),
+                };
+                payload
+                    .step(&mut buffer, state, inbound)
+                    .map(|(data, _, _)| data.data)
+                    .map_err(|(e, _)| e)
+            })
+            .collect();
+        (info.keep_alive, outputs, payload.is_complete())
+    }
+
+    #[test]
+    fn test_response_body_chunked() {
+        let (keep_alive, outputs, _) = body_of(
+            ResponseHead::default(),
+            vec![header!(b"Transfer-Encoding", b"gzip, chunked")],
+            &[b"data", b"", b"0123456789abcdef!", b""],
+        );
+
+        assert_eq!(keep_alive, KeepAlive::KeepAlive);
+        let outputs: Vec<Bytes> = outputs.into_iter().map(Result::unwrap).collect();
+        assert_eq!(
+            outputs,
+            [
+                &b"4\r\ndata\r\n"[..],
+                b"",
+                b"11\r\n0123456789abcdef!\r\n",
+                b"0\r\n\r\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_response_body_unframed_transfer_encoding() {
+        // HTTP/1.0 clients can't read chunks, and without `chunked` last the
+        // body can only end with the connection.
+        let cases = [
+            (
+                HttpVersion::Http10,
+                header!(b"Transfer-Encoding", b"chunked"),
+            ),
+            (HttpVersion::Http11, header!(b"Transfer-Encoding", b"gzip")),
+        ];
+
+        for (version, header) in cases {
+            let (keep_alive, outputs, _) = body_of(
+                ResponseHead::new(version, true, false),
+                vec![header],
+                &[b"data"],
+            );
+
+            assert_eq!(keep_alive, KeepAlive::Close);
+            assert_eq!(outputs[0].as_ref().unwrap(), &b"data"[..]);
+        }
+    }
+
+    #[test]
+    fn test_response_body_content_length() {
+        let headers = || vec![header!(b"Content-Length", b"4")];
+
+        let (_, outputs, complete) = body_of(ResponseHead::default(), headers(), &[b"da", b"ta"]);
+        assert!(outputs.iter().all(Result::is_ok));
+        assert!(complete);
+
+        let (_, outputs, complete) = body_of(ResponseHead::default(), headers(), &[b"dat"]);
+        assert!(outputs[0].is_ok());
+        assert!(!complete);
+
+        let (_, outputs, _) = body_of(ResponseHead::default(), headers(), &[b"data", b"!"]);
+        assert!(outputs[0].is_ok());
+        assert!(matches!(outputs[1], Err(ResponseError::BodyTooLong)));
+    }
+
+    #[test]
+    fn test_response_head_invalid_content_length() {
+        let cases = [
+            vec![header!(b"Content-Length", b"four")],
+            vec![header!(b"Content-Length", b"-1")],
+            vec![header!(b"Content-Length", b"1, 1")],
+            vec![header!(b"Content-Length", b"99999999999999999999")],
+            vec![
+                header!(b"Content-Length", b"1"),
+                header!(b"Content-Length", b"2"),
+            ],
+            vec![
+                header!(b"Content-Length", b"4"),
+                header!(b"Transfer-Encoding", b"chunked"),
+            ],
+        ];
+
+        for headers in cases {
+            let mut buffer = BytesMut::new();
+            let inbound = ResponseStart {
+                status: 200,
+                headers: headers.clone(),
+            };
+
+            let result =
+                ResponseHead::default().step(&mut buffer, State::RequestBodyFinished, inbound);
+
+            assert!(
+                matches!(
+                    result,
+                    Err((ResponseError::InvalidContentLength(_), State::Closed))
+                ),
+                "{:?}",
+                headers
+            );
+        }
+
+        // Repeating the same length is allowed.
+        let (_, keep_alive) = connection_of(
+            ResponseHead::default(),
+            200,
+            vec![
+                header!(b"Content-Length", b"0"),
+                header!(b"content-length", b" 0 "),
+            ],
+        );
+        assert_eq!(keep_alive, KeepAlive::KeepAlive);
+    }
+
     #[test]
     fn test_response_head_with_no_canonical_reason() {
-        let mut response = ResponseHead {};
+        let mut response = ResponseHead::default();
         let mut buffer = BytesMut::new();
         let state = State::RequestBodyFinished;
         let inbound = ResponseStart {
@@ -125,7 +750,51 @@ mod test {
         assert!(matches!(state, State::ResponseHeadFinished));
         assert_eq!(
             data.data,
-            Bytes::from_static(b"HTTP/1.1 999 \r\nName: Value\r\nName2: Value2\r\n\r\n")
+            Bytes::from_static(
+                b"HTTP/1.1 999 \r\nName: Value\r\nName2: Value2\r\nConnection: close\r\n\r\n"
+            )
         );
     }
+
+    #[test]
+    fn test_response_body_before_head() {
+        let mut response = ResponsePayload::default();
+        let mut buffer = BytesMut::new();
+        let inbound = ResponseBody {
+            body: Bytes::from_static(b"data"),
+            more_body: false,
+        };
+
+        let result = response.step(&mut buffer, State::RequestBodyFinished, inbound);
+
+        assert!(matches!(
+            result,
+            Err((ResponseError::InvalidState(_), State::RequestBodyFinished))
+        ));
+    }
+
+    #[test]
+    fn test_bodyless_response_drops_body() {
+        let mut buffer = BytesMut::new();
+        let (_, state, info) = ResponseHead::default()
+            .step(
+                &mut buffer,
+                State::RequestBodyFinished,
+                ResponseStart {
+                    status: 204,
+                    headers: vec![],
+                },
+            )
+            .unwrap();
+        let mut payload = info.payload;
+
+        let inbound = ResponseBody {
+            body: Bytes::from_static(b"data"),
+            more_body: false,
+        };
+        let (data, state, _) = payload.step(&mut buffer, state, inbound).unwrap();
+
+        assert!(data.data.is_empty());
+        assert_eq!(state, State::Closed);
+    }
 }
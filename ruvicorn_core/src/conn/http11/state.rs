@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum State {
     // Ready to get request.
     Idle,
@@ -11,3 +11,98 @@ pub enum State {
     // Connection closed by error or finished all request/response cycle.
     Closed,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    // Request data while waiting for a request head.
+    RequestHead,
+    // Request data while reading the request body.
+    RequestBody,
+    ResponseStart,
+    ResponseBody,
+    Disconnect,
+    Tick,
+}
+
+/// Event is not allowed in the current state.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidState {
+    pub state: State,
+    pub event: Event,
+}
+
+impl State {
+    /// Check `event` against the transition table.
+    ///
+    /// | State                  | Allowed events                 |
+    /// |------------------------|--------------------------------|
+    /// | `Idle`                 | `RequestHead`                  |
+    /// | `RequestHeadFinished`  | `RequestBody`                  |
+    /// | `RequestBodyFinished`  | `ResponseStart`                |
+    /// | `ResponseHeadFinished` | `ResponseBody`                 |
+    /// | `Closed`               | none                           |
+    ///
+    /// `Disconnect` and `Tick` are allowed in every state.
+    pub fn check(self, event: Event) -> Result<(), InvalidState> {
+        let allowed = matches!(
+            (self, event),
+            (_, Event::Disconnect | Event::Tick)
+                | (State::Idle, Event::RequestHead)
+                | (State::RequestHeadFinished, Event::RequestBody)
+                | (State::RequestBodyFinished, Event::ResponseStart)
+                | (State::ResponseHeadFinished, Event::ResponseBody)
+        );
+        if allowed {
+            return Ok(());
+        }
+        Err(InvalidState { state: self, event })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const STATES: [State; 5] = [
+        State::Idle,
+        State::RequestHeadFinished,
+        State::RequestBodyFinished,
+        State::ResponseHeadFinished,
+        State::Closed,
+    ];
+
+    #[test]
+    fn test_transition_table() {
+        let allowed = [
+            (State::Idle, Event::RequestHead),
+            (State::RequestHeadFinished, Event::RequestBody),
+            (State::RequestBodyFinished, Event::ResponseStart),
+            (State::ResponseHeadFinished, Event::ResponseBody),
+        ];
+        let events = [
+            Event::RequestHead,
+            Event::RequestBody,
+            Event::ResponseStart,
+            Event::ResponseBody,
+        ];
+
+        for state in STATES {
+            for event in events {
+                let expected = if allowed.contains(&(state, event)) {
+                    Ok(())
+                } else {
+                    Err(InvalidState { state, event })
+                };
+                assert_eq!(state.check(event), expected, "{:?} {:?}", state, event);
+            }
+        }
+    }
+
+    #[test]
+    fn test_disconnect_and_tick_always_allowed() {
+        for state in STATES {
+            assert_eq!(state.check(Event::Disconnect), Ok(()));
+            assert_eq!(state.check(Event::Tick), Ok(()));
+        }
+    }
+}
@@ -1 +1,63 @@
-mod http11;
+pub mod http11;
+pub mod proxy_protocol;
+pub mod tls;
+pub mod tls_reload;
+
+use std::sync::Arc;
+
+use crate::config::ServerConfig;
+use crate::conn::http11::bound::HttpVersion;
+use crate::conn::http11::conn::Http11Connection;
+
+/// Application protocol spoken on a connection.
+///
+/// Negotiated with ALPN on TLS connections. Plain connections, and TLS
+/// clients that don't use ALPN, speak HTTP/1.1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Http11,
+}
+
+impl Protocol {
+    /// Supported protocols, most preferred first.
+    pub const ALL: [Protocol; 1] = [Protocol::Http11];
+
+    /// Protocol ID of the ALPN registry.
+    pub fn alpn_id(self) -> &'static [u8] {
+        match self {
+            Self::Http11 => b"http/1.1",
+        }
+    }
+
+    pub fn from_alpn_id(id: &[u8]) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.alpn_id() == id)
+    }
+
+    // `http_version` of the ASGI scope. HTTP/1.x requests carry their own
+    // version, which ALPN doesn't tell apart.
+    pub fn http_version(self, request_version: HttpVersion) -> &'static str {
+        match self {
+            Self::Http11 => request_version.as_str(),
+        }
+    }
+}
+
+/// State machine of a connection, picked from its protocol.
+#[derive(Debug)]
+pub enum Connection {
+    Http11(Http11Connection),
+}
+
+impl Connection {
+    pub fn new(protocol: Protocol, config: Arc<ServerConfig>) -> Self {
+        match protocol {
+            Protocol::Http11 => Self::Http11(Http11Connection::new(config)),
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        match self {
+            Self::Http11(_) => Protocol::Http11,
+        }
+    }
+}
@@ -0,0 +1,420 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use bytes::{Buf, Bytes, BytesMut};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// Longest v1 line, `PROXY TCP6` with the longest addresses and ports.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_FIXED_LEN: usize = 16;
+
+/// TLV types of the v2 header.
+pub mod tlv {
+    pub const ALPN: u8 = 0x01;
+    pub const AUTHORITY: u8 = 0x02;
+    pub const CRC32C: u8 = 0x03;
+    pub const NOOP: u8 = 0x04;
+    pub const UNIQUE_ID: u8 = 0x05;
+    pub const SSL: u8 = 0x20;
+    pub const NETNS: u8 = 0x30;
+    // VPC endpoint ID sent by AWS NLB.
+    pub const AWS: u8 = 0xea;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProxyProtocolMode {
+    // Connections start with HTTP.
+    Off,
+    // PROXY header is read if the connection starts with one.
+    Optional,
+    // Connections without a PROXY header are closed.
+    Required,
+}
+
+impl ProxyProtocolMode {
+    pub const NAMES: [&'static str; 3] = ["off", "optional", "required"];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "off" => Some(Self::Off),
+            "optional" => Some(Self::Optional),
+            "required" => Some(Self::Required),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Optional => "optional",
+            Self::Required => "required",
+        }
+    }
+}
+
+/// Addresses and TLVs sent by the proxy.
+#[derive(Debug, Default, PartialEq)]
+pub struct ProxyHeader {
+    // `None` for `LOCAL` and `UNKNOWN` connections, and for address families
+    // other than TCP/UDP over IPv4/IPv6. The real addresses are used then.
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+    // `(type, value)` in the order sent. Empty for v1.
+    pub tlvs: Vec<(u8, Bytes)>,
+}
+
+impl ProxyHeader {
+    /// Value of the first TLV of `kind`.
+    pub fn tlv(&self, kind: u8) -> Option<&Bytes> {
+        self.tlvs.iter().find(|(k, _)| *k == kind).map(|(_, v)| v)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProxyProtocolError {
+    // Connection does not start with a PROXY header in required mode.
+    Missing,
+    Invalid(&'static str),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Decoded {
+    // Header is removed from the buffer.
+    Header(ProxyHeader),
+    // Connection starts with something else, left in the buffer.
+    NoHeader,
+    // Need more data.
+    Partial,
+}
+
+// `buffer` can still become `signature`.
+fn may_start_with(buffer: &[u8], signature: &[u8]) -> bool {
+    let len = buffer.len().min(signature.len());
+    buffer[..len] == signature[..len]
+}
+
+/// Read the PROXY header at the start of `buffer`.
+pub fn decode(
+    buffer: &mut BytesMut,
+    mode: ProxyProtocolMode,
+) -> Result<Decoded, ProxyProtocolError> {
+    if mode == ProxyProtocolMode::Off {
+        return Ok(Decoded::NoHeader);
+    }
+    if buffer.starts_with(V2_SIGNATURE) {
+        return decode_v2(buffer);
+    }
+    if buffer.starts_with(V1_PREFIX) {
+        return decode_v1(buffer);
+    }
+    if may_start_with(buffer, V2_SIGNATURE) || may_start_with(buffer, V1_PREFIX) {
+        return Ok(Decoded::Partial);
+    }
+    if mode == ProxyProtocolMode::Required {
+        return Err(ProxyProtocolError::Missing);
+    }
+    Ok(Decoded::NoHeader)
+}
+
+// `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n`
+fn decode_v1(buffer: &mut BytesMut) -> Result<Decoded, ProxyProtocolError> {
+    let invalid = ProxyProtocolError::Invalid;
+    let end = match buffer.windows(2).position(|w| w == b"\r\n") {
+        Some(end) if end + 2 <= V1_MAX_LEN => end,
+        Some(_) => return Err(invalid("PROXY v1 line is too long")),
+        None if buffer.len() < V1_MAX_LEN => return Ok(Decoded::Partial),
+        None => return Err(invalid("PROXY v1 line is too long")),
+    };
+    let line = buffer.split_to(end + 2);
+    let line = std::str::from_utf8(&line[V1_PREFIX.len()..end])
+        .map_err(|_| invalid("PROXY v1 line is not ASCII"))?;
+
+    let parts: Vec<&str> = line.split(' ').collect();
+    let is_v4 = match parts[0] {
+        // Rest of the line is ignored.
+        "UNKNOWN" => return Ok(Decoded::Header(ProxyHeader::default())),
+        "TCP4" => true,
+        "TCP6" => false,
+        _ => return Err(invalid("PROXY v1 protocol is not TCP4, TCP6 or UNKNOWN")),
+    };
+    let [_, source, destination, source_port, destination_port] = parts[..] else {
+        return Err(invalid("PROXY v1 line has wrong number of fields"));
+    };
+    let address = |addr: &str| -> Result<IpAddr, ProxyProtocolError> {
+        let addr = if is_v4 {
+            addr.parse::<Ipv4Addr>().map(IpAddr::V4)
+        } else {
+            addr.parse::<Ipv6Addr>().map(IpAddr::V6)
+        };
+        addr.map_err(|_| invalid("PROXY v1 address is not valid"))
+    };
+    // Ports are 0-65535 without leading zeros.
+    let port = |port: &str| -> Result<u16, ProxyProtocolError> {
+        match port.parse::<u16>() {
+            Ok(p) if port.bytes().all(|b| b.is_ascii_digit()) && p.to_string() == port => Ok(p),
+            _ => Err(invalid("PROXY v1 port is not valid")),
+        }
+    };
+    Ok(Decoded::Header(ProxyHeader {
+        source: Some(SocketAddr::new(address(source)?, port(source_port)?)),
+        destination: Some(SocketAddr::new(
+            address(destination)?,
+            port(destination_port)?,
+        )),
+        tlvs: Vec::new(),
+    }))
+}
+
+fn decode_v2(buffer: &mut BytesMut) -> Result<Decoded, ProxyProtocolError> {
+    let invalid = ProxyProtocolError::Invalid;
+    if buffer.len() < V2_FIXED_LEN {
+        return Ok(Decoded::Partial);
+    }
+    let version_command = buffer[12];
+    let family = buffer[13];
+    let len = u16::from_be_bytes([buffer[14], buffer[15]]) as usize;
+    if version_command >> 4 != 2 {
+        return Err(invalid("PROXY v2 version is not 2"));
+    }
+    let local = match version_command & 0x0f {
+        0x0 => true,
+        0x1 => false,
+        _ => return Err(invalid("PROXY v2 command is not LOCAL or PROXY")),
+    };
+    if buffer.len() < V2_FIXED_LEN + len {
+        return Ok(Decoded::Partial);
+    }
+
+    buffer.advance(V2_FIXED_LEN);
+    let mut payload = buffer.split_to(len).freeze();
+    let addresses_len = match family >> 4 {
+        0x1 => 12,
+        0x2 => 36,
+        0x3 => 216,
+        _ => 0,
+    };
+    if payload.len() < addresses_len {
+        return Err(invalid("PROXY v2 addresses are truncated"));
+    }
+    let mut addresses = payload.split_to(addresses_len);
+
+    let mut header = ProxyHeader::default();
+    // TCP and UDP over IPv4 and IPv6. Unix sockets have no `SocketAddr`.
+    let inet = !local && matches!(family & 0x0f, 0x1 | 0x2);
+    match family >> 4 {
+        0x1 if inet => {
+            let source = Ipv4Addr::from(addresses.get_u32());
+            let destination = Ipv4Addr::from(addresses.get_u32());
+            header.source = Some(SocketAddr::new(source.into(), addresses.get_u16()));
+            header.destination = Some(SocketAddr::new(destination.into(), addresses.get_u16()));
+        }
+        0x2 if inet => {
+            let source = Ipv6Addr::from(addresses.get_u128());
+            let destination = Ipv6Addr::from(addresses.get_u128());
+            header.source = Some(SocketAddr::new(source.into(), addresses.get_u16()));
+            header.destination = Some(SocketAddr::new(destination.into(), addresses.get_u16()));
+        }
+        _ => {}
+    }
+
+    while payload.has_remaining() {
+        if payload.len() < 3 {
+            return Err(invalid("PROXY v2 TLV is truncated"));
+        }
+        let kind = payload.get_u8();
+        let len = payload.get_u16() as usize;
+        if payload.len() < len {
+            return Err(invalid("PROXY v2 TLV is truncated"));
+        }
+        let value = payload.split_to(len);
+        if kind != tlv::NOOP {
+            header.tlvs.push((kind, value));
+        }
+    }
+    Ok(Decoded::Header(header))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode_all(
+        data: &[u8],
+        mode: ProxyProtocolMode,
+    ) -> (Result<Decoded, ProxyProtocolError>, BytesMut) {
+        let mut buffer = BytesMut::from(data);
+        let result = decode(&mut buffer, mode);
+        (result, buffer)
+    }
+
+    fn v2(version_command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[version_command, family]);
+        data.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn header(source: &str, destination: &str) -> Decoded {
+        Decoded::Header(ProxyHeader {
+            source: Some(source.parse().unwrap()),
+            destination: Some(destination.parse().unwrap()),
+            tlvs: Vec::new(),
+        })
+    }
+
+    #[test]
+    fn test_v1() {
+        let (result, buffer) = decode_all(
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET / HTTP/1.1\r\n",
+            ProxyProtocolMode::Required,
+        );
+        assert_eq!(result, Ok(header("192.0.2.1:56324", "192.0.2.2:443")));
+        assert_eq!(&buffer[..], b"GET / HTTP/1.1\r\n");
+
+        let (result, _) = decode_all(
+            b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n",
+            ProxyProtocolMode::Required,
+        );
+        assert_eq!(
+            result,
+            Ok(header("[2001:db8::1]:56324", "[2001:db8::2]:443"))
+        );
+
+        let (result, buffer) = decode_all(
+            b"PROXY UNKNOWN ffff::1 x\r\nGET",
+            ProxyProtocolMode::Required,
+        );
+        assert_eq!(result, Ok(Decoded::Header(ProxyHeader::default())));
+        assert_eq!(&buffer[..], b"GET");
+    }
+
+    #[test]
+    fn test_v1_invalid() {
+        let cases: [&[u8]; 7] = [
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 56324\r\n",
+            b"PROXY TCP4 2001:db8::1 192.0.2.2 56324 443\r\n",
+            b"PROXY TCP6 192.0.2.1 192.0.2.2 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 056324 443\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 65536 443\r\n",
+            b"PROXY TCP4  192.0.2.1 192.0.2.2 1 443\r\n",
+            b"PROXY UDP4 192.0.2.1 192.0.2.2 1 443\r\n",
+        ];
+        for case in cases {
+            let (result, _) = decode_all(case, ProxyProtocolMode::Required);
+            assert!(
+                matches!(result, Err(ProxyProtocolError::Invalid(_))),
+                "{}",
+                String::from_utf8_lossy(case)
+            );
+        }
+
+        let (result, _) = decode_all(&[b'P'; 200], ProxyProtocolMode::Optional);
+        assert_eq!(result, Ok(Decoded::NoHeader));
+        let mut long = b"PROXY ".to_vec();
+        long.extend_from_slice(&[b'a'; 200]);
+        let (result, _) = decode_all(&long, ProxyProtocolMode::Optional);
+        assert!(matches!(result, Err(ProxyProtocolError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_v2() {
+        let mut payload = vec![192, 0, 2, 1, 192, 0, 2, 2, 0xdc, 0x04, 0x01, 0xbb];
+        payload.extend_from_slice(&[tlv::AUTHORITY, 0, 11]);
+        payload.extend_from_slice(b"example.com");
+        payload.extend_from_slice(&[tlv::NOOP, 0, 2, 0, 0]);
+        payload.extend_from_slice(&[tlv::AWS, 0, 3, 1, b'v', b'p']);
+        let mut data = v2(0x21, 0x11, &payload);
+        data.extend_from_slice(b"GET");
+
+        let (result, buffer) = decode_all(&data, ProxyProtocolMode::Required);
+
+        let Ok(Decoded::Header(header)) = result else {
+            panic!("{:?}", result);
+        };
+        assert_eq!(header.source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(header.destination, Some("192.0.2.2:443".parse().unwrap()));
+        assert_eq!(header.tlv(tlv::AUTHORITY).unwrap(), "example.com");
+        assert_eq!(header.tlv(tlv::AWS).unwrap(), &b"\x01vp"[..]);
+        assert_eq!(header.tlvs.len(), 2);
+        assert_eq!(&buffer[..], b"GET");
+    }
+
+    #[test]
+    fn test_v2_ipv6_and_local() {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        payload.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        payload.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+
+        let (result, _) = decode_all(&v2(0x21, 0x21, &payload), ProxyProtocolMode::Required);
+        assert_eq!(
+            result,
+            Ok(header("[2001:db8::1]:56324", "[2001:db8::2]:443"))
+        );
+
+        // Health checks of the proxy itself.
+        let (result, _) = decode_all(&v2(0x20, 0x00, &[]), ProxyProtocolMode::Required);
+        assert_eq!(result, Ok(Decoded::Header(ProxyHeader::default())));
+
+        let (result, _) = decode_all(&v2(0x20, 0x21, &payload), ProxyProtocolMode::Required);
+        assert_eq!(result, Ok(Decoded::Header(ProxyHeader::default())));
+    }
+
+    #[test]
+    fn test_v2_invalid() {
+        let cases = [
+            v2(0x11, 0x11, &[0; 12]),
+            v2(0x22, 0x11, &[0; 12]),
+            v2(0x21, 0x11, &[0; 8]),
+            v2(
+                0x21,
+                0x11,
+                &[[0; 12].as_slice(), &[tlv::AUTHORITY, 0, 5, b'a']].concat(),
+            ),
+            v2(
+                0x21,
+                0x11,
+                &[[0; 12].as_slice(), &[tlv::AUTHORITY, 0]].concat(),
+            ),
+        ];
+        for case in cases {
+            let (result, _) = decode_all(&case, ProxyProtocolMode::Required);
+            assert!(
+                matches!(result, Err(ProxyProtocolError::Invalid(_))),
+                "{:?}",
+                case
+            );
+        }
+    }
+
+    #[test]
+    fn test_partial() {
+        let data = v2(0x21, 0x11, &[0; 12]);
+        for len in 0..data.len() {
+            let (result, _) = decode_all(&data[..len], ProxyProtocolMode::Required);
+            assert_eq!(result, Ok(Decoded::Partial), "{}", len);
+        }
+
+        let data = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n";
+        for len in 0..data.len() {
+            let (result, _) = decode_all(&data[..len], ProxyProtocolMode::Required);
+            assert_eq!(result, Ok(Decoded::Partial), "{}", len);
+        }
+    }
+
+    #[test]
+    fn test_no_header() {
+        let data = b"GET / HTTP/1.1\r\n\r\n";
+
+        let (result, buffer) = decode_all(data, ProxyProtocolMode::Optional);
+        assert_eq!(result, Ok(Decoded::NoHeader));
+        assert_eq!(&buffer[..], data);
+
+        let (result, _) = decode_all(data, ProxyProtocolMode::Required);
+        assert_eq!(result, Err(ProxyProtocolError::Missing));
+
+        let (result, _) = decode_all(b"PROXY TCP4", ProxyProtocolMode::Off);
+        assert_eq!(result, Ok(Decoded::NoHeader));
+    }
+}
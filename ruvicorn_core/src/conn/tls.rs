@@ -0,0 +1,916 @@
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use pyo3::exceptions::PyValueError;
+use pyo3::PyErr;
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::crypto::ring;
+use rustls::crypto::CryptoProvider;
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::{VerifierBuilderError, WebPkiClientVerifier};
+use rustls::{
+    DigitallySignedStruct, DistinguishedName, RootCertStore, ServerConnection, SignatureScheme,
+    SupportedCipherSuite, SupportedProtocolVersion,
+};
+use rustls_pki_types::pem::PemObject;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, UnixTime};
+use x509_parser::oid_registry::{self, Oid};
+
+use crate::config::ServerConfig;
+use crate::conn::Protocol;
+
+pub const MIN_VERSIONS: [&str; 2] = ["1.2", "1.3"];
+
+static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&rustls::version::TLS13];
+
+/// Whether TLS clients are asked for a certificate, checked against
+/// `ssl_ca_certs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientCertMode {
+    None,
+    // Clients without a certificate, or with one that doesn't verify, are
+    // let through. The scope tells the app which case it is.
+    Optional,
+    Required,
+}
+
+impl ClientCertMode {
+    pub const NAMES: [&'static str; 3] = ["none", "optional", "required"];
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "none" => Some(Self::None),
+            "optional" => Some(Self::Optional),
+            "required" => Some(Self::Required),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Optional => "optional",
+            Self::Required => "required",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    // File can't be read or has no PEM section of the expected kind.
+    Pem(String, rustls_pki_types::pem::Error),
+    UnknownCipher(String),
+    // `ssl_min_version` is not in `MIN_VERSIONS`.
+    InvalidVersion(String),
+    // Rejected by rustls, while building the config or on the connection.
+    Rustls(rustls::Error),
+    // `ssl_ca_certs` can't be used to verify client certificates.
+    ClientVerifier(VerifierBuilderError),
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Pem(path, e) => write!(f, "can't load {:?}: {}", path, e),
+            Self::UnknownCipher(name) => write!(f, "unknown cipher suite {:?}", name),
+            Self::InvalidVersion(version) => write!(
+                f,
+                "ssl_min_version must be one of {}, got {:?}",
+                MIN_VERSIONS.join(", "),
+                version
+            ),
+            Self::Rustls(e) => write!(f, "{}", e),
+            Self::ClientVerifier(e) => write!(f, "invalid ssl_ca_certs: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<TlsError> for PyErr {
+    fn from(error: TlsError) -> Self {
+        PyValueError::new_err(error.to_string())
+    }
+}
+
+impl From<rustls::Error> for TlsError {
+    fn from(error: rustls::Error) -> Self {
+        Self::Rustls(error)
+    }
+}
+
+fn cipher_suite(name: &str) -> Result<SupportedCipherSuite, TlsError> {
+    ring::ALL_CIPHER_SUITES
+        .iter()
+        .find(|s| {
+            s.suite()
+                .as_str()
+                .is_some_and(|n| n.eq_ignore_ascii_case(name))
+        })
+        .copied()
+        .ok_or_else(|| TlsError::UnknownCipher(name.to_owned()))
+}
+
+fn protocol_versions(
+    min_version: &str,
+) -> Result<&'static [&'static SupportedProtocolVersion], TlsError> {
+    match min_version {
+        "1.2" => Ok(rustls::ALL_VERSIONS),
+        "1.3" => Ok(TLS13_ONLY),
+        _ => Err(TlsError::InvalidVersion(min_version.to_owned())),
+    }
+}
+
+/// Check the TLS settings that don't need the certificate files.
+pub fn validate(config: &ServerConfig) -> Result<(), TlsError> {
+    protocol_versions(&config.ssl_min_version)?;
+    for name in &config.ssl_ciphers {
+        cipher_suite(name)?;
+    }
+    Ok(())
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let pem_error = |e| TlsError::Pem(path.to_owned(), e);
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(pem_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(pem_error)?;
+    if certs.is_empty() {
+        return Err(pem_error(rustls_pki_types::pem::Error::NoItemsFound));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, TlsError> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| TlsError::Pem(path.to_owned(), e))
+}
+
+fn pem_encode(cert: &CertificateDer) -> String {
+    let pem = pem::Pem::new("CERTIFICATE", cert.as_ref());
+    pem::encode_config(
+        &pem,
+        pem::EncodeConfig::new().set_line_ending(pem::LineEnding::LF),
+    )
+}
+
+fn attribute_name(oid: &Oid) -> Option<&'static str> {
+    let names = [
+        (oid_registry::OID_X509_COMMON_NAME, "CN"),
+        (oid_registry::OID_X509_LOCALITY_NAME, "L"),
+        (oid_registry::OID_X509_STATE_OR_PROVINCE_NAME, "ST"),
+        (oid_registry::OID_X509_ORGANIZATION_NAME, "O"),
+        (oid_registry::OID_X509_ORGANIZATIONAL_UNIT, "OU"),
+        (oid_registry::OID_X509_COUNTRY_NAME, "C"),
+        (oid_registry::OID_X509_STREET_ADDRESS, "STREET"),
+        (oid_registry::OID_DOMAIN_COMPONENT, "DC"),
+        (oid_registry::OID_USERID, "UID"),
+    ];
+    names.iter().find(|(o, _)| o == oid).map(|(_, name)| *name)
+}
+
+fn escape_dn_value(value: &str, out: &mut String) {
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        let edge = (i == 0 && matches!(c, '#' | ' ')) || (i == last && c == ' ');
+        if edge || matches!(c, '"' | '+' | ',' | ';' | '<' | '>' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+/// RFC 4514 string of the certificate subject, like `CN=client,O=Example`.
+///
+/// RDNs are written last first as the RFC asks, which is the reverse of the
+/// order in the certificate. Non-string values are written as `#` and hex.
+fn subject_name(cert: &CertificateDer) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref()).ok()?;
+    let mut name = String::new();
+    let rdns: Vec<_> = cert.subject().iter_rdn().collect();
+    for (i, rdn) in rdns.iter().rev().enumerate() {
+        if i > 0 {
+            name.push(',');
+        }
+        for (j, attr) in rdn.iter().enumerate() {
+            if j > 0 {
+                name.push('+');
+            }
+            match attribute_name(attr.attr_type()) {
+                Some(short) => name.push_str(short),
+                None => name.push_str(&attr.attr_type().to_id_string()),
+            }
+            name.push('=');
+            match attr.as_str() {
+                Ok(value) => escape_dn_value(value, &mut name),
+                Err(_) => {
+                    name.push('#');
+                    for byte in attr.attr_value().as_bytes() {
+                        name.push_str(&format!("{:02x}", byte));
+                    }
+                }
+            }
+        }
+    }
+    Some(name)
+}
+
+// Used in optional mode: the handshake goes on whatever the client sends,
+// and `TlsConnection::info` checks the certificate afterwards with the inner
+// verifier to report the error to the app.
+#[derive(Debug)]
+struct OptionalClientVerifier(Arc<dyn ClientCertVerifier>);
+
+impl ClientCertVerifier for OptionalClientVerifier {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.0.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    // The client still has to prove it holds the key of the certificate.
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.0.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_verify_schemes()
+    }
+}
+
+/// TLS settings loaded from the files of a `ServerConfig`, shared by every
+/// TLS connection.
+///
+/// Reads `ssl_certfile`, `ssl_keyfile` and `ssl_ca_certs`, so it is built
+/// once when the server starts rather than when `ServerConfig` is.
+#[derive(Debug)]
+pub struct ServerTls {
+    config: Arc<rustls::ServerConfig>,
+    // PEM of the leaf certificate, for the `tls` scope extension.
+    server_cert: String,
+    // Set in optional mode, where the handshake doesn't check certificates.
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+}
+
+impl ServerTls {
+    /// `None` if TLS is disabled.
+    pub fn load(config: &ServerConfig) -> Result<Option<Self>, TlsError> {
+        let (Some(certfile), Some(keyfile)) = (&config.ssl_certfile, &config.ssl_keyfile) else {
+            return Ok(None);
+        };
+
+        let mut provider = ring::default_provider();
+        if !config.ssl_ciphers.is_empty() {
+            provider.cipher_suites = config
+                .ssl_ciphers
+                .iter()
+                .map(|name| cipher_suite(name))
+                .collect::<Result<_, _>>()?;
+        }
+        let provider = Arc::new(provider);
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(protocol_versions(&config.ssl_min_version)?)?;
+
+        let (builder, client_verifier) = match (config.ssl_cert_reqs, &config.ssl_ca_certs) {
+            (ClientCertMode::None, _) | (_, None) => (builder.with_no_client_auth(), None),
+            (mode, Some(ca_certs)) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(ca_certs)? {
+                    roots.add(cert)?;
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .build()
+                        .map_err(TlsError::ClientVerifier)?;
+                if mode == ClientCertMode::Required {
+                    (builder.with_client_cert_verifier(verifier), None)
+                } else {
+                    let optional = Arc::new(OptionalClientVerifier(verifier.clone()));
+                    (builder.with_client_cert_verifier(optional), Some(verifier))
+                }
+            }
+        };
+
+        let certs = load_certs(certfile)?;
+        let server_cert = pem_encode(&certs[0]);
+        let mut config = builder.with_single_cert(certs, load_key(keyfile)?)?;
+        // rustls refuses clients whose ALPN list has none of these.
+        config.alpn_protocols = Protocol::ALL.iter().map(|p| p.alpn_id().to_vec()).collect();
+        Ok(Some(Self {
+            config: Arc::new(config),
+            server_cert,
+            client_verifier,
+        }))
+    }
+
+    /// PEM of the leaf certificate.
+    pub fn server_cert(&self) -> &str {
+        &self.server_cert
+    }
+}
+
+/// Names of the cipher suites usable in `ssl_ciphers`.
+pub fn cipher_names() -> Vec<&'static str> {
+    let provider: CryptoProvider = ring::default_provider();
+    provider
+        .cipher_suites
+        .iter()
+        .filter_map(|s| s.suite().as_str())
+        .collect()
+}
+
+/// ASGI `tls` scope extension of a connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsInfo {
+    pub server_cert: String,
+    // PEM certificates sent by the client, leaf first.
+    pub client_cert_chain: Vec<String>,
+    pub client_cert_name: Option<String>,
+    // Why the client certificate didn't verify, in optional mode.
+    pub client_cert_error: Option<String>,
+    // Wire values, `0x0304` for TLS 1.3.
+    pub tls_version: u16,
+    pub cipher_suite: u16,
+    pub protocol: Protocol,
+}
+
+/// Result of feeding received bytes.
+#[derive(Debug, Default)]
+pub struct TlsRead {
+    // Decrypted application data for `Http11Connection`.
+    pub plaintext: Bytes,
+    // Handshake records and alerts to send to the client.
+    pub to_send: Bytes,
+    // Client sent close_notify. No more data will come.
+    pub closed: bool,
+}
+
+/// Server side of a TLS connection, without I/O.
+///
+/// Bytes from the socket go in with `receive`, and the handshake is done
+/// before any plaintext comes out. Response bytes are encrypted with `send`.
+pub struct TlsConnection {
+    tls: Arc<ServerTls>,
+    conn: ServerConnection,
+}
+
+impl fmt::Debug for TlsConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsConnection")
+            .field("handshaking", &self.conn.is_handshaking())
+            .finish()
+    }
+}
+
+impl TlsConnection {
+    pub fn new(tls: Arc<ServerTls>) -> Result<Self, TlsError> {
+        let conn = ServerConnection::new(tls.config.clone())?;
+        Ok(Self { tls, conn })
+    }
+
+    pub fn is_handshaking(&self) -> bool {
+        self.conn.is_handshaking()
+    }
+
+    /// Protocol picked with ALPN. `None` until the handshake is done.
+    pub fn protocol(&self) -> Option<Protocol> {
+        if self.conn.is_handshaking() {
+            return None;
+        }
+        match self.conn.alpn_protocol() {
+            // Only offered protocols can be negotiated.
+            Some(id) => Protocol::from_alpn_id(id),
+            None => Some(Protocol::Http11),
+        }
+    }
+
+    /// Negotiated parameters and client certificate. `None` until the
+    /// handshake is done.
+    pub fn info(&self) -> Option<TlsInfo> {
+        let protocol = self.protocol()?;
+        let chain = self.conn.peer_certificates().unwrap_or_default();
+        let client_cert_error = match (&self.tls.client_verifier, chain.split_first()) {
+            (Some(verifier), Some((end_entity, intermediates))) => verifier
+                .verify_client_cert(end_entity, intermediates, UnixTime::now())
+                .err()
+                .map(|e| e.to_string()),
+            _ => None,
+        };
+        Some(TlsInfo {
+            server_cert: self.tls.server_cert.clone(),
+            client_cert_chain: chain.iter().map(pem_encode).collect(),
+            client_cert_name: chain.first().and_then(subject_name),
+            client_cert_error,
+            tls_version: self.conn.protocol_version().map_or(0, u16::from),
+            cipher_suite: self
+                .conn
+                .negotiated_cipher_suite()
+                .map_or(0, |s| u16::from(s.suite())),
+            protocol,
+        })
+    }
+
+    /// Records waiting to be sent. After an error this holds the alert.
+    pub fn take_output(&mut self) -> Bytes {
+        let mut output = Vec::new();
+        while self.conn.wants_write() {
+            if self.conn.write_tls(&mut output).is_err() {
+                break;
+            }
+        }
+        Bytes::from(output)
+    }
+
+    /// Decrypt `data` received from the client.
+    ///
+    /// On error the connection is unusable. Send `take_output` and close.
+    pub fn receive(&mut self, mut data: &[u8]) -> Result<TlsRead, TlsError> {
+        let mut plaintext = Vec::new();
+        let mut closed = false;
+        while !data.is_empty() {
+            // Reading from a slice only fails when rustls buffers are full,
+            // and they are emptied below after every read.
+            if self.conn.read_tls(&mut data).is_err() {
+                break;
+            }
+            let state = self.conn.process_new_packets()?;
+            // Ends with `WouldBlock` once drained, the bytes read are kept.
+            let _ = self.conn.reader().read_to_end(&mut plaintext);
+            closed |= state.peer_has_closed();
+        }
+        Ok(TlsRead {
+            plaintext: Bytes::from(plaintext),
+            to_send: self.take_output(),
+            closed,
+        })
+    }
+
+    /// Encrypt `plaintext` for the client.
+    ///
+    /// rustls takes at most its buffer limit at a time, so the records are
+    /// taken out between writes until all of `plaintext` is encrypted.
+    pub fn send(&mut self, mut plaintext: &[u8]) -> io::Result<Bytes> {
+        let mut output = Vec::new();
+        while !plaintext.is_empty() {
+            let written = self.conn.writer().write(plaintext)?;
+            if written == 0 && !self.conn.wants_write() {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            plaintext = &plaintext[written..];
+            while self.conn.wants_write() {
+                self.conn.write_tls(&mut output)?;
+            }
+        }
+        Ok(Bytes::from(output))
+    }
+
+    /// close_notify alert to send before closing the socket.
+    pub fn close(&mut self) -> Bytes {
+        self.conn.send_close_notify();
+        self.take_output()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    use std::path::PathBuf;
+
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedKey, DistinguishedName, DnType, IsCa, KeyPair,
+    };
+
+    /// Self-signed `localhost` certificate and key written to a temp dir.
+    pub struct TestCert {
+        pub cert: rcgen::Certificate,
+        pub key: KeyPair,
+        pub certfile: PathBuf,
+        pub keyfile: PathBuf,
+    }
+
+    pub fn self_signed(name: &str) -> TestCert {
+        let CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        write(name, cert, key_pair)
+    }
+
+    /// CA that signs the certificates of `signed_by`.
+    pub fn certificate_authority(name: &str) -> TestCert {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Test CA");
+        let key_pair = KeyPair::generate().unwrap();
+        let cert = params.self_signed(&key_pair).unwrap();
+        write(name, cert, key_pair)
+    }
+
+    pub fn signed_by(name: &str, common_name: &str, ca: &TestCert) -> TestCert {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "Example, Inc.");
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        let key_pair = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&key_pair, &ca.cert, &ca.key).unwrap();
+        write(name, cert, key_pair)
+    }
+
+    fn write(name: &str, cert: rcgen::Certificate, key_pair: KeyPair) -> TestCert {
+        let dir =
+            std::env::temp_dir().join(format!("ruvicorn-tls-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let certfile = dir.join("cert.pem");
+        let keyfile = dir.join("key.pem");
+        std::fs::write(&certfile, cert.pem()).unwrap();
+        std::fs::write(&keyfile, key_pair.serialize_pem()).unwrap();
+        TestCert {
+            cert,
+            key: key_pair,
+            certfile,
+            keyfile,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::test_support::{certificate_authority, self_signed, signed_by, TestCert};
+    use super::*;
+    use crate::conn::http11::bound::{Inbound, Outbound, RequestData};
+    use crate::conn::http11::conn::Http11Connection;
+    use crate::conn::Connection;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+    fn tls_config(cert: &TestCert, ciphers: &[&str], min_version: &str) -> ServerConfig {
+        ServerConfig {
+            ssl_certfile: Some(cert.certfile.to_str().unwrap().to_owned()),
+            ssl_keyfile: Some(cert.keyfile.to_str().unwrap().to_owned()),
+            ssl_ciphers: ciphers.iter().map(|c| c.to_string()).collect(),
+            ssl_min_version: min_version.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    fn connection(config: &ServerConfig) -> TlsConnection {
+        let tls = ServerTls::load(config).unwrap().unwrap();
+        TlsConnection::new(Arc::new(tls)).unwrap()
+    }
+
+    fn client(cert: &TestCert, versions: &[&'static SupportedProtocolVersion]) -> ClientConnection {
+        client_with_cert(cert, versions, None)
+    }
+
+    fn client_with_cert(
+        cert: &TestCert,
+        versions: &[&'static SupportedProtocolVersion],
+        client_cert: Option<&TestCert>,
+    ) -> ClientConnection {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_protocol_versions(versions)
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = match client_cert {
+            Some(client_cert) => {
+                let key = PrivateKeyDer::try_from(client_cert.key.serialize_der()).unwrap();
+                builder
+                    .with_client_auth_cert(vec![client_cert.cert.der().clone()], key)
+                    .unwrap()
+            }
+            None => builder.with_no_client_auth(),
+        };
+        ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap()
+    }
+
+    fn client_output(client: &mut ClientConnection) -> Vec<u8> {
+        let mut output = Vec::new();
+        while client.wants_write() {
+            client.write_tls(&mut output).unwrap();
+        }
+        output
+    }
+
+    // Run the handshake in memory. Returns the error of the server, if any.
+    fn handshake(
+        server: &mut TlsConnection,
+        client: &mut ClientConnection,
+    ) -> Result<(), TlsError> {
+        while client.is_handshaking() || server.is_handshaking() {
+            let read = server.receive(&client_output(client))?;
+            if read.to_send.is_empty() && !client.wants_write() {
+                break;
+            }
+            client.read_tls(&mut &read.to_send[..]).unwrap();
+            client.process_new_packets().unwrap();
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_request_over_tls() {
+        let cert = self_signed("request");
+        let mut server = connection(&tls_config(&cert, &[], "1.2"));
+        let mut client = client(&cert, rustls::DEFAULT_VERSIONS);
+
+        handshake(&mut server, &mut client).unwrap();
+        client
+            .writer()
+            .write_all(b"GET /secure HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let read = server.receive(&client_output(&mut client)).unwrap();
+
+        let mut conn = Http11Connection::new(Arc::default());
+        let output = conn.step(Inbound::RequestData(RequestData {
+            data: read.plaintext,
+        }));
+        assert!(matches!(output, Outbound::RequestHead(head) if head.path == "/secure"));
+
+        let encrypted = server.send(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+        client.read_tls(&mut &encrypted[..]).unwrap();
+        client.process_new_packets().unwrap();
+        let mut response = Vec::new();
+        let _ = client.reader().read_to_end(&mut response);
+        assert_eq!(response, b"HTTP/1.1 204 No Content\r\n\r\n");
+
+        let close = server.close();
+        client.read_tls(&mut &close[..]).unwrap();
+        assert!(client.process_new_packets().unwrap().peer_has_closed());
+    }
+
+    #[test]
+    fn test_send_more_than_buffer_limit() {
+        let cert = self_signed("large");
+        let mut server = connection(&tls_config(&cert, &[], "1.2"));
+        let mut client = client(&cert, rustls::DEFAULT_VERSIONS);
+        handshake(&mut server, &mut client).unwrap();
+        // rustls buffers 64 KiB by default.
+        let body: Vec<u8> = (0..256 * 1024 + 7).map(|i| (i % 251) as u8).collect();
+
+        let encrypted = server.send(&body).unwrap();
+
+        let mut received = Vec::new();
+        let mut encrypted = &encrypted[..];
+        while !encrypted.is_empty() {
+            client.read_tls(&mut encrypted).unwrap();
+            client.process_new_packets().unwrap();
+            let _ = client.reader().read_to_end(&mut received);
+        }
+        assert_eq!(received, body);
+    }
+
+    #[test]
+    fn test_client_close_notify() {
+        let cert = self_signed("close");
+        let mut server = connection(&tls_config(&cert, &[], "1.2"));
+        let mut client = client(&cert, rustls::DEFAULT_VERSIONS);
+
+        handshake(&mut server, &mut client).unwrap();
+        client.send_close_notify();
+        let read = server.receive(&client_output(&mut client)).unwrap();
+
+        assert!(read.closed);
+        assert!(read.plaintext.is_empty());
+    }
+
+    #[test]
+    fn test_min_version() {
+        let cert = self_signed("version");
+        let mut server = connection(&tls_config(&cert, &[], "1.3"));
+        let mut client = client(&cert, &[&rustls::version::TLS12]);
+
+        let result = handshake(&mut server, &mut client);
+
+        assert!(matches!(result, Err(TlsError::Rustls(_))));
+        let alert = server.take_output();
+        client.read_tls(&mut &alert[..]).unwrap();
+        assert!(client.process_new_packets().is_err());
+    }
+
+    #[test]
+    fn test_ciphers() {
+        let cert = self_signed("ciphers");
+        let config = tls_config(&cert, &["tls13_chacha20_poly1305_sha256"], "1.2");
+        let mut server = connection(&config);
+        let mut client = client(&cert, rustls::DEFAULT_VERSIONS);
+
+        handshake(&mut server, &mut client).unwrap();
+
+        assert_eq!(
+            client.negotiated_cipher_suite().unwrap().suite().as_str(),
+            Some("TLS13_CHACHA20_POLY1305_SHA256")
+        );
+        assert!(cipher_names().contains(&"TLS13_AES_128_GCM_SHA256"));
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        let cert = self_signed("invalid");
+
+        let config = tls_config(&cert, &["TLS_RSA_WITH_RC4_128_MD5"], "1.2");
+        assert!(matches!(validate(&config), Err(TlsError::UnknownCipher(_))));
+
+        let config = tls_config(&cert, &[], "1.1");
+        assert!(matches!(
+            validate(&config),
+            Err(TlsError::InvalidVersion(_))
+        ));
+
+        let mut config = tls_config(&cert, &[], "1.2");
+        config.ssl_keyfile = config.ssl_certfile.clone();
+        assert!(matches!(ServerTls::load(&config), Err(TlsError::Pem(..))));
+
+        let mut config = tls_config(&cert, &[], "1.2");
+        config.ssl_certfile = Some("/nonexistent/cert.pem".to_owned());
+        assert!(matches!(ServerTls::load(&config), Err(TlsError::Pem(..))));
+
+        assert!(ServerTls::load(&ServerConfig::default()).unwrap().is_none());
+    }
+
+    fn mtls_config(cert: &TestCert, ca: &TestCert, mode: ClientCertMode) -> ServerConfig {
+        ServerConfig {
+            ssl_ca_certs: Some(ca.certfile.to_str().unwrap().to_owned()),
+            ssl_cert_reqs: mode,
+            ..tls_config(cert, &[], "1.2")
+        }
+    }
+
+    #[test]
+    fn test_client_cert_required() {
+        let cert = self_signed("required");
+        let ca = certificate_authority("required-ca");
+        let client_cert = signed_by("required-client", "client+1", &ca);
+        let config = mtls_config(&cert, &ca, ClientCertMode::Required);
+
+        let mut server = connection(&config);
+        assert!(server.info().is_none());
+        let mut client = client_with_cert(&cert, rustls::DEFAULT_VERSIONS, Some(&client_cert));
+        handshake(&mut server, &mut client).unwrap();
+
+        let info = server.info().unwrap();
+        assert_eq!(info.server_cert, cert.cert.pem());
+        assert_eq!(info.client_cert_chain, vec![client_cert.cert.pem()]);
+        assert_eq!(
+            info.client_cert_name.as_deref(),
+            Some("CN=client\\+1,O=Example\\, Inc.")
+        );
+        assert_eq!(info.client_cert_error, None);
+        assert_eq!(info.tls_version, 0x0304);
+        assert_eq!(
+            info.cipher_suite,
+            u16::from(client.negotiated_cipher_suite().unwrap().suite())
+        );
+
+        // No certificate.
+        let mut server = connection(&config);
+        let mut client = client_with_cert(&cert, rustls::DEFAULT_VERSIONS, None);
+        assert!(handshake(&mut server, &mut client).is_err());
+
+        // Certificate of another CA.
+        let other = self_signed("required-other");
+        let mut server = connection(&config);
+        let mut client = client_with_cert(&cert, rustls::DEFAULT_VERSIONS, Some(&other));
+        assert!(handshake(&mut server, &mut client).is_err());
+    }
+
+    #[test]
+    fn test_client_cert_optional() {
+        let cert = self_signed("optional");
+        let ca = certificate_authority("optional-ca");
+        let client_cert = signed_by("optional-client", "client", &ca);
+        let config = mtls_config(&cert, &ca, ClientCertMode::Optional);
+
+        let mut server = connection(&config);
+        let mut client = client_with_cert(&cert, &[&rustls::version::TLS12], Some(&client_cert));
+        handshake(&mut server, &mut client).unwrap();
+        let info = server.info().unwrap();
+        assert_eq!(
+            info.client_cert_name.as_deref(),
+            Some("CN=client,O=Example\\, Inc.")
+        );
+        assert_eq!(info.client_cert_error, None);
+        assert_eq!(info.tls_version, 0x0303);
+
+        let mut server = connection(&config);
+        let mut client = client_with_cert(&cert, rustls::DEFAULT_VERSIONS, None);
+        handshake(&mut server, &mut client).unwrap();
+        let info = server.info().unwrap();
+        assert!(info.client_cert_chain.is_empty());
+        assert_eq!(info.client_cert_name, None);
+        assert_eq!(info.client_cert_error, None);
+
+        let other = self_signed("optional-other");
+        let mut server = connection(&config);
+        let mut client = client_with_cert(&cert, rustls::DEFAULT_VERSIONS, Some(&other));
+        handshake(&mut server, &mut client).unwrap();
+        let info = server.info().unwrap();
+        assert_eq!(info.client_cert_chain.len(), 1);
+        assert!(info.client_cert_error.is_some());
+    }
+
+    #[test]
+    fn test_client_cert_not_requested() {
+        let cert = self_signed("not-requested");
+        let ca = certificate_authority("not-requested-ca");
+        let client_cert = signed_by("not-requested-client", "client", &ca);
+        let config = mtls_config(&cert, &ca, ClientCertMode::None);
+
+        let mut server = connection(&config);
+        let mut client = client_with_cert(&cert, rustls::DEFAULT_VERSIONS, Some(&client_cert));
+        handshake(&mut server, &mut client).unwrap();
+
+        let info = server.info().unwrap();
+        assert!(info.client_cert_chain.is_empty());
+        assert_eq!(info.client_cert_name, None);
+    }
+
+    #[test]
+    fn test_escape_dn_value() {
+        let mut name = String::new();
+        escape_dn_value("# a;b<c>\"d\\ ", &mut name);
+        assert_eq!(name, "\\# a\\;b\\<c\\>\\\"d\\\\\\ ");
+    }
+
+    fn client_with_alpn(cert: &TestCert, alpn: &[&[u8]]) -> ClientConnection {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|id| id.to_vec()).collect();
+        ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_alpn() {
+        let cert = self_signed("alpn");
+        let config = tls_config(&cert, &[], "1.2");
+
+        let mut server = connection(&config);
+        assert_eq!(server.protocol(), None);
+        let mut client = client_with_alpn(&cert, &[b"h2", b"http/1.1"]);
+        handshake(&mut server, &mut client).unwrap();
+        assert_eq!(client.alpn_protocol(), Some(&b"http/1.1"[..]));
+        assert_eq!(server.protocol(), Some(Protocol::Http11));
+        assert_eq!(server.info().unwrap().protocol, Protocol::Http11);
+
+        let conn = Connection::new(server.protocol().unwrap(), Arc::new(config.clone()));
+        assert!(matches!(conn, Connection::Http11(_)));
+
+        // Clients without ALPN get HTTP/1.1.
+        let mut server = connection(&config);
+        let mut client = client_with_alpn(&cert, &[]);
+        handshake(&mut server, &mut client).unwrap();
+        assert_eq!(client.alpn_protocol(), None);
+        assert_eq!(server.protocol(), Some(Protocol::Http11));
+    }
+
+    #[test]
+    fn test_alpn_unsupported() {
+        let cert = self_signed("alpn-unsupported");
+        let mut server = connection(&tls_config(&cert, &[], "1.2"));
+        let mut client = client_with_alpn(&cert, &[b"h2", b"h3"]);
+
+        let result = handshake(&mut server, &mut client);
+
+        assert!(matches!(
+            result,
+            Err(TlsError::Rustls(rustls::Error::NoApplicationProtocol))
+        ));
+        assert_eq!(server.protocol(), None);
+    }
+}
@@ -0,0 +1,249 @@
+use std::fs;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::config::ServerConfig;
+use crate::conn::tls::{ServerTls, TlsError};
+use crate::logger::target;
+
+// Modification time and size of a file, `None` if it can't be read.
+type FileStamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &str) -> FileStamp {
+    // Follows symlinks, so a swapped Kubernetes secret counts as a change.
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+fn file_stamps(config: &ServerConfig) -> Vec<FileStamp> {
+    [
+        &config.ssl_certfile,
+        &config.ssl_keyfile,
+        &config.ssl_ca_certs,
+    ]
+    .into_iter()
+    .flatten()
+    .map(|path| stamp(path))
+    .collect()
+}
+
+/// TLS settings of the server, reloaded from the files without a restart.
+///
+/// New connections take `current()`, and keep that `ServerTls` until they
+/// close, so a reload only changes later handshakes. If the new files fail
+/// to load the error is logged and the old certificate stays in use.
+#[derive(Debug)]
+pub struct CertReloader {
+    config: Arc<ServerConfig>,
+    current: RwLock<Arc<ServerTls>>,
+    // Of the files at the last load, checked by `reload_if_changed`.
+    stamps: Mutex<Vec<FileStamp>>,
+}
+
+impl CertReloader {
+    /// `None` if TLS is disabled.
+    ///
+    /// With `ssl_reload_interval` set, a thread checks the files for changes
+    /// until the reloader is dropped.
+    pub fn new(config: Arc<ServerConfig>) -> Result<Option<Arc<Self>>, TlsError> {
+        // Taken before loading, so files changed meanwhile are loaded again.
+        let stamps = file_stamps(&config);
+        let Some(tls) = ServerTls::load(&config)? else {
+            return Ok(None);
+        };
+        let interval = config.ssl_reload_interval;
+        let reloader = Arc::new(Self {
+            config,
+            current: RwLock::new(Arc::new(tls)),
+            stamps: Mutex::new(stamps),
+        });
+
+        if !interval.is_zero() {
+            let weak = Arc::downgrade(&reloader);
+            let spawned = thread::Builder::new()
+                .name("ruvicorn-tls-reload".to_owned())
+                .spawn(move || watch(weak, interval));
+            if let Err(e) = spawned {
+                log::error!(
+                    target: target::ERROR,
+                    code = "tls.watch_failed";
+                    "Can't watch TLS certificate files: {}", e
+                );
+            }
+        }
+        Ok(Some(reloader))
+    }
+
+    pub fn current(&self) -> Arc<ServerTls> {
+        // A writer can't panic halfway through swapping an `Arc`.
+        return self
+            .current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+    }
+
+    /// Load the files again and swap the settings used for new connections.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        match ServerTls::load(&self.config) {
+            Ok(Some(tls)) => {
+                *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(tls);
+                let certfile = self.config.ssl_certfile.as_deref().unwrap_or_default();
+                log::info!(
+                    target: target::ERROR,
+                    code = "tls.reloaded";
+                    "Reloaded TLS certificate {:?}", certfile
+                );
+            }
+            // `new` doesn't build a reloader when TLS is disabled.
+            Ok(None) => {}
+            Err(e) => {
+                log::error!(
+                    target: target::ERROR,
+                    code = "tls.reload_failed";
+                    "Can't reload TLS certificate, keeping the old one: {}", e
+                );
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reload if a file changed since the last check. Returns whether it did
+    /// try, failures are logged by `reload`.
+    pub fn reload_if_changed(&self) -> bool {
+        let stamps = file_stamps(&self.config);
+        {
+            let mut last = self.stamps.lock().unwrap_or_else(|e| e.into_inner());
+            if *last == stamps {
+                return false;
+            }
+            // Also on failure: a certificate written before its key fails
+            // once, and the key write is the next change.
+            *last = stamps;
+        }
+        let _ = self.reload();
+        true
+    }
+}
+
+fn watch(reloader: Weak<CertReloader>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let Some(reloader) = reloader.upgrade() else {
+            return;
+        };
+        reloader.reload_if_changed();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::conn::tls::test_support::{self_signed, TestCert};
+    use std::fs::File;
+
+    fn tls_config(cert: &TestCert, interval: Duration) -> Arc<ServerConfig> {
+        Arc::new(ServerConfig {
+            ssl_certfile: Some(cert.certfile.to_str().unwrap().to_owned()),
+            ssl_keyfile: Some(cert.keyfile.to_str().unwrap().to_owned()),
+            ssl_reload_interval: interval,
+            ..Default::default()
+        })
+    }
+
+    // Copy the files of `from` over those of `to`, with a later mtime so the
+    // change is seen even when the sizes match.
+    fn replace_files(to: &TestCert, from: &TestCert, mtime: SystemTime) {
+        for (dst, src) in [(&to.certfile, &from.certfile), (&to.keyfile, &from.keyfile)] {
+            fs::copy(src, dst).unwrap();
+            File::options()
+                .write(true)
+                .open(dst)
+                .unwrap()
+                .set_modified(mtime)
+                .unwrap();
+        }
+    }
+
+    fn later() -> SystemTime {
+        SystemTime::now() + Duration::from_secs(60)
+    }
+
+    #[test]
+    fn test_reload() {
+        let cert = self_signed("reload");
+        let reloader = CertReloader::new(tls_config(&cert, Duration::ZERO))
+            .unwrap()
+            .unwrap();
+        let old = reloader.current();
+
+        let new_cert = self_signed("reload-new");
+        replace_files(&cert, &new_cert, later());
+        reloader.reload().unwrap();
+
+        assert_eq!(reloader.current().server_cert(), new_cert.cert.pem());
+        // Connections that started before keep their settings.
+        assert_eq!(old.server_cert(), cert.cert.pem());
+    }
+
+    #[test]
+    fn test_reload_failure_keeps_old_cert() {
+        let cert = self_signed("reload-failure");
+        let reloader = CertReloader::new(tls_config(&cert, Duration::ZERO))
+            .unwrap()
+            .unwrap();
+
+        // Key of another certificate.
+        let other = self_signed("reload-failure-other");
+        fs::copy(&other.keyfile, &cert.keyfile).unwrap();
+
+        assert!(reloader.reload().is_err());
+        assert_eq!(reloader.current().server_cert(), cert.cert.pem());
+    }
+
+    #[test]
+    fn test_reload_if_changed() {
+        let cert = self_signed("reload-changed");
+        let reloader = CertReloader::new(tls_config(&cert, Duration::ZERO))
+            .unwrap()
+            .unwrap();
+
+        assert!(!reloader.reload_if_changed());
+
+        let new_cert = self_signed("reload-changed-new");
+        replace_files(&cert, &new_cert, later());
+        assert!(reloader.reload_if_changed());
+        assert!(!reloader.reload_if_changed());
+        assert_eq!(reloader.current().server_cert(), new_cert.cert.pem());
+    }
+
+    #[test]
+    fn test_watch() {
+        let cert = self_signed("reload-watch");
+        let reloader = CertReloader::new(tls_config(&cert, Duration::from_millis(5)))
+            .unwrap()
+            .unwrap();
+
+        let new_cert = self_signed("reload-watch-new");
+        replace_files(&cert, &new_cert, later());
+
+        let mut reloaded = false;
+        for _ in 0..400 {
+            if reloader.current().server_cert() == new_cert.cert.pem() {
+                reloaded = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(reloaded);
+    }
+
+    #[test]
+    fn test_tls_disabled() {
+        let reloader = CertReloader::new(Arc::default()).unwrap();
+
+        assert!(reloader.is_none());
+    }
+}
@@ -1,6 +1,6 @@
 use bytes::Bytes;
 
 pub struct AsgiReceiveRequest {
-    body: Bytes,
-    more_body: bool,
+    pub body: Bytes,
+    pub more_body: bool,
 }
@@ -6,9 +6,9 @@ use pyo3::types::PyDict;
 pub trait ASGISendResponseStart: Sized {
     fn get_status(&self) -> Result<usize, AsgiSpecError>;
 
-    fn get_headers(&self) -> Vec<PyHeader>;
+    fn get_headers(&self) -> Result<Vec<PyHeader<'_>>, AsgiSpecError>;
 
-    fn get_trailers(&self) -> bool;
+    fn get_trailers(&self) -> Result<bool, AsgiSpecError>;
 }
 
 impl ASGISendResponseStart for &PyDict {
@@ -16,27 +16,27 @@ impl ASGISendResponseStart for &PyDict {
         util::get_item_with_casting(self, "status")
     }
 
-    fn get_headers(&self) -> Vec<PyHeader> {
+    fn get_headers(&self) -> Result<Vec<PyHeader<'_>>, AsgiSpecError> {
         util::get_item_with_default(self, "headers", Vec::new())
     }
 
-    fn get_trailers(&self) -> bool {
+    fn get_trailers(&self) -> Result<bool, AsgiSpecError> {
         util::get_item_with_default(self, "trailers", false)
     }
 }
 
 pub trait ASGISendResponseBody: Sized {
-    fn get_body(&self) -> &[u8];
+    fn get_body(&self) -> Result<&[u8], AsgiSpecError>;
 
-    fn get_more_body(&self) -> bool;
+    fn get_more_body(&self) -> Result<bool, AsgiSpecError>;
 }
 
 impl ASGISendResponseBody for &PyDict {
-    fn get_body(&self) -> &[u8] {
+    fn get_body(&self) -> Result<&[u8], AsgiSpecError> {
         util::get_item_with_default(self, "body", b"")
     }
 
-    fn get_more_body(&self) -> bool {
+    fn get_more_body(&self) -> Result<bool, AsgiSpecError> {
         util::get_item_with_default(self, "more_body", false)
     }
 }
@@ -64,8 +64,8 @@ mod test {
             let res: &PyDict = result.extract().unwrap();
             let headers: Vec<(&[u8], &[u8])> = vec![(b"x-header-key", b"x-header-value")];
             assert!(matches!(res.get_status(), Ok(200)));
-            assert_eq!(res.get_headers(), headers);
-            assert_eq!(res.get_trailers(), false);
+            assert_eq!(res.get_headers().unwrap(), headers);
+            assert!(!res.get_trailers().unwrap());
         });
     }
 
@@ -87,8 +87,8 @@ mod test {
             let res: &PyDict = result.extract().unwrap();
             let headers: Vec<(&[u8], &[u8])> = vec![];
             assert!(matches!(res.get_status(), Ok(200)));
-            assert_eq!(res.get_headers(), headers);
-            assert_eq!(res.get_trailers(), false);
+            assert_eq!(res.get_headers().unwrap(), headers);
+            assert!(!res.get_trailers().unwrap());
         });
     }
 
@@ -109,8 +109,8 @@ mod test {
             let res: &PyDict = result.extract().unwrap();
             let headers: Vec<(&[u8], &[u8])> = vec![(b"x-header-key", b"x-header-value")];
             assert!(matches!(res.get_status(), Ok(200)));
-            assert_eq!(res.get_headers(), headers);
-            assert_eq!(res.get_trailers(), false);
+            assert_eq!(res.get_headers().unwrap(), headers);
+            assert!(!res.get_trailers().unwrap());
         });
     }
 
@@ -131,8 +131,8 @@ mod test {
             let res: &PyDict = result.extract().unwrap();
             let headers: Vec<(&[u8], &[u8])> = vec![];
             assert!(matches!(res.get_status(), Ok(200)));
-            assert_eq!(res.get_headers(), headers);
-            assert_eq!(res.get_trailers(), false);
+            assert_eq!(res.get_headers().unwrap(), headers);
+            assert!(!res.get_trailers().unwrap());
         });
     }
 
@@ -151,8 +151,8 @@ mod test {
                 )
                 .unwrap();
             let res: &PyDict = result.extract().unwrap();
-            assert_eq!(res.get_body(), b"asdf");
-            assert_eq!(res.get_more_body(), true);
+            assert_eq!(res.get_body().unwrap(), b"asdf");
+            assert!(res.get_more_body().unwrap());
         });
     }
 
@@ -170,8 +170,8 @@ mod test {
                 )
                 .unwrap();
             let res: &PyDict = result.extract().unwrap();
-            assert_eq!(res.get_body(), b"");
-            assert_eq!(res.get_more_body(), true);
+            assert_eq!(res.get_body().unwrap(), b"");
+            assert!(res.get_more_body().unwrap());
         });
     }
 
@@ -189,8 +189,44 @@ mod test {
                 )
                 .unwrap();
             let res: &PyDict = result.extract().unwrap();
-            assert_eq!(res.get_body(), b"asdf");
-            assert_eq!(res.get_more_body(), false);
+            assert_eq!(res.get_body().unwrap(), b"asdf");
+            assert!(!res.get_more_body().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_convert_http_response_start_with_list_headers() {
+        Python::with_gil(|py| {
+            let result = py
+                .eval(
+                    r#"{
+                    'type':'http.response.start',
+                    'status': 200,
+                    'headers': [[b'x-header-key', b'x-header-value']],
+                }"#,
+                    None,
+                    None,
+                )
+                .unwrap();
+            let res: &PyDict = result.extract().unwrap();
+            assert!(res.get_headers().is_err());
+        });
+    }
+
+    #[test]
+    fn test_convert_http_response_body_with_wrong_type() {
+        Python::with_gil(|py| {
+            for body in ["bytearray(b'asdf')", "'asdf'"] {
+                let result = py
+                    .eval(
+                        &format!("{{'type': 'http.response.body', 'body': {}}}", body),
+                        None,
+                        None,
+                    )
+                    .unwrap();
+                let res: &PyDict = result.extract().unwrap();
+                assert!(res.get_body().is_err(), "{}", body);
+            }
         });
     }
 }
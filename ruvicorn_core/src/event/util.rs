@@ -15,18 +15,19 @@ where
     }
 }
 
+/// `default` if the key is missing. A value of the wrong type is an error,
+/// not replaced, so a malformed message can't change the response.
 pub fn get_item_with_default<'t, T: FromPyObject<'t>>(
     dict: &'t PyDict,
     key: &str,
     default: T,
-) -> T {
+) -> Result<T, AsgiSpecError> {
     if let Some(pitem) = dict.get_item(key) {
-        if let Ok(ritem) = pitem.extract::<'t, T>() {
-            return ritem;
-        } else {
-            return default;
+        match pitem.extract::<'t, T>() {
+            Ok(ritem) => Ok(ritem),
+            Err(_) => Err(AsgiSpecError {}),
         }
     } else {
-        return default;
+        Ok(default)
     }
 }
@@ -1,14 +1,30 @@
 use pyo3::prelude::*;
 
-mod conn;
+pub mod access_log;
+#[cfg(test)]
+mod alloc_counter;
+pub mod clock;
+pub mod config;
+pub mod conn;
+pub mod date;
 mod errors;
-mod event;
-mod handle;
-mod types;
+pub mod event;
+pub mod handle;
+pub mod logger;
+pub mod proxy;
+pub mod scope;
+pub mod server;
+pub mod types;
+
+pub use config::ServerConfig;
 
 /// A Python module implemented in Rust.
 #[pymodule]
 #[pyo3(name = "_ruvicorn_core")]
 fn _ruvicorn_core(_py: Python, m: &PyModule) -> PyResult<()> {
+    logger::init(log::LevelFilter::Info);
+    m.add_class::<ServerConfig>()?;
+    m.add_class::<server::Server>()?;
+    m.add_function(wrap_pyfunction!(logger::set_log_level, m)?)?;
     Ok(())
 }
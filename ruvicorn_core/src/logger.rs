@@ -0,0 +1,169 @@
+use log::kv::{self, Key, Value, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{IntoPyDict, PyDict};
+
+/// Log targets. Each one is forwarded to the Python logger of the same name.
+pub mod target {
+    // Connection and HTTP parsing events.
+    pub const PROTOCOL: &str = "ruvicorn.protocol";
+    // Server side failures.
+    pub const ERROR: &str = "ruvicorn.error";
+    // One record per finished request.
+    pub const ACCESS: &str = "ruvicorn.access";
+}
+
+const ROOT_TARGET: &str = "ruvicorn";
+
+pub const LEVELS: [&str; 6] = ["critical", "error", "warning", "info", "debug", "trace"];
+
+pub fn parse_level(level: &str) -> Option<LevelFilter> {
+    match level.to_ascii_lowercase().as_str() {
+        // `log` has nothing above error.
+        "critical" | "error" => Some(LevelFilter::Error),
+        "warning" => Some(LevelFilter::Warn),
+        "info" => Some(LevelFilter::Info),
+        "debug" => Some(LevelFilter::Debug),
+        "trace" => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+// Numeric levels of Python `logging`. Trace is the custom level used by uvicorn.
+fn py_level(level: Level) -> u8 {
+    match level {
+        Level::Error => 40,
+        Level::Warn => 30,
+        Level::Info => 20,
+        Level::Debug => 10,
+        Level::Trace => 5,
+    }
+}
+
+// Key-values of a record become `extra` attributes of the Python log record.
+struct Extra(Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for Extra {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push((key.as_str().to_owned(), value.to_string()));
+        Ok(())
+    }
+}
+
+/// Forward `log` records to Python `logging`.
+///
+/// Records are filtered by `log::max_level()` before they reach here, so
+/// disabled levels cost one atomic load and never take the GIL.
+struct PythonLogger;
+
+impl Log for PythonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with(ROOT_TARGET)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // Formatted before taking the GIL, so other threads only wait for
+        // the call into `logging`.
+        let message = record.args().to_string();
+        let mut extra = Extra(Vec::new());
+        let _ = record.key_values().visit(&mut extra);
+        Python::with_gil(|py| {
+            let result = py.import("logging").and_then(|logging| {
+                let kwargs = PyDict::new(py);
+                kwargs.set_item("extra", extra.0.into_py_dict(py))?;
+
+                logging
+                    .call_method1("getLogger", (record.target(),))?
+                    .call_method("log", (py_level(record.level()), message), Some(kwargs))
+            });
+            // Logging must never raise into the caller.
+            if let Err(e) = result {
+                e.write_unraisable(py, None);
+            }
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: PythonLogger = PythonLogger;
+
+/// Install the Python bridge. Calling it again only changes the level.
+pub fn init(level: LevelFilter) {
+    // Fails only when a logger is already installed, which is fine.
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level);
+}
+
+/// Set the level of records forwarded from Rust to Python `logging`.
+#[pyfunction]
+pub fn set_log_level(level: &str) -> PyResult<()> {
+    match parse_level(level) {
+        Some(filter) => {
+            init(filter);
+            Ok(())
+        }
+        None => Err(PyValueError::new_err(format!(
+            "log level must be one of {}, got {:?}",
+            LEVELS.join(", "),
+            level
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(parse_level("critical"), Some(LevelFilter::Error));
+        assert_eq!(parse_level("WARNING"), Some(LevelFilter::Warn));
+        assert_eq!(parse_level("trace"), Some(LevelFilter::Trace));
+        assert_eq!(parse_level("verbose"), None);
+    }
+
+    #[test]
+    fn test_forward_to_python_logging() {
+        Python::with_gil(|py| {
+            let records = pyo3::types::PyList::empty(py);
+            let globals = PyDict::new(py);
+            globals.set_item("records", records).unwrap();
+            py.run(
+                r#"
+import logging
+
+class Collect(logging.Handler):
+    def emit(self, record):
+        records.append((record.name, record.levelno, record.getMessage(), getattr(record, "code", "")))
+
+logger = logging.getLogger("ruvicorn.error")
+logger.setLevel(logging.DEBUG)
+logger.addHandler(Collect())
+"#,
+                Some(globals),
+                None,
+            )
+            .unwrap();
+
+            init(LevelFilter::Debug);
+            log::warn!(target: target::ERROR, code = "test.code"; "broken {}", 1);
+            log::warn!(target: "other", "ignored");
+
+            let records: Vec<(String, u8, String, String)> = records.extract().unwrap();
+            assert!(records.contains(&(
+                "ruvicorn.error".to_owned(),
+                30,
+                "broken 1".to_owned(),
+                "test.code".to_owned()
+            )));
+            assert!(records
+                .iter()
+                .all(|(_, _, message, _)| message != "ignored"));
+        });
+    }
+}
@@ -0,0 +1,446 @@
+use std::net::IpAddr;
+
+use crate::config::ServerConfig;
+use crate::types::RsHeader;
+
+// One entry of `forwarded_allow_ips`: an address or a CIDR network.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    fn parse(entry: &str) -> Option<Self> {
+        let (addr, prefix) = match entry.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (entry, None),
+        };
+        let addr: IpAddr = addr.parse().ok()?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) if !prefix.is_empty() && prefix.bytes().all(|b| b.is_ascii_digit()) => {
+                prefix.parse::<u8>().ok().filter(|p| *p <= max)?
+            }
+            Some(_) => return None,
+            None => max,
+        };
+        Some(Self { addr, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Check one `forwarded_allow_ips` entry: `*`, an address or a CIDR network.
+pub fn is_valid_entry(entry: &str) -> bool {
+    entry == "*" || Network::parse(entry).is_some()
+}
+
+/// Proxies whose `Forwarded` and `X-Forwarded-*` headers are believed.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    // `*` is in the list.
+    all: bool,
+    networks: Vec<Network>,
+}
+
+impl TrustedProxies {
+    /// Built from `forwarded_allow_ips`. Invalid entries are skipped, as they
+    /// are rejected by `ServerConfig::validate`.
+    pub fn new(entries: &[String]) -> Self {
+        Self {
+            all: entries.iter().any(|e| e == "*"),
+            networks: entries.iter().filter_map(|e| Network::parse(e)).collect(),
+        }
+    }
+
+    /// `None` if proxy headers are disabled.
+    pub fn from_config(config: &ServerConfig) -> Option<Self> {
+        if !config.proxy_headers {
+            return None;
+        }
+        Some(Self::new(&config.forwarded_allow_ips))
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.all || self.networks.iter().any(|n| n.contains(ip))
+    }
+
+    /// `contains` for a peer without an address, as on Unix sockets, is
+    /// only true with `*`.
+    pub fn contains_peer(&self, peer: Option<IpAddr>) -> bool {
+        match peer {
+            Some(ip) => self.contains(ip),
+            None => self.all,
+        }
+    }
+
+    /// What the proxy headers say about the request, if `peer` is trusted.
+    ///
+    /// The chain of addresses is walked from the right, skipping trusted
+    /// proxies, and the first untrusted one is the client. If every hop is
+    /// trusted the leftmost one is. `Forwarded` is used when present, and the
+    /// `proto` and `host` of the chosen element are taken with it. Otherwise
+    /// `X-Forwarded-For` gives the chain, and the rightmost values of
+    /// `X-Forwarded-Proto` and `X-Forwarded-Host`, set by the nearest proxy,
+    /// are used.
+    pub fn resolve(&self, headers: &[RsHeader], peer: IpAddr) -> Option<Forwarded> {
+        if !self.contains(peer) {
+            return None;
+        }
+        self.read(headers)
+    }
+
+    /// `resolve` for a peer on a Unix socket. It has no address, so only `*`
+    /// trusts it.
+    pub fn resolve_unix(&self, headers: &[RsHeader]) -> Option<Forwarded> {
+        if !self.all {
+            return None;
+        }
+        self.read(headers)
+    }
+
+    fn read(&self, headers: &[RsHeader]) -> Option<Forwarded> {
+        if let Some(hops) = forwarded_hops(headers) {
+            let hop = self.client_hop(&hops)?;
+            return Some(Forwarded {
+                client: hop.node.clone(),
+                scheme: hop.proto.as_deref().and_then(scheme),
+                host: hop.host.clone(),
+            });
+        }
+
+        let hops: Vec<Hop> = header_values(headers, b"x-forwarded-for")
+            .map(|value| Hop {
+                node: Some(Node::parse(value)),
+                proto: None,
+                host: None,
+            })
+            .collect();
+        let forwarded = Forwarded {
+            client: self.client_hop(&hops).and_then(|hop| hop.node.clone()),
+            scheme: header_values(headers, b"x-forwarded-proto")
+                .last()
+                .and_then(scheme),
+            host: header_values(headers, b"x-forwarded-host")
+                .last()
+                .map(str::to_owned),
+        };
+        if forwarded == Forwarded::default() {
+            return None;
+        }
+        Some(forwarded)
+    }
+
+    fn client_hop<'h>(&self, hops: &'h [Hop]) -> Option<&'h Hop> {
+        hops.iter()
+            .rev()
+            .find(|hop| {
+                !hop.node
+                    .as_ref()
+                    .and_then(|n| n.ip)
+                    .is_some_and(|ip| self.contains(ip))
+            })
+            .or_else(|| hops.first())
+    }
+}
+
+/// Client, scheme and host given by trusted proxies.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Forwarded {
+    pub client: Option<Node>,
+    // `http` or `https`, lowercased.
+    pub scheme: Option<&'static str>,
+    // `Host` the client sent to the first proxy.
+    pub host: Option<String>,
+}
+
+/// Address of a hop, RFC 7239 section 6.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    // `None` for `unknown` and obfuscated identifiers.
+    pub ip: Option<IpAddr>,
+    // Address without brackets, or the identifier as sent.
+    pub name: String,
+    // 0 if not known.
+    pub port: u16,
+}
+
+impl Node {
+    fn parse(value: &str) -> Self {
+        let value = value.trim();
+        let (name, port) = if let Some(rest) = value.strip_prefix('[') {
+            match rest.split_once(']') {
+                Some((name, port)) => (name, port.strip_prefix(':')),
+                None => (value, None),
+            }
+        } else {
+            match value.rsplit_once(':') {
+                // Bare IPv6 in X-Forwarded-For has colons but no port.
+                Some((name, port)) if !name.contains(':') => (name, Some(port)),
+                _ => (value, None),
+            }
+        };
+        Self {
+            ip: name.parse().ok(),
+            name: name.to_owned(),
+            port: port.and_then(|p| p.parse().ok()).unwrap_or(0),
+        }
+    }
+}
+
+// One element of `Forwarded`, or one address of `X-Forwarded-For`.
+#[derive(Debug, Default)]
+struct Hop {
+    node: Option<Node>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+fn scheme(proto: &str) -> Option<&'static str> {
+    match proto.trim() {
+        p if p.eq_ignore_ascii_case("http") => Some("http"),
+        p if p.eq_ignore_ascii_case("https") => Some("https"),
+        _ => None,
+    }
+}
+
+// Comma separated values of every `name` line, in order. Non-UTF-8 lines are
+// skipped.
+fn header_values<'h>(
+    headers: &'h [RsHeader],
+    name: &'static [u8],
+) -> impl Iterator<Item = &'h str> {
+    headers
+        .iter()
+        .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+        .filter_map(|(_, v)| std::str::from_utf8(v).ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
+
+// Split on `separator` outside of quoted strings.
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                parts.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+fn unquote(value: &str) -> String {
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(inner) => {
+            let mut unquoted = String::with_capacity(inner.len());
+            let mut chars = inner.chars();
+            while let Some(c) = chars.next() {
+                unquoted.push(if c == '\\' {
+                    chars.next().unwrap_or(c)
+                } else {
+                    c
+                });
+            }
+            unquoted
+        }
+        None => value.to_owned(),
+    }
+}
+
+// RFC 7239 section 4. `None` if there is no `Forwarded` header.
+fn forwarded_hops(headers: &[RsHeader]) -> Option<Vec<Hop>> {
+    let mut hops = Vec::new();
+    let mut found = false;
+    for (name, value) in headers {
+        if !name.eq_ignore_ascii_case(b"forwarded") {
+            continue;
+        }
+        found = true;
+        let Ok(value) = std::str::from_utf8(value) else {
+            continue;
+        };
+        for element in split_unquoted(value, ',') {
+            if element.trim().is_empty() {
+                continue;
+            }
+            let mut hop = Hop::default();
+            for pair in split_unquoted(element, ';') {
+                let Some((key, value)) = pair.split_once('=') else {
+                    continue;
+                };
+                let value = unquote(value.trim());
+                match key.trim() {
+                    k if k.eq_ignore_ascii_case("for") => hop.node = Some(Node::parse(&value)),
+                    k if k.eq_ignore_ascii_case("proto") => hop.proto = Some(value),
+                    k if k.eq_ignore_ascii_case("host") => hop.host = Some(value),
+                    _ => {}
+                }
+            }
+            hops.push(hop);
+        }
+    }
+    if !found {
+        return None;
+    }
+    Some(hops)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::Bytes;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> Vec<RsHeader> {
+        pairs
+            .iter()
+            .map(|(n, v)| {
+                (
+                    Bytes::from_static(n.as_bytes()),
+                    Bytes::from_static(v.as_bytes()),
+                )
+            })
+            .collect()
+    }
+
+    fn proxies(entries: &[&str]) -> TrustedProxies {
+        TrustedProxies::new(&entries.iter().map(|e| e.to_string()).collect::<Vec<_>>())
+    }
+
+    fn client_of(forwarded: Option<Forwarded>) -> Option<(String, u16)> {
+        forwarded.and_then(|f| f.client).map(|n| (n.name, n.port))
+    }
+
+    #[test]
+    fn test_network() {
+        let trusted = proxies(&["10.0.0.0/8", "192.168.1.1", "fd00::/8"]);
+
+        assert!(trusted.contains("10.1.2.3".parse().unwrap()));
+        assert!(trusted.contains("192.168.1.1".parse().unwrap()));
+        assert!(trusted.contains("fd12::1".parse().unwrap()));
+        assert!(trusted.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!trusted.contains("11.0.0.1".parse().unwrap()));
+        assert!(!trusted.contains("192.168.1.2".parse().unwrap()));
+        assert!(proxies(&["0.0.0.0/0"]).contains("8.8.8.8".parse().unwrap()));
+        assert!(proxies(&["*"]).contains("::1".parse().unwrap()));
+
+        for entry in ["10.0.0.0/33", "10.0.0.0/", "10.0.0.0/+8", "host", "::/129"] {
+            assert!(!is_valid_entry(entry), "{}", entry);
+        }
+    }
+
+    #[test]
+    fn test_untrusted_peer() {
+        let trusted = proxies(&["127.0.0.1"]);
+        let headers = headers(&[("X-Forwarded-For", "1.2.3.4"), ("Forwarded", "for=1.2.3.4")]);
+
+        assert_eq!(trusted.resolve(&headers, "10.0.0.1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_x_forwarded() {
+        let trusted = proxies(&["127.0.0.1", "10.0.0.0/8"]);
+        let headers = headers(&[
+            ("X-Forwarded-For", "6.6.6.6, 1.2.3.4"),
+            ("X-Forwarded-For", "10.0.0.2"),
+            ("X-Forwarded-Proto", "http, HTTPS"),
+            ("X-Forwarded-Host", "example.com"),
+        ]);
+
+        let forwarded = trusted
+            .resolve(&headers, "127.0.0.1".parse().unwrap())
+            .unwrap();
+
+        assert_eq!(forwarded.client.unwrap().name, "1.2.3.4");
+        assert_eq!(forwarded.scheme, Some("https"));
+        assert_eq!(forwarded.host.as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn test_all_hops_trusted() {
+        let trusted = proxies(&["10.0.0.0/8"]);
+        let headers = headers(&[("X-Forwarded-For", "10.0.0.3, 10.0.0.2")]);
+
+        let client = client_of(trusted.resolve(&headers, "10.0.0.1".parse().unwrap()));
+
+        assert_eq!(client, Some(("10.0.0.3".to_owned(), 0)));
+    }
+
+    #[test]
+    fn test_forwarded() {
+        let trusted = proxies(&["127.0.0.1", "10.0.0.0/8"]);
+        let headers = headers(&[
+            (
+                "Forwarded",
+                "for=6.6.6.6;proto=http, for=\"[2001:db8::1]:4711\";proto=https;host=\"a.example\"",
+            ),
+            ("Forwarded", "For=10.0.0.2;Proto=http;host=internal"),
+            ("X-Forwarded-For", "9.9.9.9"),
+        ]);
+
+        let forwarded = trusted
+            .resolve(&headers, "127.0.0.1".parse().unwrap())
+            .unwrap();
+
+        let client = forwarded.client.unwrap();
+        assert_eq!(client.ip, Some("2001:db8::1".parse().unwrap()));
+        assert_eq!((client.name.as_str(), client.port), ("2001:db8::1", 4711));
+        assert_eq!(forwarded.scheme, Some("https"));
+        assert_eq!(forwarded.host.as_deref(), Some("a.example"));
+    }
+
+    #[test]
+    fn test_forwarded_obfuscated_and_quoted() {
+        let trusted = proxies(&["127.0.0.1"]);
+
+        let headers = headers(&[("Forwarded", "for=_hidden:_port;host=\"a,b;c\"")]);
+        let forwarded = trusted
+            .resolve(&headers, "127.0.0.1".parse().unwrap())
+            .unwrap();
+        let client = forwarded.client.unwrap();
+        assert_eq!(
+            (client.ip, client.name.as_str(), client.port),
+            (None, "_hidden", 0)
+        );
+        assert_eq!(forwarded.host.as_deref(), Some("a,b;c"));
+
+        let headers = self::headers(&[("Forwarded", "for=unknown, for=192.0.2.43:47011")]);
+        let client = client_of(trusted.resolve(&headers, "127.0.0.1".parse().unwrap()));
+        assert_eq!(client, Some(("192.0.2.43".to_owned(), 47011)));
+    }
+
+    #[test]
+    fn test_unknown_scheme_is_ignored() {
+        let trusted = proxies(&["127.0.0.1"]);
+        let headers = headers(&[("X-Forwarded-Proto", "javascript")]);
+
+        assert_eq!(
+            trusted.resolve(&headers, "127.0.0.1".parse().unwrap()),
+            None
+        );
+    }
+}
@@ -0,0 +1,383 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyList};
+
+use crate::config::ServerConfig;
+use crate::conn::http11::bound::RequestHead;
+use crate::conn::tls::TlsInfo;
+use crate::proxy::TrustedProxies;
+use crate::types::RsHeader;
+
+// `path` of the scope is percent-decoded, `raw_path` keeps the bytes as sent.
+fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn default_port(scheme: &str) -> u16 {
+    if scheme == "https" {
+        443
+    } else {
+        80
+    }
+}
+
+// `(host, port)` of a `Host` value, with the scheme default port if it has none.
+fn host_port(host: &str, scheme: &str) -> (String, u16) {
+    let (name, port) = match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => (&host[..i], host[i + 1..].parse().ok()),
+        _ => (host, None),
+    };
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    (
+        name.to_owned(),
+        port.unwrap_or_else(|| default_port(scheme)),
+    )
+}
+
+/// ASGI `http` connection scope.
+#[derive(Debug, PartialEq)]
+pub struct HttpScope {
+    pub http_version: &'static str,
+    pub method: String,
+    pub scheme: &'static str,
+    pub path: String,
+    pub raw_path: String,
+    pub query_string: String,
+    pub root_path: String,
+    pub headers: Vec<RsHeader>,
+    pub client: Option<(String, u16)>,
+    // Port is `None` on Unix sockets, the path is given as host.
+    pub server: Option<(String, Option<u16>)>,
+    // Set on TLS connections, `scheme` is `https` then unless a proxy says
+    // otherwise.
+    pub tls: Option<Arc<TlsInfo>>,
+}
+
+fn tls_extension<'py>(py: Python<'py>, tls: &TlsInfo) -> PyResult<&'py PyDict> {
+    let extension = PyDict::new(py);
+    extension.set_item("server_cert", &tls.server_cert)?;
+    extension.set_item("client_cert_chain", &tls.client_cert_chain)?;
+    extension.set_item("client_cert_name", &tls.client_cert_name)?;
+    extension.set_item("client_cert_error", &tls.client_cert_error)?;
+    extension.set_item("tls_version", tls.tls_version)?;
+    extension.set_item("cipher_suite", tls.cipher_suite)?;
+    Ok(extension)
+}
+
+impl HttpScope {
+    pub fn to_py<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let scope = PyDict::new(py);
+        let asgi = PyDict::new(py);
+        asgi.set_item("version", "3.0")?;
+        asgi.set_item("spec_version", "2.4")?;
+
+        let headers = PyList::empty(py);
+        for (name, value) in &self.headers {
+            headers.append((
+                PyBytes::new(py, &name.to_ascii_lowercase()),
+                PyBytes::new(py, value),
+            ))?;
+        }
+
+        scope.set_item("type", "http")?;
+        scope.set_item("asgi", asgi)?;
+        scope.set_item("http_version", self.http_version)?;
+        scope.set_item("method", &self.method)?;
+        scope.set_item("scheme", self.scheme)?;
+        scope.set_item("path", &self.path)?;
+        scope.set_item("raw_path", PyBytes::new(py, self.raw_path.as_bytes()))?;
+        scope.set_item(
+            "query_string",
+            PyBytes::new(py, self.query_string.as_bytes()),
+        )?;
+        scope.set_item("root_path", &self.root_path)?;
+        scope.set_item("headers", headers)?;
+        scope.set_item("client", self.client.clone())?;
+        scope.set_item("server", self.server.clone())?;
+        if let Some(tls) = &self.tls {
+            let extensions = PyDict::new(py);
+            extensions.set_item("tls", tls_extension(py, tls)?)?;
+            scope.set_item("extensions", extensions)?;
+        }
+        Ok(scope)
+    }
+}
+
+/// Local end of a connection, the `server` of the scope.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerAddr {
+    Inet(SocketAddr),
+    // Path of a Unix socket.
+    Unix(String),
+}
+
+/// Builds the scope of every request on the server.
+///
+/// Trusted proxies are parsed from the config once, and proxy headers
+/// rewrite `client`, `scheme` and `server` when the peer is one of them.
+#[derive(Debug)]
+pub struct ScopeBuilder {
+    config: Arc<ServerConfig>,
+    proxies: Option<TrustedProxies>,
+}
+
+impl ScopeBuilder {
+    pub fn new(config: Arc<ServerConfig>) -> Self {
+        let proxies = TrustedProxies::from_config(&config);
+        Self { config, proxies }
+    }
+
+    /// `peer` is the address of the other end of the connection, `None` on
+    /// Unix sockets, and `server` the local address it was accepted on.
+    pub fn build(
+        &self,
+        head: &RequestHead,
+        peer: Option<SocketAddr>,
+        server: Option<ServerAddr>,
+        tls: Option<&Arc<TlsInfo>>,
+    ) -> HttpScope {
+        let mut scope = HttpScope {
+            http_version: tls.map_or(head.version.as_str(), |tls| {
+                tls.protocol.http_version(head.version)
+            }),
+            method: head.method.clone(),
+            scheme: if tls.is_some() { "https" } else { "http" },
+            path: percent_decode(&head.path),
+            raw_path: head.path.clone(),
+            query_string: head.query_string.clone(),
+            root_path: self.config.root_path.clone(),
+            headers: head.headers.clone(),
+            client: peer.map(|p| (p.ip().to_string(), p.port())),
+            server: None,
+            tls: tls.cloned(),
+        };
+        let unix = matches!(server, Some(ServerAddr::Unix(_)));
+        scope.server = server.map(|server| match server {
+            ServerAddr::Inet(addr) => (addr.ip().to_string(), Some(addr.port())),
+            ServerAddr::Unix(path) => (path, None),
+        });
+
+        let forwarded = match (&self.proxies, peer) {
+            (Some(proxies), Some(peer)) => proxies.resolve(&head.headers, peer.ip()),
+            (Some(proxies), None) if unix => proxies.resolve_unix(&head.headers),
+            _ => None,
+        };
+        if let Some(forwarded) = forwarded {
+            if let Some(client) = forwarded.client {
+                scope.client = Some((client.name, client.port));
+            }
+            if let Some(scheme) = forwarded.scheme {
+                scope.scheme = scheme;
+            }
+            if let Some(host) = forwarded.host {
+                let (host, port) = host_port(&host, scope.scheme);
+                scope.server = Some((host, Some(port)));
+            }
+        }
+        scope
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::conn::http11::bound::{HttpVersion, TargetForm};
+    use crate::conn::Protocol;
+    use bytes::Bytes;
+
+    fn head(headers: &[(&'static str, &'static str)]) -> RequestHead {
+        RequestHead {
+            method: "GET".to_owned(),
+            target: TargetForm::Origin,
+            path: "/a%20b/%E2%9C%93%zz".to_owned(),
+            query_string: "x=%20".to_owned(),
+            host: Some("localhost".to_owned()),
+            version: HttpVersion::Http11,
+            headers: headers
+                .iter()
+                .map(|(n, v)| {
+                    (
+                        Bytes::from_static(n.as_bytes()),
+                        Bytes::from_static(v.as_bytes()),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn addr(addr: &str) -> Option<SocketAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    fn server(addr: &str) -> Option<ServerAddr> {
+        Some(ServerAddr::Inet(addr.parse().unwrap()))
+    }
+
+    #[test]
+    fn test_scope() {
+        let builder = ScopeBuilder::new(Arc::default());
+
+        let scope = builder.build(
+            &head(&[("Host", "localhost")]),
+            addr("10.0.0.1:50000"),
+            server("127.0.0.1:8000"),
+            None,
+        );
+
+        assert_eq!(scope.path, "/a b/\u{2713}%zz");
+        assert_eq!(scope.raw_path, "/a%20b/%E2%9C%93%zz");
+        assert_eq!(scope.query_string, "x=%20");
+        assert_eq!(scope.scheme, "http");
+        assert_eq!(scope.client, Some(("10.0.0.1".to_owned(), 50000)));
+        assert_eq!(scope.server, Some(("127.0.0.1".to_owned(), Some(8000))));
+    }
+
+    #[test]
+    fn test_scope_behind_proxy() {
+        let config = ServerConfig {
+            forwarded_allow_ips: vec!["10.0.0.0/8".to_owned()],
+            ..Default::default()
+        };
+        let builder = ScopeBuilder::new(Arc::new(config));
+        let head = head(&[
+            ("X-Forwarded-For", "1.2.3.4, 10.0.0.2"),
+            ("X-Forwarded-Proto", "https"),
+            ("X-Forwarded-Host", "example.com"),
+        ]);
+
+        let scope = builder.build(&head, addr("10.0.0.1:50000"), server("10.0.0.9:8000"), None);
+        assert_eq!(scope.client, Some(("1.2.3.4".to_owned(), 0)));
+        assert_eq!(scope.scheme, "https");
+        assert_eq!(scope.server, Some(("example.com".to_owned(), Some(443))));
+
+        // Same headers from a peer that is not a trusted proxy.
+        let scope = builder.build(&head, addr("8.8.8.8:50000"), server("10.0.0.9:8000"), None);
+        assert_eq!(scope.client, Some(("8.8.8.8".to_owned(), 50000)));
+        assert_eq!(scope.scheme, "http");
+        assert_eq!(scope.server, Some(("10.0.0.9".to_owned(), Some(8000))));
+    }
+
+    #[test]
+    fn test_proxy_headers_disabled() {
+        let config = ServerConfig {
+            proxy_headers: false,
+            forwarded_allow_ips: vec!["*".to_owned()],
+            ..Default::default()
+        };
+        let builder = ScopeBuilder::new(Arc::new(config));
+
+        let scope = builder.build(
+            &head(&[("Forwarded", "for=1.2.3.4;proto=https;host=\"[::1]:8443\"")]),
+            addr("127.0.0.1:50000"),
+            None,
+            None,
+        );
+
+        assert_eq!(scope.client, Some(("127.0.0.1".to_owned(), 50000)));
+        assert_eq!(scope.scheme, "http");
+        assert_eq!(scope.server, None);
+    }
+
+    #[test]
+    fn test_scope_on_unix_socket() {
+        let unix = Some(ServerAddr::Unix("/run/app.sock".to_owned()));
+        let head = head(&[("X-Forwarded-For", "1.2.3.4")]);
+
+        let scope = ScopeBuilder::new(Arc::default()).build(&head, None, unix.clone(), None);
+        assert_eq!(scope.client, None);
+        assert_eq!(scope.server, Some(("/run/app.sock".to_owned(), None)));
+
+        // Only `*` trusts a peer without an address.
+        let config = ServerConfig {
+            forwarded_allow_ips: vec!["*".to_owned()],
+            ..Default::default()
+        };
+        let scope = ScopeBuilder::new(Arc::new(config)).build(&head, None, unix, None);
+        assert_eq!(scope.client, Some(("1.2.3.4".to_owned(), 0)));
+    }
+
+    #[test]
+    fn test_host_port() {
+        assert_eq!(
+            host_port("example.com", "http"),
+            ("example.com".to_owned(), 80)
+        );
+        assert_eq!(
+            host_port("example.com:8443", "https"),
+            ("example.com".to_owned(), 8443)
+        );
+        assert_eq!(host_port("[::1]:8443", "http"), ("::1".to_owned(), 8443));
+        assert_eq!(host_port("[::1]", "https"), ("::1".to_owned(), 443));
+    }
+
+    #[test]
+    fn test_scope_to_py() {
+        let builder = ScopeBuilder::new(Arc::default());
+        let tls = Arc::new(TlsInfo {
+            server_cert: "-----BEGIN CERTIFICATE-----\n".to_owned(),
+            client_cert_chain: Vec::new(),
+            client_cert_name: None,
+            client_cert_error: None,
+            tls_version: 0x0304,
+            cipher_suite: 0x1301,
+            protocol: Protocol::Http11,
+        });
+        let scope = builder.build(
+            &head(&[("X-Name", "Value")]),
+            addr("127.0.0.1:50000"),
+            server("127.0.0.1:8000"),
+            Some(&tls),
+        );
+
+        Python::with_gil(|py| {
+            let dict = scope.to_py(py).unwrap();
+            let locals = PyDict::new(py);
+            locals.set_item("scope", dict).unwrap();
+
+            let check = py
+                .eval(
+                    r#"(
+                        scope["type"] == "http"
+                        and scope["http_version"] == "1.1"
+                        and scope["scheme"] == "https"
+                        and scope["path"] == "/a b/✓%zz"
+                        and scope["raw_path"] == b"/a%20b/%E2%9C%93%zz"
+                        and scope["query_string"] == b"x=%20"
+                        and scope["headers"] == [(b"x-name", b"Value")]
+                        and scope["client"] == ("127.0.0.1", 50000)
+                        and scope["asgi"]["version"] == "3.0"
+                        and scope["extensions"]["tls"]["tls_version"] == 0x0304
+                        and scope["extensions"]["tls"]["cipher_suite"] == 0x1301
+                        and scope["extensions"]["tls"]["client_cert_chain"] == []
+                        and scope["extensions"]["tls"]["client_cert_name"] is None
+                    )"#,
+                    None,
+                    Some(locals),
+                )
+                .unwrap();
+            assert!(check.extract::<bool>().unwrap());
+        });
+    }
+}
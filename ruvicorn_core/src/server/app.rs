@@ -0,0 +1,219 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use pyo3::exceptions::{PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyString};
+
+use crate::conn::http11::bound::{Inbound, RequestBody, ResponseBody, ResponseStart};
+use crate::event::send::{ASGISendResponseBody, ASGISendResponseStart};
+use crate::logger::target;
+use crate::scope::HttpScope;
+use crate::server::channel::RequestChannel;
+use crate::server::Shared;
+
+pub(crate) fn request_message(py: Python<'_>, body: &RequestBody) -> PyResult<PyObject> {
+    let message = PyDict::new(py);
+    message.set_item("type", "http.request")?;
+    message.set_item("body", PyBytes::new(py, &body.body))?;
+    message.set_item("more_body", body.more_body)?;
+    Ok(message.into())
+}
+
+pub(crate) fn disconnect_message(py: Python<'_>) -> PyResult<PyObject> {
+    let message = PyDict::new(py);
+    message.set_item("type", "http.disconnect")?;
+    Ok(message.into())
+}
+
+pub(crate) fn response_inbound(message: &PyDict) -> PyResult<Inbound> {
+    let kind: &str = message
+        .get_item("type")
+        .ok_or_else(|| PyValueError::new_err("ASGI message has no type"))?
+        .extract()?;
+    match kind {
+        "http.response.start" => {
+            let status = message
+                .get_status()
+                .ok()
+                .and_then(|status| u16::try_from(status).ok())
+                .ok_or_else(|| PyValueError::new_err("http.response.start has no valid status"))?;
+            let headers = message
+                .get_headers()
+                .map_err(|_| {
+                    PyTypeError::new_err(
+                        "http.response.start headers must be a list of (bytes, bytes) tuples",
+                    )
+                })?
+                .into_iter()
+                .map(|(name, value)| (Bytes::copy_from_slice(name), Bytes::copy_from_slice(value)))
+                .collect();
+            Ok(Inbound::ResponseStart(ResponseStart { status, headers }))
+        }
+        "http.response.body" => {
+            let body = message
+                .get_body()
+                .map_err(|_| PyTypeError::new_err("http.response.body body must be bytes"))?;
+            let more_body = message
+                .get_more_body()
+                .map_err(|_| PyTypeError::new_err("http.response.body more_body must be a bool"))?;
+            Ok(Inbound::ResponseBody(ResponseBody {
+                body: Bytes::copy_from_slice(body),
+                more_body,
+            }))
+        }
+        _ => Err(PyValueError::new_err(format!(
+            "unexpected ASGI message type {:?}",
+            kind
+        ))),
+    }
+}
+
+// Completes a future on the event loop thread. Futures cancelled by the app
+// meanwhile are left alone.
+#[pyclass]
+struct SetResult {
+    future: PyObject,
+    value: PyObject,
+    error: bool,
+}
+
+#[pymethods]
+impl SetResult {
+    fn __call__(&self, py: Python<'_>) -> PyResult<()> {
+        let future = self.future.as_ref(py);
+        if future.call_method0("done")?.is_true()? {
+            return Ok(());
+        }
+        let method = if self.error {
+            "set_exception"
+        } else {
+            "set_result"
+        };
+        future.call_method1(method, (self.value.clone_ref(py),))?;
+        Ok(())
+    }
+}
+
+/// Complete `future` of the app from a connection task.
+pub(crate) fn resolve<F>(event_loop: &PyObject, future: PyObject, result: F)
+where
+    F: FnOnce(Python<'_>) -> PyResult<PyObject>,
+{
+    Python::with_gil(|py| {
+        let (value, error) = match result(py) {
+            Ok(value) => (value, false),
+            Err(e) => (e.value(py).into(), true),
+        };
+        let set = SetResult {
+            future,
+            value,
+            error,
+        };
+        if let Err(e) = event_loop.call_method1(py, "call_soon_threadsafe", (set,)) {
+            // Event loop is closed, nobody waits for the future anymore.
+            log::debug!(
+                target: target::ERROR,
+                code = "asgi.resolve_failed";
+                "Can't complete ASGI future: {}", e
+            );
+        }
+    });
+}
+
+// Scheduled on the event loop thread to run the app coroutine as a task.
+#[pyclass]
+struct StartApp {
+    coroutine: PyObject,
+    channel: Arc<RequestChannel>,
+}
+
+#[pymethods]
+impl StartApp {
+    fn __call__(&self, py: Python<'_>) {
+        let done = AppDone {
+            channel: self.channel.clone(),
+        };
+        let result = self
+            .channel
+            .event_loop()
+            .call_method1(py, "create_task", (self.coroutine.clone_ref(py),))
+            .and_then(|task| task.call_method1(py, "add_done_callback", (done,)));
+        if let Err(e) = result {
+            log::error!(
+                target: target::ERROR,
+                code = "asgi.start_failed";
+                "Can't start ASGI application: {}", e
+            );
+            self.channel.finish(true);
+        }
+    }
+}
+
+fn format_exception(py: Python<'_>, exception: &PyAny) -> String {
+    let formatted = py.import("traceback").and_then(|traceback| {
+        let lines = traceback.call_method1(
+            "format_exception",
+            (
+                exception.get_type(),
+                exception,
+                exception.getattr("__traceback__")?,
+            ),
+        )?;
+        PyString::new(py, "")
+            .call_method1("join", (lines,))?
+            .extract::<String>()
+    });
+    formatted.unwrap_or_else(|_| exception.to_string())
+}
+
+#[pyclass]
+struct AppDone {
+    channel: Arc<RequestChannel>,
+}
+
+#[pymethods]
+impl AppDone {
+    fn __call__(&self, py: Python<'_>, task: &PyAny) -> PyResult<()> {
+        let failed = if task.call_method0("cancelled")?.is_true()? {
+            log::warn!(
+                target: target::ERROR,
+                code = "asgi.cancelled";
+                "ASGI application was cancelled"
+            );
+            true
+        } else {
+            let exception = task.call_method0("exception")?;
+            if !exception.is_none() {
+                log::error!(
+                    target: target::ERROR,
+                    code = "asgi.exception";
+                    "Exception in ASGI application\n{}", format_exception(py, exception)
+                );
+            }
+            !exception.is_none()
+        };
+        self.channel.finish(failed);
+        Ok(())
+    }
+}
+
+/// Call the app with the scope and schedule it on the event loop.
+///
+/// Only the coroutine is created here, it runs on the event loop thread.
+/// Returns the channel of the app's `receive` and `send`.
+pub(crate) fn start(shared: &Shared, scope: &HttpScope) -> PyResult<Arc<RequestChannel>> {
+    Python::with_gil(|py| {
+        let channel = RequestChannel::new(shared.event_loop.clone_ref(py));
+        let (receive, send) = channel.callables(py);
+        let coroutine = shared.app.call1(py, (scope.to_py(py)?, receive, send))?;
+        let start = StartApp {
+            coroutine,
+            channel: channel.clone(),
+        };
+        shared
+            .event_loop
+            .call_method1(py, "call_soon_threadsafe", (start,))?;
+        Ok(channel)
+    })
+}
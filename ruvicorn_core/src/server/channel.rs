@@ -0,0 +1,503 @@
+//! Per-request queues between a connection task and the app.
+//!
+//! `receive()` and `send()` return awaitables that complete without
+//! suspending when the queues allow it: a body part is already read ahead,
+//! or the response data not handled yet is under `HIGH_WATER`. Only
+//! otherwise an asyncio future is created, for the connection task to
+//! complete.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::prelude::*;
+use pyo3::pyclass::IterNextOutput;
+use pyo3::types::PyDict;
+use tokio::sync::Notify;
+
+use crate::conn::http11::bound::{Inbound, RequestBody};
+use crate::server::app::{disconnect_message, request_message, resolve, response_inbound};
+
+/// Response body bytes the app may queue before `send()` waits for the
+/// connection, as the default write buffer limit of asyncio transports.
+pub const HIGH_WATER: usize = 64 * 1024;
+
+/// Response event queued by `send()`.
+#[derive(Debug)]
+pub struct Sent {
+    pub inbound: Inbound,
+    /// Future of the `send()` call, if it waits for the event to be handled.
+    pub waiter: Option<PyObject>,
+}
+
+#[derive(Debug, Default)]
+struct Queues {
+    // Body parts read ahead for `receive()`.
+    received: VecDeque<RequestBody>,
+    // Futures of `receive()` calls waiting for the next request event.
+    receivers: VecDeque<PyObject>,
+    sent: VecDeque<Sent>,
+    // Response body bytes in `sent`.
+    sent_bytes: usize,
+    started: bool,
+    complete: bool,
+    // `receive()` returns a disconnect once `received` is empty, and
+    // `send()` does nothing.
+    disconnected: bool,
+    // Set when the app task finished, `true` if it raised or was cancelled.
+    done: Option<bool>,
+}
+
+/// Request and response events of one request/response cycle.
+#[derive(Debug)]
+pub struct RequestChannel {
+    queues: Mutex<Queues>,
+    // Wakes the connection task when the app queued or took something.
+    notify: Notify,
+    event_loop: PyObject,
+}
+
+impl RequestChannel {
+    pub fn new(event_loop: PyObject) -> Arc<Self> {
+        Arc::new(Self {
+            queues: Mutex::default(),
+            notify: Notify::new(),
+            event_loop,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Queues> {
+        // Nothing panics while the lock is held.
+        self.queues.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn event_loop(&self) -> &PyObject {
+        &self.event_loop
+    }
+
+    /// `receive` and `send` callables of the app.
+    pub fn callables(self: &Arc<Self>, py: Python<'_>) -> (PyObject, PyObject) {
+        let receive = AppReceive {
+            channel: self.clone(),
+        };
+        let send = AppSend {
+            channel: self.clone(),
+        };
+        (receive.into_py(py), send.into_py(py))
+    }
+
+    /// Wait until the app queued or took an event, or finished.
+    pub async fn notified(&self) {
+        self.notify.notified().await;
+    }
+
+    /// Whether the next body part should be read ahead. One part is kept
+    /// ahead of `receive()`, so a slow app holds back the client.
+    pub fn wants_body(&self) -> bool {
+        let queues = self.lock();
+        queues.received.is_empty() && !queues.disconnected
+    }
+
+    pub fn push_body(&self, body: RequestBody) {
+        let mut queues = self.lock();
+        match queues.receivers.pop_front() {
+            Some(future) => {
+                drop(queues);
+                resolve(&self.event_loop, future, |py| request_message(py, &body));
+            }
+            None => queues.received.push_back(body),
+        }
+    }
+
+    /// Next response event to handle, in the order the app sent them.
+    pub fn next_sent(&self) -> Option<Sent> {
+        let mut queues = self.lock();
+        let sent = queues.sent.pop_front()?;
+        if let Inbound::ResponseBody(body) = &sent.inbound {
+            queues.sent_bytes -= body.body.len();
+        }
+        Some(sent)
+    }
+
+    /// Complete the future of a `send()` call, if it waits.
+    pub fn complete<F>(&self, waiter: Option<PyObject>, result: F)
+    where
+        F: FnOnce(Python<'_>) -> PyResult<PyObject>,
+    {
+        if let Some(future) = waiter {
+            resolve(&self.event_loop, future, result);
+        }
+    }
+
+    /// `Some(failed)` once the app task finished.
+    ///
+    /// Events sent before the app finished are in `next_sent` by then.
+    pub fn done(&self) -> Option<bool> {
+        return self.lock().done;
+    }
+
+    pub fn finish(&self, failed: bool) {
+        self.lock().done = Some(failed);
+        self.notify.notify_one();
+    }
+
+    /// The client is gone or the response is complete. Waiting `receive()`
+    /// calls get a disconnect and waiting `send()` calls return.
+    pub fn disconnect(&self) {
+        let (receivers, sent) = {
+            let mut queues = self.lock();
+            queues.disconnected = true;
+            queues.sent_bytes = 0;
+            (
+                std::mem::take(&mut queues.receivers),
+                std::mem::take(&mut queues.sent),
+            )
+        };
+        for future in receivers {
+            resolve(&self.event_loop, future, disconnect_message);
+        }
+        for sent in sent {
+            self.complete(sent.waiter, |py| Ok(py.None()));
+        }
+    }
+
+    fn receive(&self, py: Python<'_>) -> PyResult<Completion> {
+        let mut queues = self.lock();
+        if let Some(body) = queues.received.pop_front() {
+            drop(queues);
+            // Read the next part ahead.
+            self.notify.notify_one();
+            return Ok(Completion::ready(request_message(py, &body)?));
+        }
+        if queues.disconnected {
+            drop(queues);
+            return Ok(Completion::ready(disconnect_message(py)?));
+        }
+        let future = self.event_loop.call_method0(py, "create_future")?;
+        queues.receivers.push_back(future.clone_ref(py));
+        drop(queues);
+        self.notify.notify_one();
+        Ok(Completion::pending(future))
+    }
+
+    fn send(&self, py: Python<'_>, message: &PyDict) -> PyResult<Completion> {
+        let inbound = response_inbound(message)?;
+        let mut queues = self.lock();
+        if queues.disconnected || queues.complete {
+            // Messages after a disconnect are dropped, like uvicorn does.
+            return Ok(Completion::ready(py.None()));
+        }
+        let wait = match &inbound {
+            Inbound::ResponseStart(_) => {
+                if queues.started {
+                    return Err(PyRuntimeError::new_err(
+                        "Unexpected ASGI message 'http.response.start' sent, after response already started",
+                    ));
+                }
+                queues.started = true;
+                // The connection validates the head, errors are raised here.
+                true
+            }
+            Inbound::ResponseBody(body) => {
+                if !queues.started {
+                    return Err(PyRuntimeError::new_err(
+                        "Expected ASGI message 'http.response.start', but got 'http.response.body'",
+                    ));
+                }
+                queues.complete = !body.more_body;
+                queues.sent_bytes += body.body.len();
+                queues.sent_bytes > HIGH_WATER
+            }
+            _ => false,
+        };
+        let waiter = if wait {
+            Some(self.event_loop.call_method0(py, "create_future")?)
+        } else {
+            None
+        };
+        queues.sent.push_back(Sent {
+            inbound,
+            waiter: waiter.as_ref().map(|future| future.clone_ref(py)),
+        });
+        drop(queues);
+        self.notify.notify_one();
+        Ok(match waiter {
+            Some(future) => Completion::pending(future),
+            None => Completion::ready(py.None()),
+        })
+    }
+}
+
+enum Step {
+    // Done without suspending.
+    Ready(PyObject),
+    // Waits for the asyncio future.
+    Pending(PyObject),
+    // The future was yielded to the task, which resumes once it is done.
+    Yielded(PyObject),
+}
+
+/// Awaitable returned by `receive()` and `send()`.
+///
+/// Ready ones return from their first `__next__`, pending ones yield an
+/// asyncio future like `await future` does.
+#[pyclass(module = "ruvicorn_core")]
+pub struct Completion {
+    step: Option<Step>,
+}
+
+impl Completion {
+    fn ready(value: PyObject) -> Self {
+        Self {
+            step: Some(Step::Ready(value)),
+        }
+    }
+
+    fn pending(future: PyObject) -> Self {
+        Self {
+            step: Some(Step::Pending(future)),
+        }
+    }
+}
+
+#[pymethods]
+impl Completion {
+    fn __await__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python<'_>) -> PyResult<IterNextOutput<PyObject, PyObject>> {
+        match self.step.take() {
+            Some(Step::Ready(value)) => Ok(IterNextOutput::Return(value)),
+            Some(Step::Pending(future)) => {
+                // Tells the asyncio task that `future` was awaited.
+                future.setattr(py, "_asyncio_future_blocking", true)?;
+                self.step = Some(Step::Yielded(future.clone_ref(py)));
+                Ok(IterNextOutput::Yield(future))
+            }
+            Some(Step::Yielded(future)) => {
+                Ok(IterNextOutput::Return(future.call_method0(py, "result")?))
+            }
+            None => Err(PyRuntimeError::new_err(
+                "cannot reuse already awaited ASGI event",
+            )),
+        }
+    }
+}
+
+/// `receive` callable of the app.
+#[pyclass]
+struct AppReceive {
+    channel: Arc<RequestChannel>,
+}
+
+#[pymethods]
+impl AppReceive {
+    fn __call__(&self, py: Python<'_>) -> PyResult<Completion> {
+        self.channel.receive(py)
+    }
+}
+
+/// `send` callable of the app.
+#[pyclass]
+struct AppSend {
+    channel: Arc<RequestChannel>,
+}
+
+#[pymethods]
+impl AppSend {
+    fn __call__(&self, py: Python<'_>, message: &PyDict) -> PyResult<Completion> {
+        self.channel.send(py, message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::Bytes;
+
+    const HELPERS: &str = r#"
+import asyncio
+
+loop = asyncio.new_event_loop()
+
+async def wait(awaitable):
+    return await awaitable
+
+def now(awaitable):
+    # Result of an awaitable that must not suspend.
+    coroutine = wait(awaitable)
+    try:
+        coroutine.send(None)
+    except StopIteration as e:
+        return e.value
+    coroutine.close()
+    raise AssertionError("suspended")
+
+def later(awaitable):
+    return loop.run_until_complete(wait(awaitable))
+"#;
+
+    fn with_channel<F>(f: F)
+    where
+        F: FnOnce(Python<'_>, &Arc<RequestChannel>, &PyDict),
+    {
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            py.run(HELPERS, Some(globals), None).unwrap();
+            let channel = RequestChannel::new(globals.get_item("loop").unwrap().into());
+            let (receive, send) = channel.callables(py);
+            globals.set_item("receive", receive).unwrap();
+            globals.set_item("send", send).unwrap();
+            f(py, &channel, globals);
+            py.run("loop.close()", Some(globals), None).unwrap();
+        });
+    }
+
+    fn eval<'py>(py: Python<'py>, globals: &'py PyDict, code: &str) -> PyResult<&'py PyAny> {
+        py.eval(code, Some(globals), None)
+    }
+
+    fn body(data: &'static [u8], more_body: bool) -> RequestBody {
+        RequestBody {
+            body: Bytes::from_static(data),
+            more_body,
+        }
+    }
+
+    #[test]
+    fn test_receive_buffered() {
+        with_channel(|py, channel, globals| {
+            channel.push_body(body(b"abc", true));
+            assert!(!channel.wants_body());
+
+            let message = eval(py, globals, "now(receive())").unwrap();
+            assert_eq!(
+                message.to_string(),
+                "{'type': 'http.request', 'body': b'abc', 'more_body': True}"
+            );
+            assert!(channel.wants_body());
+        });
+    }
+
+    #[test]
+    fn test_receive_waits() {
+        with_channel(|py, channel, globals| {
+            let awaitable = eval(py, globals, "receive()").unwrap();
+            globals.set_item("awaitable", awaitable).unwrap();
+            channel.push_body(body(b"abc", false));
+
+            let message = eval(py, globals, "later(awaitable)").unwrap();
+            assert_eq!(
+                message.to_string(),
+                "{'type': 'http.request', 'body': b'abc', 'more_body': False}"
+            );
+            assert!(eval(py, globals, "now(awaitable)").is_err());
+        });
+    }
+
+    #[test]
+    fn test_disconnect() {
+        with_channel(|py, channel, globals| {
+            let awaitable = eval(py, globals, "receive()").unwrap();
+            globals.set_item("awaitable", awaitable).unwrap();
+            channel.disconnect();
+
+            let waiting = eval(py, globals, "later(awaitable)").unwrap();
+            let after = eval(py, globals, "now(receive())").unwrap();
+            assert_eq!(waiting.to_string(), "{'type': 'http.disconnect'}");
+            assert_eq!(after.to_string(), "{'type': 'http.disconnect'}");
+            // Dropped.
+            eval(
+                py,
+                globals,
+                "now(send({'type': 'http.response.start', 'status': 200}))",
+            )
+            .unwrap();
+            assert!(channel.next_sent().is_none());
+        });
+    }
+
+    #[test]
+    fn test_send() {
+        with_channel(|py, channel, globals| {
+            let start = eval(
+                py,
+                globals,
+                "send({'type': 'http.response.start', 'status': 200})",
+            )
+            .unwrap();
+            globals.set_item("start", start).unwrap();
+            // The body fits the buffer.
+            eval(
+                py,
+                globals,
+                "now(send({'type': 'http.response.body', 'body': b'abc', 'more_body': True}))",
+            )
+            .unwrap();
+
+            let sent = channel.next_sent().unwrap();
+            assert!(
+                matches!(sent.inbound, Inbound::ResponseStart(ref start) if start.status == 200)
+            );
+            channel.complete(sent.waiter, |py| Ok(py.None()));
+            assert!(eval(py, globals, "later(start)").unwrap().is_none());
+            let sent = channel.next_sent().unwrap();
+            assert!(matches!(sent.inbound, Inbound::ResponseBody(ref body) if body.body == "abc"));
+            assert!(sent.waiter.is_none());
+            assert!(channel.next_sent().is_none());
+        });
+    }
+
+    #[test]
+    fn test_send_over_high_water() {
+        with_channel(|py, channel, globals| {
+            globals.set_item("size", HIGH_WATER).unwrap();
+            eval(
+                py,
+                globals,
+                "send({'type': 'http.response.start', 'status': 200})",
+            )
+            .unwrap();
+            eval(
+                py,
+                globals,
+                "now(send({'type': 'http.response.body', 'body': bytes(size), 'more_body': True}))",
+            )
+            .unwrap();
+            let over = eval(
+                py,
+                globals,
+                "send({'type': 'http.response.body', 'body': b'a'})",
+            )
+            .unwrap();
+            globals.set_item("over", over).unwrap();
+            assert!(eval(py, globals, "now(over)").is_err());
+
+            channel.next_sent().unwrap();
+            channel.next_sent().unwrap();
+            let sent = channel.next_sent().unwrap();
+            assert!(sent.waiter.is_some());
+            channel.complete(sent.waiter, |py| Ok(py.None()));
+            assert!(eval(py, globals, "later(over)").unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn test_send_order() {
+        with_channel(|py, _, globals| {
+            let body = "send({'type': 'http.response.body', 'body': b''})";
+            let start = "send({'type': 'http.response.start', 'status': 200})";
+
+            let e = eval(py, globals, body).unwrap_err();
+            assert!(e.is_instance_of::<PyRuntimeError>(py));
+            eval(py, globals, start).unwrap();
+            let e = eval(py, globals, start).unwrap_err();
+            assert!(e.is_instance_of::<PyRuntimeError>(py));
+        });
+    }
+}
@@ -0,0 +1,353 @@
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use http::StatusCode;
+use pyo3::exceptions::PyRuntimeError;
+use tokio::time::{timeout_at, Instant};
+
+use crate::conn::http11::bound::{Inbound, Outbound, RequestBody, RequestData, RequestHead};
+use crate::conn::http11::conn::Http11Connection;
+use crate::conn::http11::response::error_response;
+use crate::conn::http11::state::State;
+use crate::conn::tls::{TlsConnection, TlsInfo};
+use crate::conn::Connection;
+use crate::logger::target;
+use crate::scope::ServerAddr;
+use crate::server::app;
+use crate::server::channel::{RequestChannel, Sent};
+use crate::server::transport::{Stream, Transport};
+use crate::server::Shared;
+
+/// Serve one accepted connection until it closes.
+///
+/// `peer` is `None` on Unix sockets, and `local` the address the connection
+/// was accepted on.
+pub(crate) async fn serve<S: Stream>(
+    stream: S,
+    peer: Option<SocketAddr>,
+    local: Option<ServerAddr>,
+    shared: Arc<Shared>,
+) {
+    let tls = match &shared.tls {
+        Some(reloader) => match TlsConnection::new(reloader.current()) {
+            Ok(tls) => Some(tls),
+            Err(e) => {
+                log::error!(
+                    target: target::ERROR,
+                    code = "tls.connection_failed";
+                    "Can't start TLS connection: {}", e
+                );
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let mut transport = Transport::new(stream, tls);
+    // A client that never finishes the handshake would hold the connection.
+    let deadline = Instant::now() + shared.config.timeout_request_head;
+    let (protocol, tls) = match timeout_at(deadline, transport.handshake()).await {
+        Ok(Ok(negotiated)) => negotiated,
+        Ok(Err(e)) => {
+            log::debug!(
+                target: target::PROTOCOL,
+                code = "tls.handshake_failed";
+                "TLS handshake failed: {}", e
+            );
+            transport.close().await;
+            return;
+        }
+        Err(_) => {
+            log::debug!(
+                target: target::PROTOCOL,
+                code = "tls.handshake_timeout";
+                "TLS handshake timed out"
+            );
+            transport.close().await;
+            return;
+        }
+    };
+
+    match Connection::new(protocol, shared.config.clone()) {
+        Connection::Http11(mut conn) => {
+            if let Some(peer) = peer {
+                conn.set_client(peer);
+            }
+            if let Some(ServerAddr::Inet(addr)) = local {
+                conn.set_server(addr);
+            }
+            let mut driver = Http11Driver {
+                conn,
+                transport,
+                shared,
+                tls,
+                local,
+            };
+            driver.run().await;
+            driver.transport.close().await;
+        }
+    }
+}
+
+// Why a request/response cycle ended.
+#[derive(Debug, PartialEq)]
+enum CycleEnd {
+    // Response is complete. The connection may serve the next request.
+    Finished,
+    Close,
+}
+
+/// Runs `Http11Connection` on a socket and the app on the event loop.
+struct Http11Driver<S> {
+    conn: Http11Connection,
+    transport: Transport<S>,
+    shared: Arc<Shared>,
+    tls: Option<Arc<TlsInfo>>,
+    local: Option<ServerAddr>,
+}
+
+impl<S: Stream> Http11Driver<S> {
+    async fn run(&mut self) {
+        let mut data = Bytes::new();
+        loop {
+            let inbound = Inbound::RequestData(RequestData {
+                data: mem::take(&mut data),
+            });
+            match self.conn.step(inbound) {
+                Outbound::PartialRequest => match self.read().await {
+                    Some(received) => data = received,
+                    None => return,
+                },
+                Outbound::RequestHead(head) => {
+                    if self.cycle(head).await == CycleEnd::Close || self.conn.state() != State::Idle
+                    {
+                        return;
+                    }
+                    // Next loop parses pipelined data buffered meanwhile.
+                }
+                Outbound::RequestErr(response) => {
+                    let _ = self.transport.write(response.data).await;
+                    return;
+                }
+                _ => return,
+            }
+        }
+    }
+
+    // Data from the socket, or `None` when the connection has to close. Sends
+    // the 408 response when the request deadline passes.
+    async fn read(&mut self) -> Option<Bytes> {
+        loop {
+            let result = match self.conn.deadline() {
+                Some(at) => timeout_at(Instant::from_std(at), self.transport.read()).await,
+                None => Ok(self.transport.read().await),
+            };
+            match result {
+                Ok(Ok(data)) if !data.is_empty() => return Some(data),
+                Ok(_) => {
+                    self.conn.step(Inbound::Disconnect);
+                    return None;
+                }
+                Err(_) => match self.conn.step(Inbound::Tick) {
+                    Outbound::Pending => continue,
+                    Outbound::RequestTimeout(response) => {
+                        let _ = self.transport.write(response.data).await;
+                        return None;
+                    }
+                    _ => return None,
+                },
+            }
+        }
+    }
+
+    // Next body part with data, or the last one. `None` if the connection
+    // has to close.
+    async fn read_body(&mut self) -> Option<RequestBody> {
+        let mut data = Bytes::new();
+        loop {
+            let inbound = Inbound::RequestData(RequestData {
+                data: mem::take(&mut data),
+            });
+            match self.conn.step(inbound) {
+                Outbound::RequestBody(body) if body.more_body && body.body.is_empty() => {
+                    data = self.read().await?;
+                }
+                Outbound::RequestBody(body) => return Some(body),
+                Outbound::RequestErr(response) => {
+                    let _ = self.transport.write(response.data).await;
+                    return None;
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    // The app may respond before reading the whole body. The rest is read
+    // and dropped, the connection can't start the response before.
+    async fn drain_body(&mut self) -> bool {
+        while self.conn.state() == State::RequestHeadFinished {
+            if self.read_body().await.is_none() {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn request_finished(&self) -> bool {
+        matches!(
+            self.conn.state(),
+            State::RequestBodyFinished | State::ResponseHeadFinished
+        )
+    }
+
+    async fn cycle(&mut self, head: RequestHead) -> CycleEnd {
+        let scope = self.shared.scopes.build(
+            &head,
+            self.conn.client(),
+            // A PROXY header may give the address the client connected to.
+            self.conn
+                .server()
+                .map(ServerAddr::Inet)
+                .or_else(|| self.local.clone()),
+            self.tls.as_ref(),
+        );
+        let channel = match app::start(&self.shared, &scope) {
+            Ok(channel) => channel,
+            Err(e) => {
+                log::error!(
+                    target: target::ERROR,
+                    code = "asgi.exception";
+                    "Exception in ASGI application: {}", e
+                );
+                let response = error_response(StatusCode::INTERNAL_SERVER_ERROR);
+                let _ = self.transport.write(response).await;
+                return CycleEnd::Close;
+            }
+        };
+        let end = self.exchange(&channel).await;
+        // The app may keep running, its next `receive()` gets a disconnect.
+        channel.disconnect();
+        end
+    }
+
+    async fn exchange(&mut self, channel: &RequestChannel) -> CycleEnd {
+        let mut started = false;
+        let mut disconnected = false;
+        loop {
+            // Taken first: events the app sent before it finished are queued.
+            let done = channel.done();
+            while let Some(Sent { inbound, waiter }) = channel.next_sent() {
+                if disconnected {
+                    channel.complete(waiter, |py| Ok(py.None()));
+                    continue;
+                }
+                if matches!(inbound, Inbound::ResponseStart(_)) && !self.drain_body().await {
+                    disconnected = true;
+                    channel.disconnect();
+                    channel.complete(waiter, |py| Ok(py.None()));
+                    continue;
+                }
+                match self.conn.step(inbound) {
+                    Outbound::ResponseStart(data) | Outbound::ResponseBody(data) => {
+                        started = true;
+                        if self.transport.write(data.data).await.is_err() {
+                            disconnected = true;
+                            self.conn.step(Inbound::Disconnect);
+                            channel.disconnect();
+                        }
+                        channel.complete(waiter, |py| Ok(py.None()));
+                    }
+                    Outbound::ReseponseErr(response) => {
+                        if let Some(response) = response {
+                            let _ = self.transport.write(response.data).await;
+                        }
+                        channel.complete(waiter, |_| {
+                            Err(PyRuntimeError::new_err("invalid ASGI response"))
+                        });
+                        return CycleEnd::Close;
+                    }
+                    Outbound::InvalidState(e) => {
+                        channel.complete(waiter, |_| {
+                            Err(PyRuntimeError::new_err(format!(
+                                "unexpected ASGI message in {:?}",
+                                e.state
+                            )))
+                        });
+                    }
+                    _ => channel.complete(waiter, |py| Ok(py.None())),
+                }
+                // Last body part sent.
+                if started && !self.request_finished() && !disconnected {
+                    return CycleEnd::Finished;
+                }
+            }
+
+            if let Some(failed) = done {
+                if disconnected {
+                    return CycleEnd::Close;
+                }
+                if started {
+                    log::error!(
+                        target: target::ERROR,
+                        code = "asgi.incomplete";
+                        "ASGI callable returned without completing response"
+                    );
+                } else {
+                    if !failed {
+                        log::error!(
+                            target: target::ERROR,
+                            code = "asgi.no_response";
+                            "ASGI callable returned without starting response"
+                        );
+                    }
+                    if self.drain_body().await {
+                        let response = error_response(StatusCode::INTERNAL_SERVER_ERROR);
+                        let _ = self.transport.write(response).await;
+                    }
+                }
+                return CycleEnd::Close;
+            }
+
+            if self.conn.state() == State::RequestHeadFinished
+                && !disconnected
+                && channel.wants_body()
+            {
+                match self.read_body().await {
+                    Some(body) => channel.push_body(body),
+                    None => {
+                        disconnected = true;
+                        channel.disconnect();
+                    }
+                }
+                continue;
+            }
+
+            // Reading after the request lets a disconnect reach the app. Data
+            // of pipelined requests is buffered by the connection.
+            if self.request_finished() && !disconnected {
+                tokio::select! {
+                    _ = channel.notified() => {}
+                    data = self.transport.read() => match data {
+                        Ok(data) if !data.is_empty() => {
+                            let inbound = Inbound::RequestData(RequestData { data });
+                            // Closed when too much pipelined data is buffered.
+                            if let Outbound::Closed = self.conn.step(inbound) {
+                                disconnected = true;
+                                channel.disconnect();
+                            }
+                        }
+                        _ => {
+                            disconnected = true;
+                            self.conn.step(Inbound::Disconnect);
+                            channel.disconnect();
+                        }
+                    },
+                }
+            } else {
+                channel.notified().await;
+            }
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::fd::{BorrowedFd, OwnedFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net as std_unix;
+use std::path::{Path, PathBuf};
+
+use tokio::net::{unix, TcpListener, TcpSocket, UnixListener, UnixSocket};
+
+use crate::config::ServerConfig;
+use crate::scope::ServerAddr;
+
+/// Socket the server accepts connections on.
+#[derive(Debug)]
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Bind as configured: `fd`, `uds`, or else `host` and `port`.
+    ///
+    /// Also returns the socket file created for `uds`, to remove once the
+    /// server is closed. Must be called inside the tokio runtime.
+    pub fn bind(config: &ServerConfig) -> io::Result<(Self, Option<PathBuf>)> {
+        if let Some(fd) = config.fd {
+            return Ok((from_fd(fd)?, None));
+        }
+        if let Some(path) = &config.uds {
+            let path = PathBuf::from(path);
+            let listener = bind_unix(&path, config.uds_mode, config.backlog)?;
+            return Ok((Self::Unix(listener), Some(path)));
+        }
+        Ok((Self::Tcp(bind_tcp(config)?), None))
+    }
+
+    pub fn local_addr(&self) -> io::Result<ServerAddr> {
+        match self {
+            Self::Tcp(listener) => Ok(ServerAddr::Inet(listener.local_addr()?)),
+            Self::Unix(listener) => Ok(ServerAddr::Unix(
+                unix_path(&listener.local_addr()?).unwrap_or_default(),
+            )),
+        }
+    }
+}
+
+/// Path of a Unix socket address. Unnamed and abstract ones have none.
+pub(crate) fn unix_path(addr: &unix::SocketAddr) -> Option<String> {
+    addr.as_pathname()
+        .map(|path| path.to_string_lossy().into_owned())
+}
+
+fn bind_tcp(config: &ServerConfig) -> io::Result<TcpListener> {
+    let addr = (config.host.as_str(), config.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "host has no address"))?;
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(config.backlog)
+}
+
+// A socket file nobody listens on is left by a server that crashed. Binding
+// fails while it exists, so it is removed. Live sockets and other files are
+// kept.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    match std_unix::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("a server is listening on {}", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+fn bind_unix(path: &Path, mode: u32, backlog: u32) -> io::Result<UnixListener> {
+    remove_stale_socket(path)?;
+    let socket = UnixSocket::new_stream()?;
+    socket.bind(path)?;
+    // Before `listen`, so no client connects with the default permissions.
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    socket.listen(backlog)
+}
+
+fn from_fd(fd: i32) -> io::Result<Listener> {
+    // SAFETY: `fd` is only borrowed to duplicate it. A closed descriptor
+    // makes the duplication fail with `EBADF`.
+    let fd: OwnedFd = unsafe { BorrowedFd::borrow_raw(fd) }.try_clone_to_owned()?;
+    let unix = std_unix::UnixListener::from(fd);
+    // Fails unless the socket is in the Unix family.
+    if unix.local_addr().is_ok() {
+        unix.set_nonblocking(true)?;
+        return Ok(Listener::Unix(UnixListener::from_std(unix)?));
+    }
+    let tcp = std::net::TcpListener::from(OwnedFd::from(unix));
+    // Fails if `fd` is not a socket.
+    tcp.local_addr()?;
+    tcp.set_nonblocking(true)?;
+    Ok(Listener::Tcp(TcpListener::from_std(tcp)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("ruvicorn-{}-{}.sock", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_remove_stale_socket() {
+        let path = socket_path("stale");
+        // The file stays when the listener is dropped.
+        drop(std_unix::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        remove_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+        remove_stale_socket(&path).unwrap();
+    }
+
+    #[test]
+    fn test_keep_live_socket() {
+        let path = socket_path("live");
+        let _listener = std_unix::UnixListener::bind(&path).unwrap();
+
+        let e = remove_stale_socket(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_keep_other_file() {
+        let path = socket_path("file");
+        fs::write(&path, b"").unwrap();
+
+        let e = remove_stale_socket(&path).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AlreadyExists);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bind_unix_mode() {
+        let path = socket_path("mode");
+        let config = ServerConfig {
+            uds: Some(path.to_str().unwrap().to_owned()),
+            uds_mode: 0o600,
+            ..Default::default()
+        };
+
+        let (listener, created) = Listener::bind(&config).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(
+            listener.local_addr().unwrap(),
+            ServerAddr::Unix(path.to_str().unwrap().to_owned())
+        );
+        assert_eq!(created, Some(path.clone()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_from_fd() {
+        use std::os::fd::AsRawFd;
+
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let listener = from_fd(tcp.as_raw_fd()).unwrap();
+        assert_eq!(
+            listener.local_addr().unwrap(),
+            ServerAddr::Inet(tcp.local_addr().unwrap())
+        );
+
+        let path = socket_path("fd");
+        let unix = std_unix::UnixListener::bind(&path).unwrap();
+        let listener = from_fd(unix.as_raw_fd()).unwrap();
+        assert!(matches!(listener, Listener::Unix(_)));
+        fs::remove_file(&path).unwrap();
+
+        let file = fs::File::open(std::env::temp_dir()).unwrap();
+        assert!(from_fd(file.as_raw_fd()).is_err());
+    }
+}
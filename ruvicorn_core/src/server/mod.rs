@@ -0,0 +1,516 @@
+// Emitted from inside the pyo3 0.19 `#[pymethods]` expansion, which an
+// attribute on the impl doesn't reach.
+#![allow(non_local_definitions)]
+
+//! Runtime where Rust owns the sockets.
+//!
+//! Connections are accepted and driven on a tokio runtime, without the GIL.
+//! Parsing, body decoding and response serialization run on its threads.
+//! The GIL is only taken at the boundary: to build the scope and events as
+//! Python objects, to copy what the app sends, and to log.
+
+mod app;
+pub mod channel;
+mod connection;
+mod listener;
+mod transport;
+
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use tokio::runtime::Runtime;
+
+use crate::config::ServerConfig;
+use crate::conn::tls_reload::CertReloader;
+use crate::logger::{self, target};
+use crate::scope::{ScopeBuilder, ServerAddr};
+use crate::server::listener::{unix_path, Listener};
+
+/// State of the server used by every connection.
+#[derive(Debug)]
+pub(crate) struct Shared {
+    pub config: Arc<ServerConfig>,
+    pub scopes: ScopeBuilder,
+    pub tls: Option<Arc<CertReloader>>,
+    pub app: PyObject,
+    pub event_loop: PyObject,
+}
+
+async fn accept(listener: Listener, shared: Arc<Shared>) {
+    loop {
+        let accepted = match &listener {
+            Listener::Tcp(listener) => listener.accept().await.map(|(stream, peer)| {
+                let _ = stream.set_nodelay(true);
+                let local = stream.local_addr().ok().map(ServerAddr::Inet);
+                tokio::spawn(connection::serve(stream, Some(peer), local, shared.clone()));
+            }),
+            // Unix peers have no address, the scope has no `client`.
+            Listener::Unix(listener) => listener.accept().await.map(|(stream, _)| {
+                let local = stream
+                    .local_addr()
+                    .ok()
+                    .and_then(|addr| unix_path(&addr))
+                    .map(ServerAddr::Unix);
+                tokio::spawn(connection::serve(stream, None, local, shared.clone()));
+            }),
+        };
+        if let Err(e) = accepted {
+            // Mostly out of file descriptors. Give connections time to close.
+            log::error!(
+                target: target::ERROR,
+                code = "server.accept_failed";
+                "Can't accept connection: {}", e
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+/// HTTP server whose sockets are owned by Rust.
+///
+/// The app runs on `event_loop`, which must be running for requests to be
+/// answered.
+#[pyclass(module = "ruvicorn_core")]
+pub struct Server {
+    shared: Arc<Shared>,
+    runtime: Option<Runtime>,
+    // Unix socket file created by `start`, removed by `close`.
+    socket_file: Option<PathBuf>,
+}
+
+#[pymethods]
+impl Server {
+    #[new]
+    fn py_new(
+        py: Python<'_>,
+        config: ServerConfig,
+        app: PyObject,
+        event_loop: PyObject,
+    ) -> PyResult<Self> {
+        let config = Arc::new(config);
+        logger::init(config.log_level_filter());
+        // Reads and parses the certificate files.
+        let tls = py.allow_threads(|| CertReloader::new(config.clone()))?;
+        let shared = Shared {
+            scopes: ScopeBuilder::new(config.clone()),
+            config,
+            tls,
+            app,
+            event_loop,
+        };
+        Ok(Self {
+            shared: Arc::new(shared),
+            runtime: None,
+            socket_file: None,
+        })
+    }
+
+    /// Bind the socket and start serving. Returns the bound `(host, port)`,
+    /// or `(path, None)` on a Unix socket.
+    fn start(&mut self, py: Python<'_>) -> PyResult<(String, Option<u16>)> {
+        if self.runtime.is_some() {
+            return Err(PyRuntimeError::new_err("server is already started"));
+        }
+        let config = &self.shared.config;
+        // Resolving the host may block.
+        let (runtime, (listener, socket_file)) = py.allow_threads(|| -> io::Result<_> {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .thread_name("ruvicorn-worker")
+                .enable_all()
+                .build()?;
+            let bound = {
+                let _guard = runtime.enter();
+                Listener::bind(config)?
+            };
+            Ok((runtime, bound))
+        })?;
+        let addr = listener.local_addr()?;
+        runtime.spawn(accept(listener, self.shared.clone()));
+        self.runtime = Some(runtime);
+        self.socket_file = socket_file;
+        Ok(match addr {
+            ServerAddr::Inet(addr) => (addr.ip().to_string(), Some(addr.port())),
+            ServerAddr::Unix(path) => (path, None),
+        })
+    }
+
+    /// Stop accepting and drop every connection. Apps still running get a
+    /// disconnect from `receive`, and their `send` does nothing.
+    fn close(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            // Waiting would deadlock with tasks waiting for the GIL.
+            runtime.shutdown_background();
+        }
+        if let Some(path) = self.socket_file.take() {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// Load the TLS certificate files again, for example from a SIGHUP
+    /// handler installed with `loop.add_signal_handler`. Only later
+    /// handshakes use the new certificate.
+    ///
+    /// Raises `ValueError` and keeps the old certificate if loading fails.
+    fn reload_tls(&self, py: Python<'_>) -> PyResult<()> {
+        let Some(tls) = &self.shared.tls else {
+            return Err(PyValueError::new_err(
+                "TLS is disabled, set ssl_certfile and ssl_keyfile",
+            ));
+        };
+        py.allow_threads(|| tls.reload())?;
+        Ok(())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::conn::tls::test_support::self_signed;
+    use pyo3::types::PyDict;
+
+    const CLIENT: &str = r#"
+import asyncio
+import ssl
+
+async def request(address, data, context):
+    host, port = address
+    if port is None:
+        reader, writer = await asyncio.open_unix_connection(host)
+    else:
+        reader, writer = await asyncio.open_connection(
+            "127.0.0.1", port, ssl=context, server_hostname="localhost" if context else None
+        )
+    writer.write(data)
+    await writer.drain()
+    response = await reader.read()
+    writer.close()
+    return response
+
+async def main(app, data, context=None, **config):
+    loop = asyncio.get_running_loop()
+    server = Server(ServerConfig(port=0, access_log=False, **config), app, loop)
+    address = server.start()
+    try:
+        return await asyncio.wait_for(request(address, data, context), 5)
+    finally:
+        server.close()
+
+async def hello(scope, receive, send):
+    body = b"Hello, " + scope["path"].encode()
+    await send({
+        "type": "http.response.start",
+        "status": 200,
+        "headers": [(b"content-length", str(len(body)).encode())],
+    })
+    await send({"type": "http.response.body", "body": body})
+
+async def echo(scope, receive, send):
+    body = b""
+    while True:
+        message = await receive()
+        body += message["body"]
+        if not message["more_body"]:
+            break
+    await send({
+        "type": "http.response.start",
+        "status": 200,
+        "headers": [(b"content-length", str(len(body)).encode())],
+    })
+    await send({"type": "http.response.body", "body": body})
+    assert (await receive())["type"] == "http.disconnect"
+
+async def addresses(scope, receive, send):
+    body = repr((scope["server"], scope["client"])).encode()
+    await send({"type": "http.response.start", "status": 200})
+    await send({"type": "http.response.body", "body": body})
+
+async def fail(scope, receive, send):
+    raise RuntimeError("app failed")
+"#;
+
+    // Run `code` after `CLIENT` and return the bytes in `response`.
+    fn run(code: &str) -> Vec<u8> {
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            globals.set_item("Server", py.get_type::<Server>()).unwrap();
+            globals
+                .set_item("ServerConfig", py.get_type::<ServerConfig>())
+                .unwrap();
+            let code = format!("{}\n{}", CLIENT, code);
+            py.run(&code, Some(globals), None).unwrap();
+            globals
+                .get_item("response")
+                .unwrap()
+                .extract::<Vec<u8>>()
+                .unwrap()
+        })
+    }
+
+    #[test]
+    fn test_request() {
+        let response = run(
+            r#"response = asyncio.run(main(hello, b"GET /world HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n"))"#,
+        );
+
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(b"\r\n\r\nHello, /world"));
+    }
+
+    #[test]
+    fn test_keep_alive_and_body() {
+        let response = run(r#"response = asyncio.run(main(echo, (
+    b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n"
+    b"3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n"
+    b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 2\r\nConnection: close\r\n\r\nfg"
+)))"#);
+
+        let response = String::from_utf8(response).unwrap();
+        assert_eq!(response.matches("HTTP/1.1 200 OK").count(), 2);
+        assert!(response.contains("\r\n\r\nabcde"));
+        assert!(response.ends_with("\r\n\r\nfg"));
+    }
+
+    #[test]
+    fn test_app_exception() {
+        let response =
+            run(r#"response = asyncio.run(main(fail, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"))"#);
+
+        assert!(response.starts_with(b"HTTP/1.1 500 Internal Server Error\r\n"));
+    }
+
+    #[test]
+    fn test_response_with_wrong_types() {
+        let response = run(r#"
+async def list_headers(scope, receive, send):
+    await send({"type": "http.response.start", "status": 200, "headers": [[b"content-length", b"4"]]})
+    await send({"type": "http.response.body", "body": b"data"})
+response = asyncio.run(main(list_headers, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"))"#);
+
+        assert!(response.starts_with(b"HTTP/1.1 500 Internal Server Error\r\n"));
+
+        let response = run(r#"
+async def wrong_body(scope, receive, send):
+    await send({"type": "http.response.start", "status": 200})
+    errors = []
+    for body in (bytearray(b"data"), "data"):
+        try:
+            await send({"type": "http.response.body", "body": body})
+        except TypeError:
+            errors.append(type(body).__name__)
+    await send({"type": "http.response.body", "body": repr(errors).encode()})
+response = asyncio.run(main(wrong_body, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n"))"#);
+
+        assert!(response.ends_with(b"\r\n\r\n['bytearray', 'str']"));
+    }
+
+    #[test]
+    fn test_invalid_request() {
+        let response = run(r#"response = asyncio.run(main(hello, b"GET / HTTP/1.1\r\n\r\n"))"#);
+
+        assert!(response.starts_with(b"HTTP/1.1 400 Bad Request\r\n"));
+    }
+
+    #[test]
+    fn test_tls() {
+        let cert = self_signed("server");
+        let response = run(&format!(
+            r#"
+context = ssl.create_default_context(cadata={:?})
+async def scheme(scope, receive, send):
+    body = (scope["scheme"] + " " + str(scope["extensions"]["tls"]["tls_version"])).encode()
+    await send({{"type": "http.response.start", "status": 200}})
+    await send({{"type": "http.response.body", "body": body}})
+response = asyncio.run(main(
+    scheme,
+    b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n",
+    context,
+    ssl_certfile={:?},
+    ssl_keyfile={:?},
+))"#,
+            cert.cert.pem(),
+            cert.certfile.to_str().unwrap(),
+            cert.keyfile.to_str().unwrap(),
+        ));
+
+        // 0x0304 is TLS 1.3.
+        assert!(response.ends_with(b"\r\n\r\nhttps 772"));
+    }
+
+    #[test]
+    fn test_tls_large_body() {
+        let cert = self_signed("large-body");
+        let response = run(&format!(
+            r#"
+body = bytes(i % 251 for i in range(300000))
+async def large(scope, receive, send):
+    await send({{
+        "type": "http.response.start",
+        "status": 200,
+        "headers": [(b"content-length", str(len(body)).encode())],
+    }})
+    await send({{"type": "http.response.body", "body": body}})
+response = asyncio.run(main(
+    large,
+    b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+    ssl.create_default_context(cadata={:?}),
+    ssl_certfile={:?},
+    ssl_keyfile={:?},
+))"#,
+            cert.cert.pem(),
+            cert.certfile.to_str().unwrap(),
+            cert.keyfile.to_str().unwrap(),
+        ));
+
+        let body: Vec<u8> = (0..300000).map(|i| (i % 251) as u8).collect();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&body));
+    }
+
+    #[test]
+    fn test_reload_tls() {
+        let cert = self_signed("server-reload");
+        let new_cert = self_signed("server-reload-new");
+        let response = run(&format!(
+            r#"
+import shutil
+async def reload():
+    loop = asyncio.get_running_loop()
+    config = ServerConfig(port=0, access_log=False, ssl_certfile={certfile:?}, ssl_keyfile={keyfile:?})
+    server = Server(config, hello, loop)
+    address = server.start()
+    data = b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n"
+    try:
+        await request(address, data, ssl.create_default_context(cadata={old:?}))
+        shutil.copy({new_certfile:?}, {certfile:?})
+        shutil.copy({new_keyfile:?}, {keyfile:?})
+        server.reload_tls()
+        # Fails unless the new certificate is presented.
+        return await request(address, data, ssl.create_default_context(cadata={new:?}))
+    finally:
+        server.close()
+response = asyncio.run(reload())"#,
+            certfile = cert.certfile.to_str().unwrap(),
+            keyfile = cert.keyfile.to_str().unwrap(),
+            old = cert.cert.pem(),
+            new_certfile = new_cert.certfile.to_str().unwrap(),
+            new_keyfile = new_cert.keyfile.to_str().unwrap(),
+            new = new_cert.cert.pem(),
+        ));
+
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_tls_handshake_timeout() {
+        use std::io::Read;
+        use std::net::TcpStream;
+
+        let cert = self_signed("server-handshake-timeout");
+        let config = ServerConfig {
+            port: 0,
+            timeout_request_head: Duration::from_millis(50),
+            ssl_certfile: Some(cert.certfile.to_str().unwrap().to_owned()),
+            ssl_keyfile: Some(cert.keyfile.to_str().unwrap().to_owned()),
+            ..Default::default()
+        };
+        let (mut server, port) = Python::with_gil(|py| {
+            let mut server = Server::py_new(py, config, py.None(), py.None()).unwrap();
+            let (_, port) = server.start(py).unwrap();
+            (server, port.unwrap())
+        });
+
+        // Never sends a ClientHello. The server closes once the deadline
+        // passes, before the read timeout.
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let read = stream.read_to_end(&mut Vec::new());
+        server.close();
+
+        assert!(read.is_ok());
+    }
+
+    #[test]
+    fn test_reload_tls_disabled() {
+        Python::with_gil(|py| {
+            let server = Server::py_new(py, ServerConfig::default(), py.None(), py.None()).unwrap();
+
+            assert!(server
+                .reload_tls(py)
+                .unwrap_err()
+                .is_instance_of::<PyValueError>(py));
+        });
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        let path =
+            std::env::temp_dir().join(format!("ruvicorn-{}-server.sock", std::process::id()));
+        let response = run(&format!(
+            r#"response = asyncio.run(main(addresses, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n", uds={:?}))"#,
+            path.to_str().unwrap()
+        ));
+
+        let expected = format!("\r\n\r\n(('{}', None), None)", path.display());
+        assert!(response.ends_with(expected.as_bytes()));
+        // Removed on close.
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_inherited_fd() {
+        let response = run(r#"
+import socket
+with socket.create_server(("127.0.0.1", 0)) as sock:
+    port = sock.getsockname()[1]
+    response = asyncio.run(main(addresses, b"GET / HTTP/1.1\r\nHost: a\r\n\r\n", fd=sock.fileno()))
+    assert f"(('127.0.0.1', {port}), ('127.0.0.1', ".encode() in response
+"#);
+
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_parse_without_gil() {
+        use std::io::{Read, Write};
+        use std::net::TcpStream;
+
+        let config = ServerConfig {
+            port: 0,
+            access_log: false,
+            timeout_request_head: Duration::from_millis(50),
+            ..Default::default()
+        };
+        Python::with_gil(|py| {
+            let mut server = Server::py_new(py, config, py.None(), py.None()).unwrap();
+            let (_, port) = server.start(py).unwrap();
+
+            // The GIL is held meanwhile. The request never reaches the app,
+            // and a timeout logs nothing, so nothing has to take it.
+            let mut stream = TcpStream::connect(("127.0.0.1", port.unwrap())).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: a\r\nX-Header: value\r\n")
+                .unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            server.close();
+
+            assert!(response.starts_with(b"HTTP/1.1 408 Request Timeout\r\n"));
+        });
+    }
+}
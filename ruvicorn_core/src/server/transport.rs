@@ -0,0 +1,146 @@
+use std::io;
+use std::sync::Arc;
+
+use bytes::{Buf, Bytes};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::conn::tls::{TlsConnection, TlsInfo};
+use crate::conn::Protocol;
+
+const READ_SIZE: usize = 64 * 1024;
+
+fn tls_error(error: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// TCP or Unix socket stream of a connection.
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + 'static> Stream for S {}
+
+/// Socket of a connection, with TLS on top when it is enabled.
+///
+/// `read` and `write` are cancel safe: decrypted data and records not sent
+/// yet are kept until the next call.
+#[derive(Debug)]
+pub(crate) struct Transport<S> {
+    stream: S,
+    tls: Option<TlsConnection>,
+    buffer: Vec<u8>,
+    // Plaintext received but not returned by `read` yet.
+    received: Bytes,
+    // Bytes for the socket, TLS records when TLS is enabled.
+    outgoing: Bytes,
+}
+
+impl<S: Stream> Transport<S> {
+    pub fn new(stream: S, tls: Option<TlsConnection>) -> Self {
+        Self {
+            stream,
+            tls,
+            buffer: vec![0; READ_SIZE],
+            received: Bytes::new(),
+            outgoing: Bytes::new(),
+        }
+    }
+
+    async fn flush(&mut self) -> io::Result<()> {
+        while !self.outgoing.is_empty() {
+            let sent = self.stream.write(&self.outgoing).await?;
+            if sent == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.outgoing.advance(sent);
+        }
+        Ok(())
+    }
+
+    fn queue(&mut self, data: Bytes) {
+        if self.outgoing.is_empty() {
+            self.outgoing = data;
+        } else {
+            let mut outgoing = self.outgoing.to_vec();
+            outgoing.extend_from_slice(&data);
+            self.outgoing = Bytes::from(outgoing);
+        }
+    }
+
+    /// Complete the TLS handshake, if TLS is enabled.
+    ///
+    /// Returns the protocol to speak and the TLS details for the scope.
+    pub async fn handshake(&mut self) -> io::Result<(Protocol, Option<Arc<TlsInfo>>)> {
+        while self.tls.as_ref().is_some_and(TlsConnection::is_handshaking) {
+            let read = self.stream.read(&mut self.buffer).await?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let Some(tls) = &mut self.tls else {
+                break;
+            };
+            match tls.receive(&self.buffer[..read]) {
+                Ok(received) => {
+                    // Data sent right after the client's Finished message.
+                    self.received = received.plaintext;
+                    self.queue(received.to_send);
+                }
+                Err(e) => {
+                    let alert = tls.take_output();
+                    self.queue(alert);
+                    let _ = self.flush().await;
+                    return Err(tls_error(e));
+                }
+            }
+            self.flush().await?;
+        }
+        match &self.tls {
+            Some(tls) => Ok((
+                tls.protocol().unwrap_or(Protocol::Http11),
+                tls.info().map(Arc::new),
+            )),
+            None => Ok((Protocol::Http11, None)),
+        }
+    }
+
+    /// Next data from the peer. Empty once the peer closed the connection.
+    pub async fn read(&mut self) -> io::Result<Bytes> {
+        self.flush().await?;
+        loop {
+            if !self.received.is_empty() {
+                return Ok(std::mem::take(&mut self.received));
+            }
+            let read = self.stream.read(&mut self.buffer).await?;
+            if read == 0 {
+                return Ok(Bytes::new());
+            }
+            let Some(tls) = &mut self.tls else {
+                return Ok(Bytes::copy_from_slice(&self.buffer[..read]));
+            };
+            let received = tls.receive(&self.buffer[..read]).map_err(tls_error)?;
+            self.received = received.plaintext;
+            self.queue(received.to_send);
+            self.flush().await?;
+            if received.closed && self.received.is_empty() {
+                return Ok(Bytes::new());
+            }
+        }
+    }
+
+    pub async fn write(&mut self, data: Bytes) -> io::Result<()> {
+        let data = match &mut self.tls {
+            Some(tls) => tls.send(&data)?,
+            None => data,
+        };
+        self.queue(data);
+        return self.flush().await;
+    }
+
+    /// Send close_notify on TLS connections and shut the socket down.
+    pub async fn close(&mut self) {
+        if let Some(tls) = &mut self.tls {
+            let close = tls.close();
+            self.queue(close);
+        }
+        let _ = self.flush().await;
+        let _ = self.stream.shutdown().await;
+    }
+}